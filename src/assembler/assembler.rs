@@ -1,33 +1,168 @@
-use crate::assembler::error::AssemblingError;
-use crate::assembler::tokens::Tokens;
-use log::{log, Level};
-use logos::Logos;
-use regex::Regex;
-use std::collections::HashMap;
-use std::u16;
-
-macro_rules! regex {
-    ($binder:ident=$pattern:literal) => {
-        // static $binder: std::sync::LazyLock<Regex> =
-        // std::sync::LazyLock::new(|| Regex::new($pattern).unwrap());
-        let $binder = Regex::new($pattern).unwrap();
-    };
+use crate::assembler::ast::{
+    BinaryOp, Expanded, Expr, Label, Line, Operand, OperandKind, Statement,
+};
+use crate::assembler::error::{AssemblingError, ErrorCode, Note};
+use crate::assembler::macros::{pseudo, Expansion, Macro, MAX_EXPANDED};
+use crate::assembler::parser::{comment_start, fields, is_identifier, parse_line};
+use crate::assembler::symbol::{Symbol, SymbolKind};
+use crate::interpreter::control::{control_register, CONTROL_REGISTERS};
+use crate::interpreter::memory::MEMORY_SIZE;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+
+/// How an instruction is encoded, with the opcode bits of the first word
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum Format {
+    /// op Rd,Ra,Rb
+    Rrr(u16),
+    /// op Ra,Rb
+    Rr(u16),
+    /// op Rd,disp[Ra]
+    Rx(u16),
+    /// op disp[Ra], the index register is optional
+    /// Conditional jumps already have the condition bit in the first word
+    Jump(u16),
+    /// op bit,disp[Ra]
+    Jumpc(u16),
+    /// Two word EXP instruction with the secondary opcode
+    Exp(u16),
+    /// data value
+    Data,
+    /// org address, moves the location counter
+    Org,
+    /// name equ value, defines a constant
+    Equ,
+    /// block n, reserves n words
+    Block,
+    /// name module
+    Module,
+    /// name import module,symbol
+    Import,
+    /// export symbol,...
+    Export,
+}
+
+/// The value of an expression and what it depends on, so addresses can be relocated
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Constant(i64),
+    Relocatable(i64),
+    /// The local name of an imported symbol and an offset from it,
+    /// the symbol's value is added by the linker
+    Imported(String, i64),
+}
+
+impl Format {
+    /// Mnemonics are not case sensitive
+    pub(super) fn lookup(mnemonic: &str) -> Option<Format> {
+        let format = match mnemonic.to_ascii_lowercase().as_str() {
+            "add" => Format::Rrr(0x0000),
+            "sub" => Format::Rrr(0x1000),
+            "mul" => Format::Rrr(0x2000),
+            "div" => Format::Rrr(0x3000),
+            "cmp" => Format::Rr(0x4000),
+            "addc" => Format::Rrr(0x5000),
+            "muln" => Format::Rrr(0x6000),
+            "divn" => Format::Rrr(0x7000),
+            "rrr1" => Format::Rrr(0x8000),
+            "rrr2" => Format::Rrr(0x9000),
+            "rrr3" => Format::Rrr(0xa000),
+            "rrr4" => Format::Rrr(0xb000),
+            "trap" => Format::Rrr(0xc000),
+            "lea" => Format::Rx(0xf000),
+            "load" => Format::Rx(0xf001),
+            "store" => Format::Rx(0xf002),
+            "jump" => Format::Jump(0xf003),
+            "jumpc0" => Format::Jumpc(0xf004),
+            "jumpc1" => Format::Jumpc(0xf005),
+            "jumple" => Format::Jump(0xf004),
+            "jumpne" => Format::Jump(0xf204),
+            "jumpge" => Format::Jump(0xf404),
+            "jumpgt" => Format::Jump(0xf005),
+            "jumpeq" => Format::Jump(0xf205),
+            "jumplt" => Format::Jump(0xf405),
+            "jal" => Format::Rx(0xf006),
+            "jumpz" => Format::Rx(0xf007),
+            "jumpnz" => Format::Rx(0xf008),
+            "testset" => Format::Rx(0xf009),
+            "resume" => Format::Exp(0x00),
+            "save" => Format::Exp(0x01),
+            "restore" => Format::Exp(0x02),
+            "push" => Format::Exp(0x03),
+            "pop" => Format::Exp(0x04),
+            "top" => Format::Exp(0x05),
+            "shiftl" => Format::Exp(0x06),
+            "shiftr" => Format::Exp(0x07),
+            "logicw" => Format::Exp(0x08),
+            "logicf" => Format::Exp(0x09),
+            "extract" => Format::Exp(0x0a),
+            "getctl" => Format::Exp(0x0b),
+            "putctl" => Format::Exp(0x0c),
+            "brc0" => Format::Exp(0x0d),
+            "brc1" => Format::Exp(0x0e),
+            "dsptch" => Format::Exp(0x0f),
+            "data" => Format::Data,
+            "org" => Format::Org,
+            "equ" => Format::Equ,
+            "block" => Format::Block,
+            "module" => Format::Module,
+            "import" => Format::Import,
+            "export" => Format::Export,
+            _ => return None,
+        };
+        Some(format)
+    }
+
+    /// Number of words the statement takes in memory
+    /// The size of a block or data statement depends on its operands, so it is worked out in pass one
+    fn size(&self) -> usize {
+        match self {
+            Format::Rrr(_) | Format::Rr(_) => 1,
+            Format::Rx(_) | Format::Jump(_) | Format::Jumpc(_) | Format::Exp(_) => 2,
+            Format::Org
+            | Format::Equ
+            | Format::Block
+            | Format::Data
+            | Format::Module
+            | Format::Import
+            | Format::Export => 0,
+        }
+    }
 }
 
 /// A container to hold Sigma16 code and byte code
 pub struct Assembler {
     pub code: String,
     pub assembled: Vec<u16>,
-    pub symbol_table: HashMap<String, usize>,
+    pub symbol_table: HashMap<String, Symbol>,
     pub mem_to_code: HashMap<usize, usize>,
     pub errors: Vec<AssemblingError>,
-    last_token_processed: Tokens,
+    pub module: Option<String>,
+    pub exports: Vec<String>,
+    /// Addresses of words holding a relocatable address
+    pub relocations: Vec<usize>,
+    /// Addresses of words holding an imported symbol, with its local name
+    pub imports: Vec<(usize, String)>,
     line: usize,
-    cursor: usize,
-    data_inserts: HashMap<String, Vec<u16>>,
-    data_locations: HashMap<String, (u16, usize)>,
+    /// Text in the line that errors point at, the statement or the operand being assembled
+    span: Option<Range<usize>>,
+    /// Lines each symbol is used on, by an instruction, data or export
+    pub references: HashMap<String, Vec<usize>>,
+    /// Labels defined more than once, so their first definition is only reported once
+    duplicates: HashSet<String>,
+    /// Values of equ constants, which may be negative
+    constants: HashMap<String, i64>,
+    /// Labels of locations in the program with the text defining them, checked for being used
+    locations: HashMap<String, Range<usize>>,
     pub registers_used: Vec<usize>,
     pub trap_index: Option<usize>,
+    macros: HashMap<String, Macro>,
+    /// The lines each macro call expanded to, with their addresses
+    pub expansions: Vec<Expansion>,
+    /// The macro expansion being assembled, errors in it get a note showing the expanded line
+    expansion: Option<Expanded>,
+    /// Number of lines macro calls have expanded to so far
+    expanded: usize,
 }
 
 impl Assembler {
@@ -35,17 +170,26 @@ impl Assembler {
     pub fn new(code: String) -> Assembler {
         Assembler {
             code,
-            assembled: Vec::with_capacity(u16::MAX as usize),
+            assembled: Vec::new(),
             symbol_table: HashMap::new(),
             mem_to_code: HashMap::new(),
             errors: Vec::new(),
-            last_token_processed: Tokens::Newline,
-            line: 1,
-            cursor: 0,
-            data_inserts: HashMap::new(),
-            data_locations: HashMap::new(),
+            module: None,
+            exports: Vec::new(),
+            relocations: Vec::new(),
+            imports: Vec::new(),
+            line: 0,
+            span: None,
+            references: HashMap::new(),
+            duplicates: HashSet::new(),
+            constants: HashMap::new(),
+            locations: HashMap::new(),
             registers_used: vec![15],
             trap_index: None,
+            macros: HashMap::new(),
+            expansions: Vec::new(),
+            expansion: None,
+            expanded: 0,
         }
     }
 
@@ -54,347 +198,1561 @@ impl Assembler {
     /// Check for errors by ensuring Assembler.errors is of zero length
     pub fn assemble(&mut self) {
         let code = self.code.clone();
-        let lexer = Tokens::lexer(&code);
-
-        // Iterate over each token and process it
-        for token in lexer {
-            log!(Level::Info, "{token:?}");
-            match token {
-                Ok(token) => {
-                    let valid_token = self.validate_token(token.clone());
-                    match valid_token {
-                        Ok(_) => {
-                            self.parse_token(token.clone());
-                            self.last_token_processed = token;
-                        }
-                        Err(error) => {
-                            self.errors.push(error);
-                        }
+        let lines = self.read_lines(&code);
+
+        // Pass one -- give every label a value and every statement an address
+        let mut statements: Vec<(usize, Statement, Format, usize, usize)> = Vec::new();
+        let mut address = 0_usize;
+        let mut end = 0_usize;
+        for line in lines {
+            self.line = line.number;
+            self.span = None;
+            self.expansion = None;
+
+            let Some(statement) = line.statement else {
+                if let Some(label) = line.label {
+                    self.define_address(label, address);
+                }
+                continue;
+            };
+            self.expansion = statement.expansion.clone();
+            let Some(format) = Format::lookup(&statement.mnemonic) else {
+                if let Some(label) = line.label {
+                    self.define_address(label, address);
+                }
+                self.span = Some(statement.span.clone());
+                self.error(
+                    ErrorCode::UnknownInstruction,
+                    format!("Unknown instruction {}.", statement.mnemonic),
+                    "Check the spelling of the instruction.",
+                );
+                continue;
+            };
+
+            let label = line.label;
+            self.span = Some(statement.full_span());
+            let size = match format {
+                Format::Equ | Format::Import | Format::Module => {
+                    let label = label.map(|label| label.name);
+                    self.define_directive(label, &statement, format);
+                    continue;
+                }
+                Format::Org => {
+                    if let Some(label) = label {
+                        self.define_address(label, address);
+                    }
+                    if let Some(org) = self.directive_operand(&statement, 0xffff) {
+                        address = org as usize;
                     }
+                    continue;
                 }
-                Err(e) => {
-                    log!(Level::Warn, "uhh ohh {e:?}");
-                    self.errors.push(AssemblingError {
-                        message: "Unknown token.".to_string(),
-                        line: self.line,
-                        resolution: "".to_string(),
-                    });
+                Format::Block => {
+                    if let Some(label) = label {
+                        self.define_address(label, address);
+                    }
+                    self.directive_operand(&statement, 0xffff).unwrap_or(0) as usize
+                }
+                Format::Data => {
+                    if let Some(label) = label {
+                        self.define_address(label, address);
+                    }
+                    Self::data_size(&statement)
+                }
+                _ => {
+                    if let Some(label) = label {
+                        self.define_address(label, address);
+                    }
+                    format.size()
                 }
             };
+            if address + size > MEMORY_SIZE {
+                self.span = Some(statement.full_span());
+                self.error(
+                    ErrorCode::ProgramTooLarge,
+                    "Program does not fit in memory.".to_string(),
+                    "Programs can be at most 65536 words long, check the org and block directives.",
+                );
+                break;
+            }
+            if let Some(expanded) = &statement.expansion {
+                self.expansions.push(Expansion {
+                    line: line.number,
+                    text: expanded.text.clone(),
+                    address,
+                    size,
+                });
+            }
+            statements.push((line.number, statement, format, address, size));
+            address += size;
+            end = end.max(address);
         }
 
-        // Add variables
-        for (name, (dest, line)) in &self.data_locations {
-            println!("Adding {name} vars");
-            if let Some(locations) = self.data_inserts.get(name) {
-                for location in locations {
-                    self.assembled[*location as usize] = *dest;
+        // Pass two -- encode each statement now that every label is known
+        self.assembled = vec![0; end];
+        for (line, statement, format, address, size) in statements {
+            self.line = line;
+            self.span = Some(statement.full_span());
+            self.expansion = statement.expansion.clone();
+            if format == Format::Export {
+                self.export(&statement);
+                continue;
+            }
+            let words = self.encode(&statement, format, address, size);
+            for (i, word) in words.into_iter().enumerate() {
+                if let Some(&other) = self.mem_to_code.get(&(address + i)) {
+                    self.span = Some(statement.full_span());
+                    self.error(
+                        ErrorCode::AddressOverlap,
+                        format!(
+                            "Address {:#06x} is already used by line {other}.",
+                            address + i
+                        ),
+                        "Check the org directives do not overlap.",
+                    );
+                    self.add_note(
+                        other,
+                        None,
+                        format!("Line {other} is also at this address."),
+                    );
+                    break;
                 }
-                self.data_inserts.remove(name);
-            } else {
-                self.errors.push(AssemblingError {
-                    message: "Label is not used.".to_string(),
-                    line: *line,
-                    resolution: "Either a spelling mistake or it is planned to be used later.\nYou can add a jump to this label after trap R0,R0,R0 to get rid of this error.".to_string()
-                })
+                self.assembled[address + i] = word;
+                self.mem_to_code.insert(address + i, line);
             }
-            self.symbol_table
-                .insert(name.clone(), dest.clone() as usize);
         }
 
+        self.expansion = None;
+        // Constants and imports are left out, only labels of locations are expected to be used
+        let mut unused: Vec<(usize, Range<usize>)> = self
+            .locations
+            .iter()
+            .filter(|(name, _)| !self.references.contains_key(*name))
+            .map(|(name, span)| (self.symbol_table[name].line, span.clone()))
+            .collect();
+        unused.sort_unstable_by_key(|(line, span)| (*line, span.start));
+        for (line, span) in unused {
+            self.errors.push(
+                AssemblingError::new(
+                    ErrorCode::UnusedLabel,
+                    line,
+                    Some(span),
+                    "Label is not used.".to_string(),
+                    "Either a spelling mistake or it is planned to be used later.\nThe program can still run with this warning.",
+                )
+                .warning(),
+            );
+        }
+
+        self.errors.sort_by_key(|error| error.line);
         match self.trap_index {
             Some(_) => {}
-            None => self.errors.push(AssemblingError {
-                message: "No trap instruction, program will never terminate when run.".to_string(),
-                line: 0,
-                resolution: "Add \"trap R0,R0,R0\" at the end of the program.".to_string(),
-            }),
+            None => self.errors.push(AssemblingError::new(
+                ErrorCode::NoTrap,
+                0,
+                None,
+                "No trap instruction, program will never terminate when run.".to_string(),
+                "Add \"trap R0,R0,R0\" at the end of the program.",
+            )),
         }
-        // Any left over entries in data_inserts should result in error
 
-        self.assembled.shrink_to_fit();
         self.registers_used.sort_unstable();
     }
 
-    /// Verifies that tokens are in the correct order
-    /// Records errors where tokens are ordered incorrectly
-    /// For example: "add R3,variable[R0]"
-    ///     We dont want to compile IRX arguments for a RRR instruction
-    fn validate_token(&mut self, token: Tokens) -> Result<Tokens, AssemblingError> {
-        match token {
-            Tokens::Ignore | Tokens::Newline => Ok(token),
-            Tokens::Label(_) => match self.last_token_processed {
-                Tokens::Newline => Ok(token),
-                _ => Err(AssemblingError {
-                    message: "Labels need to be on a new line.".to_string(),
-                    line: self.line,
-                    resolution: "Put the label on a new line.".to_string(),
-                }),
-            },
-            Tokens::RRRArg(_) => match self.last_token_processed {
-                Tokens::RRR(_) => Ok(token),
-                _ => Err(AssemblingError {
-                    message: "Expected RRR instruction for RRR arguments.".to_string(),
-                    line: self.line,
-                    resolution: "Either incorrect arguments, or incorrect instruction.".to_string(),
-                }),
-            },
-
-            Tokens::RRArg(_) => match self.last_token_processed {
-                Tokens::RR(_) => Ok(token),
-                _ => Err(AssemblingError {
-                    message: "Expected RR instruction for RR arguments.".to_string(),
+    /// Adds a symbol to the symbol table
+    /// Labels are always in the first column, so errors point at 0..name.len()
+    fn define(&mut self, name: String, value: u16, kind: SymbolKind) {
+        if let Some(symbol) = self.symbol_table.get(&name) {
+            let first = symbol.line;
+            let span = self.span.replace(0..name.len());
+            self.error(
+                ErrorCode::DuplicateLabel,
+                format!("Label {name} is already defined on line {first}."),
+                "Labels can only be defined once, rename one of them.",
+            );
+            self.add_note(
+                first,
+                Some(0..name.len()),
+                format!("{name} is first defined here."),
+            );
+            self.span = span;
+            if self.duplicates.insert(name.clone()) {
+                let mut error = AssemblingError::new(
+                    ErrorCode::DuplicateLabel,
+                    first,
+                    Some(0..name.len()),
+                    format!("Label {name} is defined again on line {}.", self.line),
+                    "Labels can only be defined once, rename one of them.",
+                );
+                error.notes.push(Note {
                     line: self.line,
-                    resolution: "Either incorrect arguments, or incorrect instruction.".to_string(),
-                }),
-            },
+                    span: Some(0..name.len()),
+                    message: format!("{name} is defined again here."),
+                });
+                self.errors.push(error);
+            }
+            return;
+        }
+        let line = self.line;
+        self.symbol_table.insert(name, Symbol { value, kind, line });
+    }
 
-            Tokens::IRXArg(_) => match self.last_token_processed {
-                Tokens::IRX(_) => Ok(token),
-                _ => Err(AssemblingError {
-                    message: "Expected IRX instruction for IRX arguments.".to_string(),
-                    line: self.line,
-                    resolution: "Either incorrect arguments, or incorrect instruction.".to_string(),
-                }),
-            },
+    /// Records the address of a label
+    fn define_address(&mut self, label: Label, address: usize) {
+        if !self.symbol_table.contains_key(&label.name) {
+            self.locations.insert(label.name.clone(), label.span);
+        }
+        self.define(label.name, address as u16, SymbolKind::Relocatable);
+    }
 
-            Tokens::RRR(_) | Tokens::RR(_) | Tokens::IRX(_) | Tokens::Jump(_) | Tokens::Data(_) => {
-                match self.last_token_processed {
-                    Tokens::Newline | Tokens::Label(_) => Ok(token),
-                    _ => Err(AssemblingError {
-                        message: "Instruction not on a new line.".to_string(),
-                        line: self.line,
-                        resolution: "Start instruction on a new line".to_string(),
-                    }),
+    /// Handles the directives that name something: equ, import and module
+    fn define_directive(&mut self, label: Option<String>, statement: &Statement, format: Format) {
+        let Some(label) = label else {
+            self.error(
+                ErrorCode::InvalidDirective,
+                format!("{} needs a label.", statement.mnemonic),
+                "Put the name being defined in the first column, for example: n equ 10",
+            );
+            return;
+        };
+        let operands = &statement.operands;
+        match format {
+            // n equ value
+            Format::Equ => {
+                if !self.expect_operands(statement, 1) {
+                    return;
+                }
+                self.point_at(&operands[0]);
+                let OperandKind::Expr(expr) = &operands[0].kind else {
+                    self.error(
+                        ErrorCode::ExpectedConstant,
+                        format!("Expected a value for {label}, found {}.", operands[0]),
+                        "equ defines a name for a number or address.",
+                    );
+                    return;
+                };
+                match self.resolve(expr) {
+                    Some(Value::Constant(value)) if (-32768..=0xffff).contains(&value) => {
+                        if !self.symbol_table.contains_key(&label) {
+                            self.constants.insert(label.clone(), value);
+                        }
+                        self.define(label, value as u16, SymbolKind::Constant);
+                    }
+                    Some(Value::Relocatable(value)) => {
+                        self.define(label, value as u16, SymbolKind::Relocatable);
+                    }
+                    Some(Value::Constant(_)) => {
+                        self.error(
+                            ErrorCode::OutOfRange,
+                            format!("{expr} does not fit in 16 bits."),
+                            "Constants are from -32768 to 65535.",
+                        );
+                    }
+                    Some(Value::Imported(..)) => {
+                        self.error(
+                            ErrorCode::NotRelocatable,
+                            format!("{label} cannot be defined as the imported {expr}."),
+                            "Use the imported name directly.",
+                        );
+                    }
+                    None => {}
+                }
+            }
+            // x import module,name
+            // The name defaults to the label
+            Format::Import => {
+                let names: Vec<Option<&String>> = operands
+                    .iter()
+                    .map(|operand| match &operand.kind {
+                        OperandKind::Expr(Expr::Symbol(name)) => Some(name),
+                        _ => None,
+                    })
+                    .collect();
+                match names.as_slice() {
+                    [Some(module)] => {
+                        let kind = SymbolKind::Imported {
+                            module: module.to_string(),
+                            name: label.clone(),
+                        };
+                        self.define(label, 0, kind);
+                    }
+                    [Some(module), Some(name)] => {
+                        let kind = SymbolKind::Imported {
+                            module: module.to_string(),
+                            name: name.to_string(),
+                        };
+                        self.define(label, 0, kind);
+                    }
+                    _ => self.error(
+                        ErrorCode::InvalidDirective,
+                        format!("Expected a module and symbol to import for {label}."),
+                        "Imports are written: x import Module,y",
+                    ),
                 }
             }
+            // name module
+            Format::Module => {
+                if !self.expect_operands(statement, 0) {
+                    return;
+                }
+                match &self.module {
+                    Some(module) => self.error(
+                        ErrorCode::InvalidDirective,
+                        format!("This program is already the module {module}."),
+                        "Each program can only be one module, put other modules in their own editor.",
+                    ),
+                    None => self.module = Some(label),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Evaluates the operand of org or block, it must only use symbols defined above it
+    fn directive_operand(&mut self, statement: &Statement, max: u16) -> Option<u16> {
+        if !self.expect_operands(statement, 1) {
+            return None;
         }
+        self.constant(&statement.operands[0], max)
     }
 
-    /// Parses tokens into byte code
-    /// Tokens are only passed to here if the token did not create an error
-    fn parse_token(&mut self, token: Tokens) {
-        match token {
-            // Base instructions, all instructions are created the same way
-            Tokens::RRR(instruction) | Tokens::RR(instruction) | Tokens::IRX(instruction) => {
-                self.assembled.push(instruction);
-                self.mem_to_code.insert(self.cursor, self.line);
-                self.cursor += 1;
+    /// export x,y
+    fn export(&mut self, statement: &Statement) {
+        for operand in &statement.operands {
+            self.point_at(operand);
+            let OperandKind::Expr(Expr::Symbol(name)) = &operand.kind else {
+                self.error(
+                    ErrorCode::InvalidDirective,
+                    format!("Expected a label to export, found {operand}."),
+                    "Exports are written: export x",
+                );
+                continue;
+            };
+            match self.symbol_table.get(name).map(|symbol| &symbol.kind) {
+                Some(SymbolKind::Imported { .. }) => self.error(
+                    ErrorCode::InvalidDirective,
+                    format!("{name} is imported, it cannot be exported."),
+                    "Only symbols defined in this module can be exported.",
+                ),
+                Some(_) => {
+                    self.reference(name);
+                    if !self.exports.contains(name) {
+                        self.exports.push(name.clone());
+                    }
+                }
+                None => self.error(
+                    ErrorCode::UndefinedLabel,
+                    format!("Label {name} is not defined."),
+                    "Either a spelling mistake or the label is missing.",
+                ),
             }
+        }
+    }
+
+    /// Parses every line, defining macros and replacing each macro call with the lines it expands to
+    fn read_lines(&mut self, code: &str) -> Vec<Line> {
+        let mut lines: Vec<Line> = Vec::new();
+        // The macro being defined, with no name if its definition is invalid
+        let mut defining: Option<(Option<String>, Macro)> = None;
+        for (i, text) in code.lines().enumerate() {
+            self.line = i + 1;
+            let source = match comment_start(text) {
+                Some(start) => &text[..start],
+                None => text,
+            };
+            let words = fields(source);
+            self.span = words
+                .first()
+                .map(|first| first.start..source.trim_end().len());
+            let first = words
+                .first()
+                .map(|first| source[first.clone()].to_ascii_lowercase());
 
-            // RRR arguments
-            // Gets the arguments and bit wise ors them with the instruction
-            Tokens::RRRArg(args) => {
-                let reg: u16 = self.parse_rnargs(args, 3);
-                self.assembled[self.cursor - 1] |= reg;
-                if self.assembled[self.cursor - 1] == 0xc000_u16 {
-                    self.trap_index = Some(self.cursor - 1);
+            if let Some((name, mut definition)) = defining.take() {
+                match first.as_deref() {
+                    Some("endm") => {
+                        if let Some(name) = name {
+                            self.macros.insert(name.to_ascii_lowercase(), definition);
+                        }
+                    }
+                    Some("macro") => {
+                        self.error(
+                            ErrorCode::InvalidMacro,
+                            "Macros cannot be defined inside another macro.".to_string(),
+                            "End the macro being defined with endm first.",
+                        );
+                        defining = Some((name, definition));
+                    }
+                    _ => {
+                        definition.body.push(source.trim_end().to_string());
+                        defining = Some((name, definition));
+                    }
                 }
+                continue;
             }
+            match first.as_deref() {
+                Some("macro") => defining = Some(self.define_macro(source, &words)),
+                Some("endm") => self.error(
+                    ErrorCode::InvalidMacro,
+                    "endm without a macro.".to_string(),
+                    "Macros start with: macro name param,param",
+                ),
+                _ => match parse_line(self.line, text, |name| self.is_macro(name)) {
+                    Ok(line) => {
+                        self.expand(line, text, None, &mut Vec::new(), &mut lines);
+                    }
+                    Err(error) => self.errors.push(error),
+                },
+            }
+        }
+        if let Some((Some(name), definition)) = defining {
+            self.line = definition.line;
+            self.span = None;
+            self.error(
+                ErrorCode::InvalidMacro,
+                format!("Macro {name} has no endm."),
+                "End the macro with a line holding endm.",
+            );
+        }
+        lines
+    }
+
+    /// macro name param,param
+    fn define_macro(&mut self, source: &str, words: &[Range<usize>]) -> (Option<String>, Macro) {
+        let params: Vec<String> = words
+            .iter()
+            .skip(2)
+            .map(|word| &source[word.clone()])
+            .collect::<String>()
+            .split(',')
+            .filter(|param| !param.is_empty())
+            .map(str::to_string)
+            .collect();
+        let definition = Macro {
+            params: params.clone(),
+            body: Vec::new(),
+            line: self.line,
+        };
+        let Some(word) = words.get(1) else {
+            self.error(
+                ErrorCode::InvalidMacro,
+                "Macro has no name.".to_string(),
+                "Macros start with: macro name param,param",
+            );
+            return (None, definition);
+        };
+        self.span = Some(word.clone());
+        let name = source[word.clone()].to_string();
+        let reserved = ["macro", "endm"].contains(&name.to_ascii_lowercase().as_str());
+        if !is_identifier(&name) || reserved || Format::lookup(&name).is_some() {
+            self.error(
+                ErrorCode::InvalidMacro,
+                format!("Invalid macro name {name}."),
+                "Macro names start with a letter and cannot be an instruction or directive.",
+            );
+            return (None, definition);
+        }
+        if let Some(first) = self
+            .macros
+            .get(&name.to_ascii_lowercase())
+            .map(|first| first.line)
+        {
+            self.error(
+                ErrorCode::InvalidMacro,
+                format!("Macro {name} is defined more than once."),
+                "Rename one of the macros.",
+            );
+            self.add_note(first, None, "First defined here.".to_string());
+            return (None, definition);
+        }
+        if let Some(param) = params.iter().find(|param| !is_identifier(param)) {
+            self.span = words
+                .get(2)
+                .map(|_| words[2].start..words[words.len() - 1].end);
+            self.error(
+                ErrorCode::InvalidMacro,
+                format!("Invalid macro parameter {param}."),
+                "Parameters are names separated by commas, for example: macro swap a,b",
+            );
+            return (None, definition);
+        }
+        (Some(name), definition)
+    }
+
+    /// Macro names are kept in lower case, so like mnemonics they can be called in any case
+    fn is_macro(&self, name: &str) -> bool {
+        self.macros.contains_key(&name.to_ascii_lowercase())
+    }
 
-            // RR arguments
-            // Gets the arguments and bit wise ors them with the instruction
-            Tokens::RRArg(args) => {
-                let reg: u16 = self.parse_rnargs(args, 2);
-                self.assembled[self.cursor - 1] |= reg;
+    /// Adds the line to the program, or the lines it expands to if it calls a macro
+    /// Expanded lines point at the outermost call, so errors in them underline the call
+    /// calling holds the macros being expanded, returns false when the call stops expanding
+    fn expand(
+        &mut self,
+        mut line: Line,
+        text: &str,
+        call: Option<Range<usize>>,
+        calling: &mut Vec<String>,
+        lines: &mut Vec<Line>,
+    ) -> bool {
+        let definition = match &line.statement {
+            Some(statement) if Format::lookup(&statement.mnemonic).is_none() => self
+                .macros
+                .get(&statement.mnemonic.to_ascii_lowercase())
+                .cloned()
+                .or_else(|| pseudo(&statement.mnemonic)),
+            _ => None,
+        };
+        let (Some(definition), Some(statement)) = (definition, &line.statement) else {
+            if let Some(call) = &call {
+                line.point_at(call);
+            }
+            lines.push(line);
+            return true;
+        };
+
+        let name = statement.mnemonic.clone();
+        let call = call.unwrap_or(statement.full_span());
+        self.line = line.number;
+        self.span = Some(call.clone());
+        let key = name.to_ascii_lowercase();
+        if calling.contains(&key) {
+            self.error(
+                ErrorCode::InvalidMacro,
+                format!("Macro {name} calls itself."),
+                "Macros cannot call themselves, directly or through other macros.",
+            );
+            return false;
+        }
+        if statement.operands.len() != definition.params.len() {
+            self.error(
+                ErrorCode::OperandCount,
+                format!(
+                    "{name} expects {} operands, found {}.",
+                    definition.params.len(),
+                    statement.operands.len()
+                ),
+                "Either incorrect arguments, or incorrect macro.",
+            );
+            return true;
+        }
+        let args: Vec<&str> = statement
+            .operands
+            .iter()
+            .map(|operand| &text[operand.span.clone()])
+            .collect();
+
+        // The label of the call is put on the first word of the expansion
+        // A label in a macro body points at the outermost call, like the rest of its line
+        if let Some(mut label) = line.label.take() {
+            if !calling.is_empty() {
+                label.span = call.clone();
+            }
+            lines.push(Line {
+                number: line.number,
+                label: Some(label),
+                ..Default::default()
+            });
+        }
+        let body = definition.expand(&args);
+        self.expanded += body.len();
+        if self.expanded > MAX_EXPANDED {
+            // Only the call that goes over the limit is reported
+            if self.expanded - body.len() <= MAX_EXPANDED {
+                self.error(
+                    ErrorCode::InvalidMacro,
+                    format!("Macro calls expand to more than {MAX_EXPANDED} lines."),
+                    "Check the macros do not call each other more times than needed.",
+                );
             }
+            return false;
+        }
+        calling.push(key);
+        for expanded in body {
+            match parse_line(line.number, &expanded, |name| self.is_macro(name)) {
+                Ok(mut inner) => {
+                    if let Some(statement) = &mut inner.statement {
+                        statement.expansion.get_or_insert(Expanded {
+                            name: name.clone(),
+                            text: expanded.trim().to_string(),
+                        });
+                    }
+                    if !self.expand(inner, &expanded, Some(call.clone()), calling, lines) {
+                        calling.pop();
+                        return false;
+                    }
+                }
+                Err(mut error) => {
+                    error.span = Some(call.clone());
+                    error.notes.push(Note {
+                        line: line.number,
+                        span: None,
+                        message: format!("In the expansion of {name}: {}", expanded.trim()),
+                    });
+                    self.errors.push(error);
+                }
+            }
+        }
+        calling.pop();
+        true
+    }
 
-            // IRX arguments
-            // Gets the arguments and bit wise ors them with the instruction
-            // Pushes the address to the next memory address
-            Tokens::IRXArg(args) => {
-                let (register, address) = self.parse_irxargs(&args);
+    fn expect_operands(&mut self, statement: &Statement, arity: usize) -> bool {
+        if statement.operands.len() != arity {
+            self.error(
+                ErrorCode::OperandCount,
+                format!(
+                    "{} expects {arity} operands, found {}.",
+                    statement.mnemonic,
+                    statement.operands.len()
+                ),
+                "Either incorrect arguments, or incorrect instruction.",
+            );
+            return false;
+        }
+        true
+    }
+
+    /// Encodes a statement into the words it takes in memory
+    /// Statements with errors are still the right size so later addresses do not move
+    fn encode(
+        &mut self,
+        statement: &Statement,
+        format: Format,
+        address: usize,
+        size: usize,
+    ) -> Vec<u16> {
+        match format {
+            // Reserved words are all 0, the size was checked in pass one
+            Format::Block => return vec![0; size],
+            Format::Data => return self.data(statement, address),
+            _ => {}
+        }
+        let mut words = vec![0_u16; size];
+        let arity = match format {
+            Format::Rrr(_) => 3,
+            Format::Rr(_) | Format::Rx(_) | Format::Jumpc(_) => 2,
+            Format::Exp(secondary) => Self::exp_arity(secondary),
+            _ => 1,
+        };
+        let operands = &statement.operands;
+        if !self.expect_operands(statement, arity) {
+            return words;
+        }
 
-                self.assembled[self.cursor - 1] |= register;
-                self.assembled.push(address);
-                self.mem_to_code.insert(self.cursor, self.line);
-                self.cursor += 1;
+        match format {
+            Format::Rrr(op) | Format::Rr(op) => {
+                words[0] = op;
+                // Registers fill the instruction from the right: Rd,Ra,Rb or Ra,Rb
+                for (i, operand) in operands.iter().rev().enumerate() {
+                    words[0] |= self.register(operand).unwrap_or(0) << (4 * i);
+                }
+                if words[0] == 0xc000 {
+                    self.trap_index = Some(address);
+                }
+            }
+            Format::Rx(op) => {
+                let rd = self.register(&operands[0]).unwrap_or(0);
+                let (disp, ra) = self
+                    .indexed(&operands[1], false, address + 1)
+                    .unwrap_or((0, 0));
+                words[0] = op | rd << 8 | ra << 4;
+                words[1] = disp;
             }
+            Format::Jump(op) => {
+                let (disp, ra) = self
+                    .indexed(&operands[0], true, address + 1)
+                    .unwrap_or((0, 0));
+                words[0] = op | ra << 4;
+                words[1] = disp;
+            }
+            Format::Jumpc(op) => {
+                let bit = self.constant(&operands[0], 15).unwrap_or(0);
+                let (disp, ra) = self
+                    .indexed(&operands[1], false, address + 1)
+                    .unwrap_or((0, 0));
+                words[0] = op | bit << 8 | ra << 4;
+                words[1] = disp;
+            }
+            Format::Exp(secondary) => {
+                // Every EXP instruction other than resume has a register as the first operand
+                let rd = match operands.first() {
+                    Some(operand) => self.register(operand).unwrap_or(0),
+                    None => 0,
+                };
+                words[0] = 0xe000 | rd << 8 | secondary;
+                words[1] = self.exp_operands(secondary, operands, address);
+            }
+            _ => {}
+        }
+        words
+    }
 
-            // Labels -- anything that goes in the symbol table
-            // Not actual instructions or arguments, records the memory location it was
-            // created at for later processing
-            Tokens::Label(arg) => {
-                let label = arg.replace(" ", "");
+    /// Number of words in a data statement, strings take one word per character
+    fn data_size(statement: &Statement) -> usize {
+        statement
+            .operands
+            .iter()
+            .map(|operand| match &operand.kind {
+                OperandKind::String(text) => text.chars().count(),
+                _ => 1,
+            })
+            .sum()
+    }
 
-                self.data_locations
-                    .insert(label, (self.cursor as u16, self.line));
+    /// data 1,'a',"text",x+1
+    fn data(&mut self, statement: &Statement, address: usize) -> Vec<u16> {
+        if statement.operands.is_empty() {
+            self.error(
+                ErrorCode::OperandCount,
+                "data needs at least one value.".to_string(),
+                "Values are separated by commas, for example: data 1,2,3",
+            );
+        }
+        let mut words = Vec::new();
+        for operand in &statement.operands {
+            match &operand.kind {
+                OperandKind::String(text) => {
+                    self.point_at(operand);
+                    for c in text.chars() {
+                        words.push(self.character(c).unwrap_or(0));
+                    }
+                }
+                _ => {
+                    let word = self.word(operand, address + words.len()).unwrap_or(0);
+                    words.push(word);
+                }
             }
+        }
+        words
+    }
 
-            // Data -- anything that is artificially inserted into memory
-            // Can be a decimal constant, hexadecimal constant, or a label
-            Tokens::Data(args) => {
-                regex!(
-                    regex = r" +data +(?:(P?<var>[a-zA-Z][a-zA-Z0-9_]*)|(?P<const>[0-9]+)|(?P<hex>\$[a-fA-F0-9]{4}))"
+    /// Characters are stored one per word
+    fn character(&mut self, c: char) -> Option<u16> {
+        match u16::try_from(c as u32) {
+            Ok(word) => Some(word),
+            Err(_) => {
+                self.error(
+                    ErrorCode::OutOfRange,
+                    format!("The character {c} does not fit in 16 bits."),
+                    "Only characters with a code up to 65535 can be stored.",
                 );
+                None
+            }
+        }
+    }
+
+    fn exp_arity(secondary: u16) -> usize {
+        match secondary {
+            0x00 => 0,
+            0x0b | 0x0c | 0x0f => 2,
+            0x08 | 0x0a => 4,
+            0x09 => 5,
+            _ => 3,
+        }
+    }
 
-                let extracted = regex.captures(&args).unwrap();
-
-                if let Some(value) = extracted.name("hex") {
-                    // Match hex
-                    self.assembled
-                        .push(u16::from_str_radix(&value.as_str()[1..], 16).unwrap());
-                } else if let Some(value) = extracted.name("const") {
-                    // Match constant
-                    self.assembled.push(value.as_str().parse::<u16>().unwrap());
-                } else if let Some(value) = extracted.name("var") {
-                    // Match variable
-                    self.assembled.push(0_u16);
-
-                    self.data_inserts
-                        .entry(value.as_str().parse().unwrap())
-                        .or_default()
-                        .push(self.cursor as u16);
+    /// Encodes the second word of an EXP instruction
+    fn exp_operands(&mut self, secondary: u16, operands: &[Operand], address: usize) -> u16 {
+        match secondary {
+            // save Rd,Re,disp[Rf]
+            0x01 | 0x02 => {
+                let re = self.register(&operands[1]).unwrap_or(0);
+                self.point_at(&operands[2]);
+                match &operands[2].kind {
+                    OperandKind::Indexed(disp, rf) => {
+                        self.use_register(*rf);
+                        let disp = self.value(disp, 0, 0xff).unwrap_or(0);
+                        re << 12 | (*rf as u16) << 8 | disp
+                    }
+                    _ => {
+                        self.error(
+                            ErrorCode::ExpectedIndexed,
+                            format!("Expected disp[Rf], found {}.", operands[2]),
+                            "The displacement is a constant from 0 to 255, for example 0[R14].",
+                        );
+                        0
+                    }
+                }
+            }
+            // push Rd,Ra,Rb
+            0x03..=0x05 => {
+                let ra = self.register(&operands[1]).unwrap_or(0);
+                let rb = self.register(&operands[2]).unwrap_or(0);
+                ra << 12 | rb << 8
+            }
+            // shiftl Rd,Ra,k
+            0x06 | 0x07 => {
+                let ra = self.register(&operands[1]).unwrap_or(0);
+                let k = self.constant(&operands[2], 15).unwrap_or(0);
+                ra << 12 | k << 8
+            }
+            // logicw Rd,Ra,Rb,k
+            0x08 => {
+                let ra = self.register(&operands[1]).unwrap_or(0);
+                let rb = self.register(&operands[2]).unwrap_or(0);
+                let k = self.constant(&operands[3], 15).unwrap_or(0);
+                ra << 12 | rb << 8 | k << 4
+            }
+            // logicf Rd,fd,fa,fb,k
+            0x09 => {
+                let mut fields = 0_u16;
+                for (i, operand) in operands[1..].iter().enumerate() {
+                    fields |= self.constant(operand, 15).unwrap_or(0) << (12 - 4 * i);
+                }
+                fields
+            }
+            // extract Rd,Ra,start,width
+            0x0a => {
+                let ra = self.register(&operands[1]).unwrap_or(0);
+                let start = self.constant(&operands[2], 15).unwrap_or(0);
+                let width = self.constant(&operands[3], 16).unwrap_or(1);
+                if width == 0 {
+                    self.error(
+                        ErrorCode::ExpectedConstant,
+                        "extract needs a width of at least 1.".to_string(),
+                        "The width is the number of bits to extract, from 1 to 16.",
+                    );
                 }
-                //self.data_locations
-                //    .insert(name, (self.cursor as u16, self.line));
-                self.mem_to_code.insert(self.cursor, self.line);
-                self.cursor += 1;
+                ra << 12 | start << 8 | (width.max(1) - 1) << 4
             }
+            // getctl Rd,ctl
+            0x0b | 0x0c => {
+                self.point_at(&operands[1]);
+                let ctl = match &operands[1].kind {
+                    OperandKind::Expr(Expr::Symbol(name)) => control_register(name),
+                    _ => None,
+                };
+                match ctl {
+                    Some(ctl) => (ctl as u16) << 12,
+                    None => {
+                        self.error(
+                            ErrorCode::UnknownControlRegister,
+                            format!("Unknown control register {}.", operands[1]),
+                            &format!("Control registers are {}.", CONTROL_REGISTERS.join(", ")),
+                        );
+                        0
+                    }
+                }
+            }
+            // brc0 Rd,bit,label
+            0x0d | 0x0e => {
+                let bit = self.constant(&operands[1], 15).unwrap_or(0);
+                bit << 12 | self.branch_offset(&operands[2], address)
+            }
+            // dsptch Rd,n
+            0x0f => self.constant(&operands[1], 0xffff).unwrap_or(0),
+            _ => 0,
+        }
+    }
 
-            // Jump instructions
-            // Handles all jump instructions
-            Tokens::Jump(command) => {
-                let (instruction, address) = self.parse_jump(command);
-                self.assembled.push(instruction);
-                self.assembled.push(address);
-                self.mem_to_code.insert(self.cursor, self.line);
-                self.cursor += 1;
-                self.mem_to_code.insert(self.cursor, self.line);
-                self.cursor += 1;
+    /// 12 bit offset of a branch, relative to the address after the instruction
+    fn branch_offset(&mut self, operand: &Operand, address: usize) -> u16 {
+        self.point_at(operand);
+        let OperandKind::Expr(expr) = &operand.kind else {
+            self.error(
+                ErrorCode::InvalidOperand,
+                format!("Expected a label to branch to, found {operand}."),
+                "Put a label on the line to branch to.",
+            );
+            return 0;
+        };
+        let dest = match self.resolve(expr) {
+            Some(Value::Relocatable(dest)) | Some(Value::Constant(dest)) => dest,
+            Some(Value::Imported(..)) => {
+                self.error(
+                    ErrorCode::NotRelocatable,
+                    format!("Cannot branch to the imported {expr}."),
+                    "Conditional branches can only reach labels in the same module, use a jump instead.",
+                );
+                return 0;
             }
+            None => return 0,
+        };
+        let offset = dest - (address as i64 + 2);
+        if (-2048..=2047).contains(&offset) {
+            (offset as u16) & 0x0fff
+        } else {
+            self.error(
+                ErrorCode::BranchTooFar,
+                format!("Branch to {expr} is too far away."),
+                "Conditional branches can only reach 2047 words in either direction, use a jump instead.",
+            );
+            0
+        }
+    }
 
-            // Tokens that do not insert into memory
-            Tokens::Ignore => {}
-            Tokens::Newline => {
-                self.line += 1;
+    /// Checks an operand is a register
+    fn register(&mut self, operand: &Operand) -> Option<u16> {
+        self.point_at(operand);
+        match operand.kind {
+            OperandKind::Register(reg) => {
+                self.use_register(reg);
+                Some(reg as u16)
+            }
+            _ => {
+                self.error(
+                    ErrorCode::ExpectedRegister,
+                    format!("Expected a register, found {operand}."),
+                    "Registers are written R0 to R15.",
+                );
+                None
             }
         }
     }
 
-    /// Processes RRR and RR instruction arguments
-    fn parse_rnargs(&mut self, args: String, n: usize) -> u16 {
-        let mut arg = 0_u16;
-        let mut temp = 0_u16;
+    fn use_register(&mut self, reg: u8) {
+        if !self.registers_used.contains(&(reg as usize)) {
+            self.registers_used.push(reg as usize);
+        }
+    }
 
-        // Reverse Rd,Ra,Rb or Ra,..[Rb] or Ra,Rb then loop over them
-        for (i, reg) in args.rsplit(',').enumerate().take(n) {
-            temp = reg[1..].parse::<u16>().unwrap();
-            arg |= temp << (4 * i as u16);
-            if !self.registers_used.contains(&(temp as usize)) {
-                self.registers_used.push(temp as usize);
+    /// Checks an operand is disp[Ra], returns (disp, Ra)
+    /// When the index is optional a plain disp uses R0
+    /// at is the address the displacement is stored in
+    fn indexed(&mut self, operand: &Operand, optional: bool, at: usize) -> Option<(u16, u16)> {
+        self.point_at(operand);
+        match &operand.kind {
+            OperandKind::Indexed(disp, ra) => {
+                self.use_register(*ra);
+                Some((self.address(disp, at)?, *ra as u16))
+            }
+            OperandKind::Expr(disp) if optional => Some((self.address(disp, at)?, 0)),
+            _ => {
+                self.error(
+                    ErrorCode::ExpectedIndexed,
+                    format!("Expected disp[Ra], found {operand}."),
+                    "The address is a label or constant with an index register, for example x[R0].",
+                );
+                None
             }
         }
+    }
 
-        arg
+    /// Checks an operand is a constant from 0 to max
+    fn constant(&mut self, operand: &Operand, max: u16) -> Option<u16> {
+        self.point_at(operand);
+        match &operand.kind {
+            OperandKind::Expr(expr) => self.value(expr, 0, max),
+            _ => {
+                self.error(
+                    ErrorCode::ExpectedConstant,
+                    format!("Expected a constant from 0 to {max}, found {operand}."),
+                    "Constants are written in decimal, or in hexadecimal starting with $.",
+                );
+                None
+            }
+        }
     }
 
-    /// Processes IRX instruction arguments
-    /// Extracts the arguments then the address
-    /// Returns (arguments, address) both as u16
-    fn parse_irxargs(&mut self, args: &str) -> (u16, u16) {
-        regex!(
-            regex = r"[Rr](?P<rd>[0-9]|1[0-5]),(?:(?P<var_match>[a-zA-Z][a-zA-Z0-9]*)|(?P<cons>[0-9]+)|(?P<hex>\$[a-fA-F0-9]{4}))\[[Rr](?P<disp>[0-9]|1[0-5])]"
-        );
+    /// Checks an operand is a 16 bit word, at is the address the word is stored in
+    fn word(&mut self, operand: &Operand, at: usize) -> Option<u16> {
+        self.point_at(operand);
+        match &operand.kind {
+            OperandKind::Expr(expr) => self.address(expr, at),
+            _ => {
+                self.error(
+                    ErrorCode::InvalidOperand,
+                    format!("Expected a value, found {operand}."),
+                    "Values are constants or labels.",
+                );
+                None
+            }
+        }
+    }
 
-        let mut arg = 0_u16;
-        let mut addr = 0_u16;
-        let extarcted_args = regex.captures(args).unwrap();
-
-        let rd = extarcted_args
-            .name("rd")
-            .unwrap()
-            .as_str()
-            .parse::<u16>()
-            .unwrap();
-        let disp = extarcted_args
-            .name("disp")
-            .unwrap()
-            .as_str()
-            .parse::<u16>()
-            .unwrap();
-
-        // 0xf{rd}{disp}1
-        arg |= rd << 8;
-        arg |= disp << 4;
-
-        if !self.registers_used.contains(&(rd as usize)) {
-            self.registers_used.push(rd as usize);
-        }
-
-        if !self.registers_used.contains(&(disp as usize)) {
-            self.registers_used.push(disp as usize);
-        }
-
-        if let Some(cons) = extarcted_args.name("cons") {
-            // If constant in addr
-
-            addr = cons.as_str().parse::<u16>().unwrap();
-        } else if let Some(var_match) = extarcted_args.name("var_match") {
-            // If variable in addr
-            let var = var_match.as_str();
-            // If variable mapping exists, add to the vec, otherwise create mapping
-            self.data_inserts
-                .entry(var.parse().unwrap())
-                .or_default()
-                .push(self.cursor as u16);
-        } else if let Some(hex) = extarcted_args.name("hex") {
-            // If hex in addr
-            addr = u16::from_str_radix(&hex.as_str()[1..], 16).unwrap();
+    /// Evaluates a constant expression and checks it is in the range min to max
+    fn value(&mut self, expr: &Expr, min: i64, max: u16) -> Option<u16> {
+        let value = match self.resolve(expr)? {
+            Value::Constant(value) => value,
+            _ => {
+                self.error(
+                    ErrorCode::ExpectedConstant,
+                    format!("{expr} is an address, a constant is needed here."),
+                    "Define constants with equ, for example: n equ 10",
+                );
+                return None;
+            }
+        };
+        if (min..=max as i64).contains(&value) {
+            Some(value as u16)
         } else {
-            // This is unreachable as the token would not have been created if it was reachable
-            // This is here to satisfy the Rust compiler
-            println!("Unknown argument");
+            self.error(
+                ErrorCode::ExpectedConstant,
+                format!("Expected a constant from {min} to {max}, found {expr}."),
+                "Constants are written in decimal, or in hexadecimal starting with $.",
+            );
+            None
+        }
+    }
+
+    /// Evaluates a 16 bit word that may be an address, at is the address the word is stored in
+    /// Records where relocatable and imported addresses are, so the word can be fixed up when linking
+    /// Negative constants are stored as two's complement
+    fn address(&mut self, expr: &Expr, at: usize) -> Option<u16> {
+        let value = self.resolve(expr)?;
+        let (Value::Constant(number) | Value::Relocatable(number) | Value::Imported(_, number)) =
+            value.clone();
+        if !(-32768..=0xffff).contains(&number) {
+            self.error(
+                ErrorCode::OutOfRange,
+                format!("{expr} does not fit in 16 bits."),
+                "Values are from -32768 to 65535.",
+            );
+            return None;
+        }
+        match value {
+            Value::Relocatable(_) => self.relocations.push(at),
+            Value::Imported(name, _) => self.imports.push((at, name)),
+            Value::Constant(_) => {}
+        }
+        Some(number as u16)
+    }
+
+    /// Evaluates an expression, looking up labels in the symbol table
+    /// Addresses can have constants added or subtracted, and the difference of two addresses
+    /// is a constant, anything else could not be relocated
+    fn resolve(&mut self, expr: &Expr) -> Option<Value> {
+        match expr {
+            Expr::Number(value) => Some(Value::Constant(*value)),
+            Expr::Neg(inner) => match self.resolve(inner)? {
+                Value::Constant(value) => Some(Value::Constant(-value)),
+                _ => {
+                    self.error(
+                        ErrorCode::NotRelocatable,
+                        format!("Cannot negate the address {inner}."),
+                        "Only constants can be negative.",
+                    );
+                    None
+                }
+            },
+            Expr::Binary(left, op, right) => {
+                // Evaluate both sides so every label is marked as used
+                let (left, right) = (self.resolve(left), self.resolve(right));
+                let value = match (left?, op, right?) {
+                    (Value::Constant(a), BinaryOp::Add, Value::Constant(b)) => {
+                        Value::Constant(a + b)
+                    }
+                    (Value::Constant(a), BinaryOp::Sub, Value::Constant(b)) => {
+                        Value::Constant(a - b)
+                    }
+                    (Value::Relocatable(a), BinaryOp::Add, Value::Constant(b))
+                    | (Value::Constant(b), BinaryOp::Add, Value::Relocatable(a)) => {
+                        Value::Relocatable(a + b)
+                    }
+                    (Value::Relocatable(a), BinaryOp::Sub, Value::Constant(b)) => {
+                        Value::Relocatable(a - b)
+                    }
+                    (Value::Relocatable(a), BinaryOp::Sub, Value::Relocatable(b)) => {
+                        Value::Constant(a - b)
+                    }
+                    (Value::Imported(name, a), BinaryOp::Add, Value::Constant(b))
+                    | (Value::Constant(b), BinaryOp::Add, Value::Imported(name, a)) => {
+                        Value::Imported(name, a + b)
+                    }
+                    (Value::Imported(name, a), BinaryOp::Sub, Value::Constant(b)) => {
+                        Value::Imported(name, a - b)
+                    }
+                    _ => {
+                        self.error(
+                            ErrorCode::NotRelocatable,
+                            format!("{expr} cannot be relocated."),
+                            "Addresses can only have constants added or subtracted, or be subtracted from another address in this module.",
+                        );
+                        return None;
+                    }
+                };
+                Some(value)
+            }
+            Expr::Symbol(name) => match self.symbol_table.get(name) {
+                Some(symbol) => {
+                    let value = match symbol.kind {
+                        SymbolKind::Constant => Value::Constant(
+                            self.constants
+                                .get(name)
+                                .copied()
+                                .unwrap_or(symbol.value as i64),
+                        ),
+                        SymbolKind::Relocatable => Value::Relocatable(symbol.value as i64),
+                        SymbolKind::Imported { .. } => Value::Imported(name.clone(), 0),
+                    };
+                    self.reference(name);
+                    Some(value)
+                }
+                None => {
+                    self.error(
+                        ErrorCode::UndefinedLabel,
+                        format!("Label {name} is not defined."),
+                        "Either a spelling mistake or the label is missing.",
+                    );
+                    None
+                }
+            },
         }
+    }
+
+    /// Records a use of a symbol on the current line
+    fn reference(&mut self, name: &str) {
+        let lines = self.references.entry(name.to_string()).or_default();
+        if lines.last() != Some(&self.line) {
+            lines.push(self.line);
+        }
+    }
 
-        (arg, addr)
+    /// Points the next errors at an operand
+    fn point_at(&mut self, operand: &Operand) {
+        self.span = Some(operand.span.clone());
     }
 
-    /// Processes jump instructions
-    fn parse_jump(&mut self, command: String) -> (u16, u16) {
-        regex!(
-            regex = r"(?P<type>jump(?:[a-zA-Z]{2})?) +(?:(?P<label>[A-z][A-Za-z0-9]*)|(?P<const>\$[A-Fa-f0-9]{4}))(?:\[R(?P<register>[0-9]|1[0-5])])?"
+    fn error(&mut self, code: ErrorCode, message: String, resolution: &str) {
+        self.errors.push(AssemblingError::new(
+            code,
+            self.line,
+            self.span.clone(),
+            message,
+            resolution,
+        ));
+        if let Some(expanded) = self.expansion.clone() {
+            self.add_note(
+                self.line,
+                None,
+                format!("In the expansion of {}: {}", expanded.name, expanded.text),
+            );
+        }
+    }
+
+    /// Adds a related location to the last error
+    fn add_note(&mut self, line: usize, span: Option<Range<usize>>, message: String) {
+        if let Some(error) = self.errors.last_mut() {
+            error.notes.push(Note {
+                line,
+                span,
+                message,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::code::Code;
+    use crate::assembler::error::Severity;
+    use crate::interpreter::state::{RunningState, State};
+
+    fn assemble(code: &str) -> Assembler {
+        let mut assembler = Assembler::new(code.to_string());
+        assembler.assemble();
+        assembler
+    }
+
+    #[test]
+    fn labels_resolve_forwards_and_backwards() {
+        let assembler = assemble(
+            "loop lea R1,1[R0]\n     jumplt done[R0]\n     jump loop\ndone trap R0,R0,R0\n",
+        );
+        assert!(assembler.errors.is_empty());
+        assert_eq!(
+            assembler.assembled,
+            vec![0xf100, 0x0001, 0xf405, 0x0006, 0xf003, 0x0000, 0xc000]
         );
+        assert_eq!(assembler.symbol_table["done"].value, 6);
+        assert_eq!(assembler.mem_to_code[&6], 4);
+    }
 
-        let extracted_command = match regex.captures(&command) {
-            None => {
-                self.errors.push(AssemblingError {
-                    message: "Jump instruction error".to_string(),
-                    line: self.line,
-                    resolution: "Unknown error".to_string(),
-                });
-                return (0, 0);
+    #[test]
+    fn encodes_every_format() {
+        let assembler = assemble(
+            "     add R1,R2,R3\n     cmp R4,R5\n     store R6,$00ff[R7]\n     jumpc1 3,x[R0]\n     push R1,R2,R3\n     trap R0,R0,R0\nx    data 65535\n",
+        );
+        assert!(assembler.errors.is_empty());
+        assert_eq!(
+            assembler.assembled,
+            vec![0x0123, 0x4045, 0xf672, 0x00ff, 0xf305, 0x0009, 0xe103, 0x2300, 0xc000, 0xffff]
+        );
+        assert_eq!(assembler.registers_used, vec![0, 1, 2, 3, 4, 5, 6, 7, 15]);
+    }
+
+    #[test]
+    fn reports_errors_on_their_lines() {
+        let assembler = assemble(
+            "     lee R1,0[R0]\n     add R1,R2\n     lea R1,x\n     load R16,0[R0]\n     jump nowhere\nx    data 70000\n     trap R0,R0,R0\n",
+        );
+        let mut lines: Vec<usize> = assembler.errors.iter().map(|e| e.line).collect();
+        lines.dedup();
+        assert_eq!(lines, vec![1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn duplicate_labels() {
+        let assembler = assemble(
+            "x    data 1\nx    data 2\n     load R1,x[R0]\n     trap R0,R0,R0\nx    data 3\n",
+        );
+        // Both sites of the first duplicate, then each later one
+        let lines: Vec<usize> = assembler.errors.iter().map(|e| e.line).collect();
+        assert_eq!(lines, vec![1, 2, 5]);
+        assert!(assembler
+            .errors
+            .iter()
+            .all(|e| e.code == ErrorCode::DuplicateLabel && e.is_error()));
+        assert_eq!(assembler.errors[0].notes[0].line, 2);
+        assert_eq!(assembler.errors[1].notes[0].line, 1);
+        assert_eq!(assembler.symbol_table["x"].value, 0);
+    }
+
+    #[test]
+    fn undefined_labels_at_every_use() {
+        let assembler = assemble(
+            "     load R1,y[R0]\n     jump y\n     data y+1,y\n     trap R0,R0,R0\n     export y\n",
+        );
+        let lines: Vec<usize> = assembler.errors.iter().map(|e| e.line).collect();
+        assert_eq!(lines, vec![1, 2, 3, 3, 5]);
+        assert!(assembler
+            .errors
+            .iter()
+            .all(|e| e.code == ErrorCode::UndefinedLabel));
+    }
+
+    #[test]
+    fn unused_labels_are_warnings() {
+        let code = Code::new("spare add R1,R2,R3\n      trap R0,R0,R0\n".to_string());
+        assert_eq!(code.errors.len(), 1);
+        assert_eq!(code.errors[0].code, ErrorCode::UnusedLabel);
+        assert_eq!(code.errors[0].severity, Severity::Warning);
+        assert_eq!(State::new(&code).state, RunningState::Step);
+
+        // Constants and imports do not have to be used, labels are underlined by their own span
+        let assembler =
+            assemble("n    equ 3\ny    import Lib,z\nswap mov R1,R2\n     trap R0,R0,R0\n");
+        let unused: Vec<(usize, Option<Range<usize>>)> = assembler
+            .errors
+            .iter()
+            .map(|e| (e.line, e.span.clone()))
+            .collect();
+        assert_eq!(unused, vec![(3, Some(0..4))]);
+
+        let code = Code::new("     add R1,R2,R3\n".to_string());
+        assert_eq!(State::new(&code).state, RunningState::Error);
+    }
+
+    #[test]
+    fn org_equ_and_block() {
+        let assembler = assemble(
+            "n    equ 3\n     load R1,x[R0]\n     shiftl R1,R1,n\n     trap R0,R0,R0\n     org $0010\nx    data n\nbuf  block n\ny    data x\n     export buf,y\n",
+        );
+        assert!(assembler.errors.is_empty());
+        assert_eq!(assembler.assembled.len(), 0x15);
+        assert_eq!(&assembler.assembled[..2], &[0xf101, 0x0010]);
+        assert_eq!(&assembler.assembled[0x10..], &[3, 0, 0, 0, 0x0010]);
+        assert_eq!(assembler.symbol_table["n"].kind, SymbolKind::Constant);
+        assert_eq!(assembler.symbol_table["buf"].value, 0x11);
+        // Gaps left by org are not part of the program
+        assert!(!assembler.mem_to_code.contains_key(&5));
+        assert_eq!(assembler.mem_to_code[&0x12], 7);
+        // The load displacement and the data holding x are addresses
+        assert_eq!(assembler.relocations, vec![1, 0x14]);
+    }
+
+    #[test]
+    fn modules_import_and_export() {
+        let assembler = assemble(
+            "Main module\nsum  import Lib,total\n     export start\nstart load R1,sum[R0]\n     trap R0,R0,R0\n",
+        );
+        assert!(assembler.errors.is_empty());
+        assert_eq!(assembler.module.as_deref(), Some("Main"));
+        assert_eq!(assembler.exports, vec!["start".to_string()]);
+        assert_eq!(assembler.imports, vec![(1, "sum".to_string())]);
+        assert_eq!(
+            assembler.symbol_table["sum"].kind,
+            SymbolKind::Imported {
+                module: "Lib".to_string(),
+                name: "total".to_string()
             }
-            Some(captures) => captures,
-        };
-        let mut instruction = match Some(extracted_command.name("type").unwrap().as_str()) {
-            Some("jump") => 0xf003_u16,
-            Some("jumplt") => 0xf405_u16,
-            Some("jumple") => 0xf004_u16,
-            Some("jumpeq") => 0xf205_u16,
-            Some("jumpne") => 0xf204_u16,
-            Some("jumpge") => 0xf404_u16,
-            Some("jumpgt") => 0xf005_u16,
-            _ => 0_u16,
-        };
+        );
+    }
+
+    #[test]
+    fn directive_errors() {
+        let assembler = assemble(
+            "     equ 3\nx    equ y\ny    data 1\n     org 5\nz    data 2\n     org 5\nw    data 3\n     export nothing\n     trap R0,R0,R0\n",
+        );
+        let mut lines: Vec<usize> = assembler.errors.iter().map(|e| e.line).collect();
+        lines.dedup();
+        // equ without a label, equ of a later label, overlapping org, unknown export
+        assert_eq!(lines, vec![1, 2, 3, 5, 7, 8]);
+    }
+
+    #[test]
+    fn expressions() {
+        let assembler = assemble(
+            "n    equ -2\n     load R1,x+1[R0]\n     lea R2,-(n+1)[R2]\n     trap R0,R0,R0\nx    data $ff\n     data 0x7fff+0b1\n     data n\n     data -32768\n     data y-x\ny    data x-(1-2)\n",
+        );
+        assert!(assembler.errors.is_empty());
+        assert_eq!(
+            assembler.assembled,
+            vec![
+                0xf101, 0x0006, 0xf220, 0x0001, 0xc000, 0x00ff, 0x8000, 0xfffe, 0x8000, 0x0005,
+                0x0006
+            ]
+        );
+        // x+1 and x-(1-2) are still addresses, y-x is a constant
+        assert_eq!(assembler.relocations, vec![1, 10]);
+    }
+
+    #[test]
+    fn expression_errors() {
+        let assembler = assemble(
+            "x    data 65536\n     data -32769\n     data x+x\n     data -x\n     data (1\n     data 1+\n     data 0b102\n     data $\n     trap R0,R0,R0\n",
+        );
+        let mut lines: Vec<usize> = assembler.errors.iter().map(|e| e.line).collect();
+        lines.dedup();
+        assert_eq!(lines, vec![1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn diagnostics_point_at_the_text() {
+        let code = "x    data 1\n     add R1,R2,x\n\tlee R1,0[R0]\nx    load R1,y[R0]\n     data 1,70000\n     trap R0,R0,R0\n";
+        let assembler = assemble(code);
+        let errors: Vec<(usize, ErrorCode, Option<Range<usize>>)> = assembler
+            .errors
+            .iter()
+            .map(|e| (e.line, e.code, e.span.clone()))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (1, ErrorCode::DuplicateLabel, Some(0..1)),
+                (1, ErrorCode::UnusedLabel, Some(0..1)),
+                (2, ErrorCode::ExpectedRegister, Some(15..16)),
+                (3, ErrorCode::UnknownInstruction, Some(1..4)),
+                (4, ErrorCode::DuplicateLabel, Some(0..1)),
+                (4, ErrorCode::UndefinedLabel, Some(13..18)),
+                (5, ErrorCode::OutOfRange, Some(12..17)),
+            ]
+        );
+        let duplicate = &assembler.errors[4];
+        assert_eq!(duplicate.notes[0].line, 1);
+        assert_eq!(duplicate.notes[0].span, Some(0..1));
+        assert_eq!(assembler.errors[3].column(code), Some(2));
+        assert_eq!(
+            assembler.errors[3].underline(code).unwrap(),
+            " lee R1,0[R0]\n ^^^"
+        );
+        assert!(!assembler.errors[1].is_error());
+
+        let assembler = assemble("     add R1,R2,R3\n     lea R1,1 [R0] x\n");
+        assert_eq!(assembler.errors[0].code, ErrorCode::ExpectedComma);
+        assert_eq!(assembler.errors[0].span, Some(19..20));
+        assert_eq!(assembler.errors[1].code, ErrorCode::NoTrap);
+        assert_eq!(assembler.errors[1].line, 0);
+    }
 
-        // The register in jump arguments is optional, defaults to R0
-        if let Some(register) = extracted_command.name("register") {
-            instruction |= register.as_str().parse::<u16>().unwrap() << 4;
+    #[test]
+    fn flexible_layout() {
+        let expected = assemble(
+            "loop lea R1,1[R0]\n     add R1,R2,R3\n     getctl R1,status\n     jump loop[R0]\n     trap R0,R0,R0\n",
+        );
+        assert!(expected.errors.is_empty());
+        let samples = [
+            "loop\tLEA\tr1,1[r0]\r\n\tAdd R1, R2, R3\r\n  GetCtl R1 , Status\r\n\tjump loop [ R0 ]\r\ntrap r0,r0,r0\r\n",
+            "loop lea R1,1[R0] ; comment\nadd R1,R2,R3\ngetctl R1,status\njump loop[R0]\n\t trap R0,R0,R0",
+        ];
+        for sample in samples {
+            let assembler = assemble(sample);
+            assert!(assembler.errors.is_empty());
+            assert_eq!(assembler.assembled, expected.assembled);
         }
 
-        let address = if let Some(addr) = extracted_command.name("label") {
-            self.data_inserts
-                .entry(addr.as_str().parse().unwrap())
-                .or_default()
-                .push((self.cursor + 1) as u16);
-            0
-        } else if let Some(addr) = extracted_command.name("const") {
-            u16::from_str_radix(&addr.as_str()[1..], 16).unwrap()
-        } else {
-            0
-        };
+        // A mnemonic followed by another mnemonic is still a label
+        let assembler = assemble("add  add R1,R2,R3\n     jump add[R0]\n     trap R0,R0,R0\n");
+        assert!(assembler.errors.is_empty());
+        assert_eq!(assembler.symbol_table["add"].value, 0);
+
+        let assembler = assemble("     add R1,R2,R3 extra\n     add R1 R2\n     trap R0,R0,R0\n");
+        let lines: Vec<usize> = assembler.errors.iter().map(|e| e.line).collect();
+        assert_eq!(lines, vec![1, 2]);
+    }
+
+    #[test]
+    fn data_values_and_strings() {
+        let assembler = assemble(
+            "     trap R0,R0,R0\nx    data 1,-1,'a',x,s\ns    data \"a; b\\n\",'\\0'\n     data '\\'',\"\\\"\",' '\n",
+        );
+        assert!(assembler.errors.is_empty());
+        assert_eq!(
+            assembler.assembled,
+            vec![0xc000, 1, 0xffff, 97, 1, 6, 97, 59, 32, 98, 10, 0, 39, 34, 32]
+        );
+        assert_eq!(assembler.symbol_table["s"].value, 6);
+        assert_eq!(assembler.relocations, vec![4, 5]);
+        assert_eq!(assembler.mem_to_code[&5], 2);
+        assert_eq!(assembler.mem_to_code[&11], 3);
+        assert_eq!(assembler.mem_to_code[&14], 4);
+    }
+
+    #[test]
+    fn data_errors() {
+        let assembler = assemble(
+            "     data\n     data 'ab'\n     data \"\\q\"\n     data 1,\n     lea R1,\"x\"[R0]\n     data \"\u{1f600}\"\n     trap R0,R0,R0\n",
+        );
+        let mut lines: Vec<usize> = assembler.errors.iter().map(|e| e.line).collect();
+        lines.dedup();
+        assert_eq!(lines, vec![1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn expands_macros_and_pseudo_instructions() {
+        let assembler = assemble(
+            "     macro swap a,b ; exchange two registers\n     add R15,a,R0\n     add a,b,R0\n     add b,R15,R0\n     endm\nmov  mov R1,R2\nx    swap R3,R4\n     nop\n     inc R5\n     dec R5\n     jump x[R0]\n     trap R0,R0,R0\n",
+        );
+        // Only the label mov is reported, as it is not used
+        assert!(!assembler.errors.iter().any(|e| e.is_error()));
+        assert_eq!(
+            assembler.assembled,
+            vec![
+                0x0120, 0x0f30, 0x0340, 0x04f0, 0x0000, 0xf550, 0x0001, 0xf550, 0xffff, 0xf003,
+                0x0001, 0xc000
+            ]
+        );
+        // Expanded words belong to the line of the call
+        assert_eq!(assembler.mem_to_code[&0], 6);
+        assert_eq!(assembler.mem_to_code[&3], 7);
+        assert_eq!(assembler.symbol_table["x"].value, 1);
+        assert_eq!(assembler.symbol_table["mov"].value, 0);
+        let texts: Vec<(usize, &str, usize)> = assembler
+            .expansions
+            .iter()
+            .map(|e| (e.line, e.text.as_str(), e.address))
+            .collect();
+        assert_eq!(
+            texts[..4],
+            [
+                (6, "add R1,R2,R0", 0),
+                (7, "add R15,R3,R0", 1),
+                (7, "add R3,R4,R0", 2),
+                (7, "add R4,R15,R0", 3)
+            ]
+        );
+    }
 
-        (instruction, address)
+    #[test]
+    fn macro_calls_in_any_case_and_column() {
+        let assembler = assemble(
+            "     macro Swap a,b\n     add R15,a,R0\n     add a,b,R0\n     add b,R15,R0\n     endm\nSWAP R1,R2\nx    swap R3,R4\n     SwAp R5,R6\n     jump x[R0]\n     trap R0,R0,R0\n",
+        );
+        assert!(assembler.errors.is_empty());
+        assert!(!assembler.symbol_table.contains_key("SWAP"));
+        assert_eq!(assembler.mem_to_code[&0], 6);
+        assert_eq!(assembler.symbol_table["x"].value, 3);
+        assert_eq!(assembler.assembled.len(), 12);
+    }
+
+    #[test]
+    fn macro_errors() {
+        let assembler = assemble(
+            "     macro twice a\n     twice a\n     endm\n     macro add x\n     endm\n     macro bad\n     lea R1,q[R0]\n     endm\n     twice R1\n     bad\n     bad R1\n     endm\n     trap R0,R0,R0\n     macro open\n",
+        );
+        let errors: Vec<(usize, ErrorCode)> =
+            assembler.errors.iter().map(|e| (e.line, e.code)).collect();
+        assert_eq!(
+            errors,
+            vec![
+                (4, ErrorCode::InvalidMacro),
+                (9, ErrorCode::InvalidMacro),
+                (10, ErrorCode::UndefinedLabel),
+                (11, ErrorCode::OperandCount),
+                (12, ErrorCode::InvalidMacro),
+                (14, ErrorCode::InvalidMacro),
+            ]
+        );
+        // Errors in an expansion underline the call and show the expanded line
+        let undefined = &assembler.errors[2];
+        assert_eq!(undefined.span, Some(5..8));
+        assert_eq!(
+            undefined.notes[0].message,
+            "In the expansion of bad: lea R1,q[R0]"
+        );
+    }
+
+    #[test]
+    fn recursive_macros_stop_at_the_first_repeated_call() {
+        let assembler = assemble(
+            "     macro grow\n     grow\n     grow\n     endm\n     grow\n     trap R0,R0,R0\n",
+        );
+        let errors: Vec<(usize, ErrorCode)> =
+            assembler.errors.iter().map(|e| (e.line, e.code)).collect();
+        assert_eq!(errors, vec![(5, ErrorCode::InvalidMacro)]);
+
+        // Macros calling each other can still expand to too many lines
+        let mut code = "     macro m0\n     nop\n     nop\n     endm\n".to_string();
+        for i in 1..20 {
+            code += &format!(
+                "     macro m{i}\n     m{}\n     m{}\n     endm\n",
+                i - 1,
+                i - 1
+            );
+        }
+        code += "     m19\n     m19\n     trap R0,R0,R0\n";
+        let assembler = assemble(&code);
+        let errors: Vec<(usize, ErrorCode)> =
+            assembler.errors.iter().map(|e| (e.line, e.code)).collect();
+        assert_eq!(errors, vec![(81, ErrorCode::InvalidMacro)]);
+    }
+
+    #[test]
+    fn never_panics() {
+        let samples = [
+            "     lea R1,",
+            "     lea R1,[R0]",
+            "     lea R1,x[R0",
+            "     lea R1,x[]",
+            "     lea R1,x[R0]]",
+            "     add R1,,R2",
+            "     add ,",
+            "     data $",
+            "     data $fffff",
+            "     data 99999999999999999999999",
+            "     brc0 R1,99,x",
+            "     save R1,R2,R3",
+            "     getctl R1,R2",
+            "     data",
+            "     block",
+            "     data \"",
+            "     data 'ab'",
+            "     data '\\",
+            "     data \"\\q\"",
+            "     lea R1,\"x\"[R0]",
+            "x",
+            "1x  add R1,R2,R3",
+            "     add R1,R2,R3 extra",
+            "\u{e9}    \u{e9}",
+            ";",
+            "     ;",
+            "     macro",
+            "     macro m a,,",
+            "     endm",
+            "     macro m\n     m\n     endm\n     m",
+            "     inc",
+            "     inc R1,R2",
+            "",
+        ];
+        for sample in samples {
+            assemble(sample);
+        }
+
+        // Assemble every prefix and mutation of a program
+        let program = "loop lea R1,1[R0]\n     brc1 R1,2,loop\n     save R1,R2,4[R14]\n     jumpeq loop[R0]\n     trap R0,R0,R0\n";
+        for end in 0..program.len() {
+            assemble(&program[..end]);
+        }
+        let mut seed = 12345_u32;
+        for _ in 0..500 {
+            let mut bytes = program.as_bytes().to_vec();
+            for _ in 0..4 {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                let i = (seed >> 8) as usize % bytes.len();
+                bytes[i] = b" ,[]$;R0x9\n"[(seed >> 20) as usize % 11];
+            }
+            assemble(&String::from_utf8_lossy(&bytes));
+        }
     }
 }
@@ -0,0 +1,131 @@
+use std::fmt;
+use std::ops::Range;
+
+/// One line of Sigma16 source code
+/// Every field is optional, a blank line has none of them
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Line {
+    /// Line number in the source, starting at 1
+    pub number: usize,
+    pub label: Option<Label>,
+    pub statement: Option<Statement>,
+    pub comment: Option<String>,
+}
+
+impl Line {
+    /// Points every part of the line at other text, used for lines of a macro expansion
+    pub fn point_at(&mut self, span: &Range<usize>) {
+        if let Some(label) = &mut self.label {
+            label.span = span.clone();
+        }
+        if let Some(statement) = &mut self.statement {
+            statement.span = span.clone();
+            for operand in &mut statement.operands {
+                operand.span = span.clone();
+            }
+        }
+    }
+}
+
+/// A label in the first column of a line
+#[derive(Debug, Clone, PartialEq)]
+pub struct Label {
+    pub name: String,
+    pub span: Range<usize>,
+}
+
+/// An instruction or directive with its operands
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statement {
+    pub mnemonic: String,
+    pub span: Range<usize>,
+    pub operands: Vec<Operand>,
+    /// Set for statements from a macro expansion, their spans point at the macro call
+    pub expansion: Option<Expanded>,
+}
+
+/// The macro a statement was expanded from and the line it was expanded to
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expanded {
+    pub name: String,
+    pub text: String,
+}
+
+impl Statement {
+    /// From the mnemonic to the end of the last operand
+    pub fn full_span(&self) -> Range<usize> {
+        match self.operands.last() {
+            Some(operand) => self.span.start..operand.span.end,
+            None => self.span.clone(),
+        }
+    }
+}
+
+/// A single comma separated operand
+/// Spans are byte offsets into the line
+#[derive(Debug, Clone, PartialEq)]
+pub struct Operand {
+    pub kind: OperandKind,
+    pub span: Range<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OperandKind {
+    /// R0 to R15
+    Register(u8),
+    /// A constant or a symbol
+    Expr(Expr),
+    /// disp[Ra]
+    Indexed(Expr, u8),
+    /// "text" with the escapes already replaced
+    String(String),
+}
+
+/// A value that is known after the first pass
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(i64),
+    Symbol(String),
+    Neg(Box<Expr>),
+    Binary(Box<Expr>, BinaryOp, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Number(value) => write!(f, "{value}"),
+            Expr::Symbol(name) => write!(f, "{name}"),
+            Expr::Neg(expr) => match **expr {
+                Expr::Binary(..) => write!(f, "-({expr})"),
+                _ => write!(f, "-{expr}"),
+            },
+            Expr::Binary(left, op, right) => {
+                let op = match op {
+                    BinaryOp::Add => '+',
+                    BinaryOp::Sub => '-',
+                };
+                match **right {
+                    Expr::Binary(..) => write!(f, "{left}{op}({right})"),
+                    _ => write!(f, "{left}{op}{right}"),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            OperandKind::Register(reg) => write!(f, "R{reg}"),
+            OperandKind::Expr(expr) => write!(f, "{expr}"),
+            OperandKind::Indexed(expr, reg) => write!(f, "{expr}[R{reg}]"),
+            OperandKind::String(text) => write!(f, "{text:?}"),
+        }
+    }
+}
@@ -3,17 +3,40 @@ use log::{log, Level};
 use std::collections::HashMap;
 
 use super::error::AssemblingError;
+use super::linker::LinkedModule;
+use super::macros::Expansion;
+use super::symbol::Symbol;
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
 /// A wrapper for the code so the assembler does not need to be serializable
 pub(crate) struct Code {
     pub memory: Vec<u16>,
     pub code: String,
     pub memory_to_code: HashMap<usize, usize>,
-    pub symbol_table: HashMap<String, usize>,
+    pub symbol_table: HashMap<String, Symbol>,
     pub errors: Vec<AssemblingError>,
     pub used_registers: Vec<usize>,
     pub trap_index: Option<usize>,
+    #[serde(default)]
+    pub module: Option<String>,
+    #[serde(default)]
+    pub exports: Vec<String>,
+    #[serde(default)]
+    pub relocations: Vec<usize>,
+    #[serde(default)]
+    pub imports: Vec<(usize, String)>,
+    /// Lines each symbol is used on
+    #[serde(default)]
+    pub references: HashMap<String, Vec<usize>>,
+    /// Loaded from object code, so code holds the object records rather than source
+    #[serde(default)]
+    pub object: bool,
+    /// Modules linked into this program, in the order they are placed in memory
+    #[serde(default)]
+    pub modules: Vec<LinkedModule>,
+    /// What each macro call expanded to
+    #[serde(default)]
+    pub expansions: Vec<Expansion>,
 }
 
 impl Code {
@@ -26,7 +49,9 @@ impl Code {
             for error in assembler.errors.clone() {
                 log!(
                     Level::Error,
-                    "message: {}\nline: {}\n resolution {}",
+                    "{} {}: {}\nline: {}\n resolution {}",
+                    error.severity,
+                    error.code,
                     error.message,
                     error.line,
                     error.resolution
@@ -42,9 +67,36 @@ impl Code {
             errors: assembler.errors,
             trap_index: assembler.trap_index,
             used_registers: assembler.registers_used,
+            module: assembler.module,
+            exports: assembler.exports,
+            relocations: assembler.relocations,
+            imports: assembler.imports,
+            references: assembler.references,
+            object: false,
+            modules: Vec::new(),
+            expansions: assembler.expansions,
         }
     }
 
+    /// Addresses of the labels, leaving out constants and imports
+    pub fn addresses(&self) -> HashMap<String, usize> {
+        self.symbol_table
+            .iter()
+            .filter(|(_, symbol)| symbol.is_address())
+            .map(|(name, symbol)| (name.clone(), symbol.value as usize))
+            .collect()
+    }
+
+    /// Editor and line a line of linked code came from, line 0 is the comment naming the module
+    pub fn source(&self, line: usize) -> Option<(&str, usize)> {
+        let module = self
+            .modules
+            .iter()
+            .rev()
+            .find(|module| module.first_line <= line)?;
+        Some((&module.editor, line - module.first_line))
+    }
+
     pub fn code_line_from_mem_loc(&self, mem_loc: usize) -> (String, usize) {
         let lines = self.code.lines().collect::<Vec<&str>>();
         let line = self.memory_to_code[&mem_loc] - 1;
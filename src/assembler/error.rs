@@ -1,7 +1,174 @@
-#[derive(Clone, serde::Serialize, serde::Deserialize)]
+use std::fmt;
+use std::ops::Range;
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 /// Simple struct to hold information of errors during assembly
 pub struct AssemblingError {
     pub message: String,
     pub line: usize,
     pub resolution: String,
+    #[serde(default)]
+    pub severity: Severity,
+    /// Byte offsets of the offending text within the line
+    #[serde(default)]
+    pub span: Option<Range<usize>>,
+    #[serde(default)]
+    pub code: ErrorCode,
+    /// Other lines that help explain the error
+    #[serde(default)]
+    pub notes: Vec<Note>,
+}
+
+/// Only errors stop a program from running
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Severity {
+    #[default]
+    Error,
+    Warning,
+    Note,
+}
+
+/// A related location, for example where a label was first defined
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Note {
+    pub line: usize,
+    pub span: Option<Range<usize>>,
+    pub message: String,
+}
+
+/// What went wrong, each kind of error keeps its code so it can be looked up in the manual
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum ErrorCode {
+    /// Errors saved before codes were added
+    #[default]
+    Unknown,
+    InvalidLabel,
+    UnexpectedCharacter,
+    ExpectedComma,
+    MissingOperand,
+    InvalidOperand,
+    InvalidNumber,
+    InvalidEscape,
+    UnknownInstruction,
+    OperandCount,
+    ExpectedRegister,
+    ExpectedIndexed,
+    ExpectedConstant,
+    OutOfRange,
+    UndefinedLabel,
+    DuplicateLabel,
+    NotRelocatable,
+    BranchTooFar,
+    UnknownControlRegister,
+    InvalidDirective,
+    InvalidMacro,
+    ProgramTooLarge,
+    AddressOverlap,
+    UnusedLabel,
+    NoTrap,
+    InvalidObject,
+    UnresolvedImport,
+    DuplicateModule,
+}
+
+impl ErrorCode {
+    /// The stable code shown next to the message
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorCode::Unknown => "A000",
+            ErrorCode::InvalidLabel => "A001",
+            ErrorCode::UnexpectedCharacter => "A002",
+            ErrorCode::ExpectedComma => "A003",
+            ErrorCode::MissingOperand => "A004",
+            ErrorCode::InvalidOperand => "A005",
+            ErrorCode::InvalidNumber => "A006",
+            ErrorCode::InvalidEscape => "A007",
+            ErrorCode::UnknownInstruction => "A101",
+            ErrorCode::OperandCount => "A102",
+            ErrorCode::ExpectedRegister => "A103",
+            ErrorCode::ExpectedIndexed => "A104",
+            ErrorCode::ExpectedConstant => "A105",
+            ErrorCode::OutOfRange => "A106",
+            ErrorCode::UnknownControlRegister => "A107",
+            ErrorCode::InvalidDirective => "A108",
+            ErrorCode::InvalidMacro => "A109",
+            ErrorCode::UndefinedLabel => "A201",
+            ErrorCode::DuplicateLabel => "A202",
+            ErrorCode::NotRelocatable => "A203",
+            ErrorCode::BranchTooFar => "A204",
+            ErrorCode::ProgramTooLarge => "A301",
+            ErrorCode::AddressOverlap => "A302",
+            ErrorCode::UnusedLabel => "A303",
+            ErrorCode::NoTrap => "A304",
+            ErrorCode::InvalidObject => "A401",
+            ErrorCode::UnresolvedImport => "A501",
+            ErrorCode::DuplicateModule => "A502",
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.code())
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "Error"),
+            Severity::Warning => write!(f, "Warning"),
+            Severity::Note => write!(f, "Note"),
+        }
+    }
+}
+
+impl AssemblingError {
+    pub fn new(
+        code: ErrorCode,
+        line: usize,
+        span: Option<Range<usize>>,
+        message: String,
+        resolution: &str,
+    ) -> Self {
+        AssemblingError {
+            message,
+            line,
+            resolution: resolution.to_string(),
+            severity: Severity::Error,
+            span,
+            code,
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn warning(mut self) -> Self {
+        self.severity = Severity::Warning;
+        self
+    }
+
+    /// Warnings and notes do not stop the program from running
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    /// Column of the start of the span, counting from 1
+    pub fn column(&self, source: &str) -> Option<usize> {
+        let text = source.lines().nth(self.line.checked_sub(1)?)?;
+        Some(text.get(..self.span.as_ref()?.start)?.chars().count() + 1)
+    }
+
+    /// The line with the offending text underlined, tabs are shown as spaces so the marks line up
+    pub fn underline(&self, source: &str) -> Option<String> {
+        let text = source.lines().nth(self.line.checked_sub(1)?)?;
+        let span = self.span.as_ref()?;
+        let before = text.get(..span.start)?.chars().count();
+        let length = text.get(span.clone())?.chars().count().max(1);
+        Some(format!(
+            "{}\n{}{}",
+            text.replace('\t', " "),
+            " ".repeat(before),
+            "^".repeat(length)
+        ))
+    }
 }
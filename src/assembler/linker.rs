@@ -0,0 +1,243 @@
+use crate::assembler::code::Code;
+use crate::assembler::error::{AssemblingError, ErrorCode, Note};
+use crate::assembler::macros::Expansion;
+use crate::assembler::symbol::{Symbol, SymbolKind};
+use crate::interpreter::memory::MEMORY_SIZE;
+
+/// Where a module was placed in a linked program
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct LinkedModule {
+    /// Name of the editor the module came from
+    pub editor: String,
+    pub module: String,
+    /// Address of the module's first word
+    pub base: usize,
+    /// Line of the linked code that comes before the module's first line
+    pub first_line: usize,
+}
+
+/// Links assembled modules into one program, placing them one after the other in memory
+/// The first module starts at address 0, so execution starts there
+/// Addresses are relocated and imports are filled in from the other modules' exports
+/// The linked code holds every module's source, each under a comment naming it,
+/// so lines of the linked program can be traced back to their editor
+pub fn link(modules: &[(&str, &Code)]) -> Code {
+    let mut linked = Code::default();
+
+    // Place each module after the one before it
+    for (editor, code) in modules {
+        let module = code.module.clone().unwrap_or(editor.to_string());
+        let base = linked.memory.len();
+        let first_line = linked.code.lines().count() + 1;
+        linked
+            .code
+            .push_str(&format!("; module {module} from {editor}\n"));
+        linked.code.push_str(&code.code);
+        if !linked.code.ends_with('\n') {
+            linked.code.push('\n');
+        }
+
+        if let Some(other) = linked.modules.iter().find(|other| other.module == module) {
+            let mut error = AssemblingError::new(
+                ErrorCode::DuplicateModule,
+                first_line,
+                None,
+                format!("Module {module} is linked more than once."),
+                "Each module can only be linked once, rename one of them with the module directive.",
+            );
+            error.notes.push(Note {
+                line: other.first_line,
+                span: None,
+                message: format!("{module} is first linked from {}.", other.editor),
+            });
+            linked.errors.push(error);
+        }
+        if base + code.memory.len() > MEMORY_SIZE {
+            linked.errors.push(AssemblingError::new(
+                ErrorCode::ProgramTooLarge,
+                first_line,
+                None,
+                "The linked program does not fit in memory.".to_string(),
+                "Programs can be at most 65536 words long, link fewer modules.",
+            ));
+            break;
+        }
+
+        // Errors from assembling the module, each module is only checked for a trap once linked
+        for error in code.errors.iter().filter(|e| e.code != ErrorCode::NoTrap) {
+            let mut error = error.clone();
+            error.line += first_line;
+            for note in &mut error.notes {
+                note.line += first_line;
+            }
+            linked.errors.push(error);
+        }
+
+        linked.memory.extend_from_slice(&code.memory);
+        for (address, line) in &code.memory_to_code {
+            linked
+                .memory_to_code
+                .insert(base + address, first_line + line);
+        }
+        for address in &code.relocations {
+            let word = &mut linked.memory[base + address];
+            *word = word.wrapping_add(base as u16);
+            linked.relocations.push(base + address);
+        }
+        for (name, symbol) in &code.symbol_table {
+            let value = match symbol.kind {
+                SymbolKind::Imported { .. } => continue,
+                SymbolKind::Relocatable => symbol.value.wrapping_add(base as u16),
+                SymbolKind::Constant => symbol.value,
+            };
+            linked.symbol_table.insert(
+                format!("{module}.{name}"),
+                Symbol {
+                    value,
+                    kind: symbol.kind.clone(),
+                    line: first_line + symbol.line,
+                },
+            );
+        }
+        for expansion in &code.expansions {
+            linked.expansions.push(Expansion {
+                line: first_line + expansion.line,
+                address: base + expansion.address,
+                ..expansion.clone()
+            });
+        }
+        for reg in &code.used_registers {
+            if !linked.used_registers.contains(reg) {
+                linked.used_registers.push(*reg);
+            }
+        }
+        if linked.trap_index.is_none() {
+            linked.trap_index = code.trap_index.map(|index| base + index);
+        }
+        linked.modules.push(LinkedModule {
+            editor: editor.to_string(),
+            module,
+            base,
+            first_line,
+        });
+    }
+
+    // Fill in each import now that every module has an address
+    for ((_, code), placed) in modules.iter().zip(linked.modules.clone()) {
+        for (address, local) in &code.imports {
+            let Some(SymbolKind::Imported { module, name }) =
+                code.symbol_table.get(local).map(|symbol| &symbol.kind)
+            else {
+                continue;
+            };
+            let qualified = format!("{module}.{name}");
+            let exported = linked
+                .modules
+                .iter()
+                .zip(modules)
+                .find(|(other, _)| other.module == *module)
+                .filter(|(_, (_, other))| other.exports.contains(name))
+                .and_then(|_| linked.symbol_table.get(&qualified));
+            match exported {
+                Some(symbol) => {
+                    let value = symbol.value;
+                    let relocatable = symbol.is_address();
+                    let word = &mut linked.memory[placed.base + address];
+                    *word = word.wrapping_add(value);
+                    if relocatable {
+                        linked.relocations.push(placed.base + address);
+                    }
+                }
+                None => {
+                    let line = code.memory_to_code.get(address).copied().unwrap_or(0);
+                    linked.errors.push(AssemblingError::new(
+                        ErrorCode::UnresolvedImport,
+                        placed.first_line + line,
+                        None,
+                        format!("{qualified} is not exported by any linked module."),
+                        "Link the module that exports it, or check it is in that module's export directive.",
+                    ));
+                }
+            }
+        }
+    }
+
+    linked.errors.sort_by_key(|error| error.line);
+    if linked.trap_index.is_none() {
+        linked.errors.push(AssemblingError::new(
+            ErrorCode::NoTrap,
+            0,
+            None,
+            "No trap instruction, program will never terminate when run.".to_string(),
+            "One of the linked modules needs a \"trap R0,R0,R0\".",
+        ));
+    }
+    linked.used_registers.sort_unstable();
+    linked.relocations.sort_unstable();
+    linked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn links_modules_and_resolves_imports() {
+        let main = Code::new(
+            "Main module\ny    import Lib,y\nf    import Lib,f\n     load R1,y[R0]\n     jal R13,f[R0]\n     load R2,x[R0]\n     trap R0,R0,R0\nx    data 7\n"
+                .to_string(),
+        );
+        let lib = Code::new(
+            "Lib  module\nf    add R1,R1,R1\n     jump 0[R13]\ny    data 5\nn    equ 3\n     export f,y,n\n"
+                .to_string(),
+        );
+        assert!(main.errors.is_empty());
+        assert!(lib.errors.iter().all(|e| e.code == ErrorCode::NoTrap));
+
+        let linked = link(&[("main", &main), ("lib", &lib)]);
+        assert!(linked.errors.is_empty());
+        // Main is 8 words, so Lib starts at 8 and y is at 8 + 3
+        assert_eq!(
+            linked.memory,
+            vec![
+                0xf101, 0x000b, 0xfd06, 0x0008, 0xf201, 0x0007, 0xc000, 0x0007, 0x0111, 0xf0d3,
+                0x0000, 0x0005
+            ]
+        );
+        assert_eq!(linked.symbol_table["Lib.y"].value, 11);
+        assert_eq!(linked.symbol_table["Lib.n"].value, 3);
+        assert_eq!(linked.symbol_table["Main.x"].value, 7);
+        assert_eq!(linked.relocations, vec![1, 3, 5]);
+        assert_eq!(linked.trap_index, Some(6));
+
+        // The jump in Lib is on line 3 of the lib editor
+        let line = linked.memory_to_code[&9];
+        assert_eq!(linked.source(line), Some(("lib", 3)));
+        assert_eq!(linked.code.lines().nth(line - 1), Some("     jump 0[R13]"));
+    }
+
+    #[test]
+    fn reports_unresolved_and_duplicate_modules() {
+        let main = Code::new(
+            "Main module\ny    import Lib,y\nz    import Other,z\n     load R1,y[R0]\n     load R1,z[R0]\n     trap R0,R0,R0\n"
+                .to_string(),
+        );
+        let lib = Code::new("Lib  module\ny    data 1\n".to_string());
+        let linked = link(&[("main", &main), ("lib", &lib), ("again", &lib)]);
+        let errors: Vec<(ErrorCode, Option<(&str, usize)>)> = linked
+            .errors
+            .iter()
+            .filter(|e| e.is_error())
+            .map(|e| (e.code, linked.source(e.line)))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (ErrorCode::UnresolvedImport, Some(("main", 4))),
+                (ErrorCode::UnresolvedImport, Some(("main", 5))),
+                (ErrorCode::DuplicateModule, Some(("again", 0))),
+            ]
+        );
+        assert!(!link(&[("lib", &lib)]).errors.is_empty());
+    }
+}
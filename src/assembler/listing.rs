@@ -0,0 +1,182 @@
+use crate::assembler::code::Code;
+use crate::assembler::symbol::SymbolKind;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// Words shown on each row of the listing, enough for any instruction
+const WORDS_PER_ROW: usize = 2;
+/// Long data and blocks are cut short after this many rows
+const MAX_ROWS: usize = 4;
+
+/// Listing of an assembled program in the style of the Sigma16 .lst files
+/// Each source line is shown with its address and the words assembled from it,
+/// followed by the symbol table with where each symbol is defined and used
+pub fn listing(code: &Code) -> String {
+    let mut addresses: HashMap<usize, Vec<usize>> = HashMap::new();
+    for (&address, &line) in &code.memory_to_code {
+        addresses.entry(line).or_default().push(address);
+    }
+
+    let mut out = String::new();
+    if let Some(module) = &code.module {
+        let _ = writeln!(out, "Module {module}\n");
+    }
+    let _ = writeln!(out, "Line  Addr  Code       Source");
+    for (i, text) in code.code.lines().enumerate() {
+        let line = i + 1;
+        let mut lines_addresses = addresses.remove(&line).unwrap_or_default();
+        lines_addresses.sort_unstable();
+        let expansions: Vec<_> = code.expansions.iter().filter(|e| e.line == line).collect();
+        if expansions.is_empty() {
+            rows(
+                &mut out,
+                code,
+                &format!("{line:>4}"),
+                &lines_addresses,
+                text,
+            );
+            continue;
+        }
+        // A macro call is followed by the lines it expanded to, each with its own words
+        rows(&mut out, code, &format!("{line:>4}"), &[], text);
+        for expansion in expansions {
+            let expanded: Vec<usize> = (expansion.address..expansion.address + expansion.size)
+                .filter(|address| lines_addresses.contains(address))
+                .collect();
+            rows(
+                &mut out,
+                code,
+                "",
+                &expanded,
+                &format!("+ {}", expansion.text),
+            );
+        }
+    }
+
+    let mut names: Vec<&String> = code.symbol_table.keys().collect();
+    if names.is_empty() {
+        return out;
+    }
+    names.sort();
+    let width = names
+        .iter()
+        .map(|name| name.len())
+        .max()
+        .unwrap_or(0)
+        .max(6);
+    let _ = writeln!(out, "\nSymbol table");
+    let _ = writeln!(
+        out,
+        "{:width$}  Value  {:<20}  Defined  Used",
+        "Symbol", "Kind"
+    );
+    for name in names {
+        let symbol = &code.symbol_table[name];
+        let value = match symbol.kind {
+            SymbolKind::Imported { .. } => "----".to_string(),
+            _ => format!("{:04x}", symbol.value),
+        };
+        let mut kind = match &symbol.kind {
+            SymbolKind::Constant => "constant".to_string(),
+            SymbolKind::Relocatable => "address".to_string(),
+            SymbolKind::Imported { module, name } => format!("import {module}.{name}"),
+        };
+        if code.exports.contains(name) {
+            kind.push_str(", exported");
+        }
+        let used = code
+            .references
+            .get(name)
+            .map(|lines| {
+                lines
+                    .iter()
+                    .map(|line| line.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            })
+            .unwrap_or_default();
+        let _ = writeln!(
+            out,
+            "{name:width$}  {value}   {kind:<20}  {:>7}  {used}",
+            symbol.line
+        );
+    }
+    out
+}
+
+/// The words at the addresses two to a row, the first row has the line number and text
+fn rows(out: &mut String, code: &Code, line: &str, addresses: &[usize], text: &str) {
+    let mut rows = addresses.chunks(WORDS_PER_ROW);
+    match rows.next() {
+        Some(row) => {
+            let _ = writeln!(
+                out,
+                "{line:>4}  {:04x}  {:<9}  {text}",
+                row[0],
+                words(code, row)
+            );
+        }
+        None => {
+            let _ = writeln!(out, "{line:>4}  {:4}  {:9}  {text}", "", "");
+        }
+    }
+    for (n, row) in rows.enumerate() {
+        if n + 1 == MAX_ROWS {
+            let _ = writeln!(out, "      ....  ({} words)", addresses.len());
+            break;
+        }
+        let _ = writeln!(out, "      {:04x}  {}", row[0], words(code, row));
+    }
+}
+
+fn words(code: &Code, addresses: &[usize]) -> String {
+    addresses
+        .iter()
+        .map(|address| format!("{:04x}", code.memory[*address]))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_lines_words_and_symbols() {
+        let code = Code::new(
+            "; count down\nloop lea R1,1[R0]\n     jump loop\n     trap R0,R0,R0\nn    equ 2\ns    data \"abcde\",n\nbuf  block 20\n     export s,buf\n"
+                .to_string(),
+        );
+        let listing = listing(&code);
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines[0], "Line  Addr  Code       Source");
+        assert_eq!(lines[1], "   1                   ; count down");
+        assert_eq!(lines[2], "   2  0000  f100 0001  loop lea R1,1[R0]");
+        assert_eq!(lines[4], "   4  0004  c000            trap R0,R0,R0");
+        assert_eq!(lines[6], "   6  0005  0061 0062  s    data \"abcde\",n");
+        assert_eq!(lines[7], "      0007  0063 0064");
+        assert_eq!(lines[8], "      0009  0065 0002");
+        assert_eq!(lines[9], "   7  000b  0000 0000  buf  block 20");
+        assert_eq!(lines[12], "      0011  0000 0000");
+        assert_eq!(lines[13], "      ....  (20 words)");
+        assert!(listing.contains("loop    0000   address                     2  3\n"));
+        assert!(listing.contains("n       0002   constant                    5  6\n"));
+        assert!(listing.contains("s       0005   address, exported           6  8\n"));
+    }
+
+    #[test]
+    fn shows_macro_expansions() {
+        let code = Code::new(
+            "     macro twice r\n     add r,r,r\n     add r,r,r\n     endm\n     twice R1\n     nop\n     trap R0,R0,R0\n"
+                .to_string(),
+        );
+        let listing = listing(&code);
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines[5], "   5                        twice R1");
+        assert_eq!(lines[6], "      0000  0111       + add R1,R1,R1");
+        assert_eq!(lines[7], "      0001  0111       + add R1,R1,R1");
+        assert_eq!(lines[8], "   6                        nop");
+        assert_eq!(lines[9], "      0002  0000       + add R0,R0,R0");
+        assert_eq!(lines[10], "   7  0003  c000            trap R0,R0,R0");
+    }
+}
@@ -0,0 +1,118 @@
+/// A macro defined with macro ... endm, or one of the built in pseudo instructions
+#[derive(Debug, Clone, PartialEq)]
+pub struct Macro {
+    pub params: Vec<String>,
+    /// Lines between macro and endm, without their comments
+    pub body: Vec<String>,
+    /// Line of the macro directive, 0 for pseudo instructions
+    pub line: usize,
+}
+
+/// A line of a macro expansion, kept so the listing can show what a macro call assembled to
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Expansion {
+    /// Line the macro is called on
+    pub line: usize,
+    pub text: String,
+    pub address: usize,
+    pub size: usize,
+}
+
+/// Macro calls stop expanding once a program's calls have expanded to this many lines
+pub const MAX_EXPANDED: usize = 65536;
+
+/// Pseudo instructions are macros that are always defined: name, parameters and body
+const PSEUDO: [(&str, &str, &str); 5] = [
+    ("nop", "", "add R0,R0,R0"),
+    ("mov", "d,s", "add d,s,R0"),
+    ("clear", "d", "add d,R0,R0"),
+    ("inc", "d", "lea d,1[d]"),
+    ("dec", "d", "lea d,-1[d]"),
+];
+
+pub fn is_pseudo(name: &str) -> bool {
+    PSEUDO
+        .iter()
+        .any(|(pseudo, _, _)| pseudo.eq_ignore_ascii_case(name))
+}
+
+pub fn pseudo(name: &str) -> Option<Macro> {
+    let (_, params, body) = PSEUDO
+        .iter()
+        .find(|(pseudo, _, _)| pseudo.eq_ignore_ascii_case(name))?;
+    Some(Macro {
+        params: params
+            .split(',')
+            .filter(|param| !param.is_empty())
+            .map(str::to_string)
+            .collect(),
+        body: vec![format!("     {body}")],
+        line: 0,
+    })
+}
+
+impl Macro {
+    /// The body with each parameter replaced by its argument
+    /// Only whole names are replaced, and text in quotes is left alone
+    pub fn expand(&self, args: &[&str]) -> Vec<String> {
+        self.body
+            .iter()
+            .map(|line| {
+                let mut expanded = String::new();
+                let mut word = String::new();
+                let mut quote: Option<char> = None;
+                let mut escaped = false;
+                for c in line.chars().chain(std::iter::once(' ')) {
+                    if quote.is_none() && (c.is_ascii_alphanumeric() || c == '_') {
+                        word.push(c);
+                        continue;
+                    }
+                    if !word.is_empty() {
+                        match self.params.iter().position(|param| *param == word) {
+                            Some(i) => expanded.push_str(args[i]),
+                            None => expanded.push_str(&word),
+                        }
+                        word.clear();
+                    }
+                    match quote {
+                        Some(_) if escaped => escaped = false,
+                        Some(_) if c == '\\' => escaped = true,
+                        Some(q) if c == q => quote = None,
+                        Some(_) => {}
+                        None if c == '"' || c == '\'' => quote = Some(c),
+                        None => {}
+                    }
+                    expanded.push(c);
+                }
+                expanded.pop();
+                expanded
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_whole_parameter_names() {
+        let swap = Macro {
+            params: vec!["a".to_string(), "b".to_string()],
+            body: vec![
+                "     add R0,a,b".to_string(),
+                "ab   data \"a\",'b',a".to_string(),
+            ],
+            line: 1,
+        };
+        assert_eq!(
+            swap.expand(&["R1", "x+1"]),
+            vec!["     add R0,R1,x+1", "ab   data \"a\",'b',R1"]
+        );
+        assert_eq!(
+            pseudo("INC").unwrap().expand(&["R3"]),
+            vec!["     lea R3,1[R3]"]
+        );
+        assert!(is_pseudo("nop") && !is_pseudo("add"));
+    }
+}
@@ -1,4 +1,11 @@
 mod assembler;
+mod ast;
 pub(crate) mod code;
 pub(crate) mod error;
+pub(crate) mod linker;
+pub(crate) mod listing;
+pub(crate) mod macros;
+pub(crate) mod object;
+mod parser;
+pub(crate) mod symbol;
 mod tokens;
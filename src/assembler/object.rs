@@ -0,0 +1,225 @@
+use crate::assembler::code::Code;
+use crate::assembler::error::{AssemblingError, ErrorCode};
+use crate::assembler::symbol::{Symbol, SymbolKind};
+use crate::interpreter::memory::MEMORY_SIZE;
+use std::fmt::Write;
+
+/// Words or addresses on each data and relocate record
+const PER_RECORD: usize = 8;
+
+/// Sigma16 object code for an assembled program, one record per line:
+///     module Main
+///     org 0010              the following data starts at this address
+///     data f101,0006,c000   words in hexadecimal
+///     import Lib,y,0003     the word at 0003 holds Lib.y, plus its offset
+///     export x,0005,relocatable
+///     relocate 0001,0005    words holding addresses that move with the module
+pub fn object(code: &Code) -> String {
+    let mut out = String::new();
+    if let Some(module) = &code.module {
+        let _ = writeln!(out, "module {module}");
+    }
+
+    let mut used: Vec<usize> = code.memory_to_code.keys().copied().collect();
+    used.sort_unstable();
+    let mut runs: Vec<Vec<usize>> = Vec::new();
+    for address in used {
+        match runs.last_mut() {
+            Some(run) if run.last() == Some(&(address - 1)) && run.len() < PER_RECORD => {
+                run.push(address)
+            }
+            _ => runs.push(vec![address]),
+        }
+    }
+    let mut next = 0;
+    for run in runs {
+        if run[0] != next {
+            let _ = writeln!(out, "org {:04x}", run[0]);
+        }
+        let _ = writeln!(out, "data {}", hex(run.iter().map(|a| code.memory[*a])));
+        next = run[run.len() - 1] + 1;
+    }
+
+    let mut imports = code.imports.clone();
+    imports.sort();
+    for (address, local) in imports {
+        if let Some(SymbolKind::Imported { module, name }) =
+            code.symbol_table.get(&local).map(|symbol| &symbol.kind)
+        {
+            let _ = writeln!(out, "import {module},{name},{address:04x}");
+        }
+    }
+    for name in &code.exports {
+        if let Some(symbol) = code.symbol_table.get(name) {
+            let movable = match symbol.kind {
+                SymbolKind::Constant => "fixed",
+                _ => "relocatable",
+            };
+            let _ = writeln!(out, "export {name},{:04x},{movable}", symbol.value);
+        }
+    }
+    let mut relocations = code.relocations.clone();
+    relocations.sort_unstable();
+    for addresses in relocations.chunks(PER_RECORD) {
+        let _ = writeln!(out, "relocate {}", hex(addresses.iter().map(|a| *a as u16)));
+    }
+    out
+}
+
+fn hex(words: impl Iterator<Item = u16>) -> String {
+    words
+        .map(|word| format!("{word:04x}"))
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+/// Reads object code back into a program that can be run without its source
+/// Each word is mapped to the line of the record it came from
+pub fn load(text: String) -> Code {
+    let mut code = Code {
+        code: text.clone(),
+        object: true,
+        // No source to tell which registers are used, so show them all
+        used_registers: (0..16).collect(),
+        ..Default::default()
+    };
+
+    let mut address = 0_usize;
+    for (i, record) in text.lines().enumerate() {
+        let line = i + 1;
+        let record = record.trim();
+        if record.is_empty() {
+            continue;
+        }
+        let (kind, fields) = record
+            .split_once(char::is_whitespace)
+            .unwrap_or((record, ""));
+        let fields: Vec<&str> = fields.split(',').map(str::trim).collect();
+        let result = match kind {
+            "module" => match fields.as_slice() {
+                [name] if !name.is_empty() => {
+                    code.module = Some(name.to_string());
+                    Ok(())
+                }
+                _ => Err("module records have a name."),
+            },
+            "org" => match fields.as_slice() {
+                [word] => word_value(word).map(|word| address = word as usize),
+                _ => Err("org records have one address."),
+            },
+            "data" => fields.iter().try_for_each(|field| {
+                let word = word_value(field)?;
+                if address >= MEMORY_SIZE {
+                    return Err("The data does not fit in memory.");
+                }
+                if code.memory.len() <= address {
+                    code.memory.resize(address + 1, 0);
+                }
+                code.memory[address] = word;
+                if word == 0xc000 {
+                    code.trap_index.get_or_insert(address);
+                }
+                code.memory_to_code.insert(address, line);
+                address += 1;
+                Ok(())
+            }),
+            "import" => match fields.as_slice() {
+                [module, name, at, ..] => word_value(at).map(|at| {
+                    code.symbol_table.entry(name.to_string()).or_insert(Symbol {
+                        value: 0,
+                        kind: SymbolKind::Imported {
+                            module: module.to_string(),
+                            name: name.to_string(),
+                        },
+                        line,
+                    });
+                    code.imports.push((at as usize, name.to_string()));
+                }),
+                _ => Err("import records have a module, a symbol and an address."),
+            },
+            "export" => match fields.as_slice() {
+                [name, value, rest @ ..] => word_value(value).map(|value| {
+                    let kind = match rest.first() {
+                        Some(&"fixed") => SymbolKind::Constant,
+                        _ => SymbolKind::Relocatable,
+                    };
+                    code.symbol_table
+                        .insert(name.to_string(), Symbol { value, kind, line });
+                    code.exports.push(name.to_string());
+                }),
+                _ => Err("export records have a symbol and its value."),
+            },
+            "relocate" => fields.iter().try_for_each(|field| {
+                code.relocations.push(word_value(field)? as usize);
+                Ok(())
+            }),
+            _ => Err("Records start with module, org, data, import, export or relocate."),
+        };
+        if let Err(resolution) = result {
+            code.errors.push(AssemblingError::new(
+                ErrorCode::InvalidObject,
+                line,
+                None,
+                format!("Invalid object record {record}."),
+                resolution,
+            ));
+        }
+    }
+    if code.trap_index.is_none() {
+        code.errors.push(AssemblingError::new(
+            ErrorCode::NoTrap,
+            0,
+            None,
+            "No trap instruction, program will never terminate when run.".to_string(),
+            "The object code needs a data word of c000 to stop.",
+        ));
+    }
+    code
+}
+
+/// A word of object code, always in hexadecimal
+fn word_value(field: &str) -> Result<u16, &'static str> {
+    u16::from_str_radix(field, 16).map_err(|_| "Words are written as 4 hexadecimal digits.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_object_code() {
+        let source = "Main module\ny    import Lib,z\n     load R1,x[R0]\n     lea R2,y+1[R0]\n     trap R0,R0,R0\n     org $0010\nx    data x,1,2,3,4,5,6,7,8\nn    equ 3\n     export x,n\n";
+        let code = Code::new(source.to_string());
+        assert!(code.errors.is_empty());
+        let text = object(&code);
+        assert_eq!(
+            text,
+            "module Main\ndata f101,0010,f200,0001,c000\norg 0010\ndata 0010,0001,0002,0003,0004,0005,0006,0007\ndata 0008\nimport Lib,z,0003\nexport x,0010,relocatable\nexport n,0003,fixed\nrelocate 0001,0010\n"
+        );
+
+        let loaded = load(text);
+        assert!(loaded.object);
+        assert!(loaded.errors.is_empty());
+        assert_eq!(loaded.module, code.module);
+        assert_eq!(loaded.memory, code.memory);
+        assert_eq!(loaded.relocations, code.relocations);
+        assert_eq!(loaded.imports, vec![(3, "z".to_string())]);
+        assert_eq!(loaded.exports, code.exports);
+        assert_eq!(loaded.symbol_table["x"].value, 0x10);
+        assert_eq!(loaded.symbol_table["n"].kind, SymbolKind::Constant);
+        assert_eq!(loaded.trap_index, Some(4));
+        assert_eq!(loaded.memory_to_code[&0x11], 4);
+        assert_eq!(loaded.memory_to_code[&0x18], 5);
+    }
+
+    #[test]
+    fn reports_bad_records() {
+        let code = load("data c000\ndata 12345\nimport Lib\nfoo 1\norg\nrelocate zz\n".to_string());
+        let lines: Vec<usize> = code.errors.iter().map(|e| e.line).collect();
+        assert_eq!(lines, vec![2, 3, 4, 5, 6]);
+        assert!(code
+            .errors
+            .iter()
+            .all(|e| e.code == ErrorCode::InvalidObject));
+    }
+}
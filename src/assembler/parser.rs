@@ -0,0 +1,421 @@
+use crate::assembler::assembler::Format;
+use crate::assembler::ast::{BinaryOp, Expr, Label, Line, Operand, OperandKind, Statement};
+use crate::assembler::error::{AssemblingError, ErrorCode};
+use crate::assembler::macros::is_pseudo;
+use crate::assembler::tokens::Tokens;
+use logos::Logos;
+use std::ops::Range;
+
+/// Parses one line of source into its label, statement and comment
+/// A line is made of whitespace separated fields:
+///     label mnemonic operands ; comment
+/// The label must start in the first column, everything after a ; is a comment
+/// An instruction in the first column is accepted when the field after it is not a mnemonic
+/// Calls to the macros defined so far count as mnemonics, is_macro tells which names they have
+pub fn parse_line(
+    number: usize,
+    text: &str,
+    is_macro: impl Fn(&str) -> bool,
+) -> Result<Line, AssemblingError> {
+    let (code, comment) = match comment_start(text) {
+        Some(start) => (&text[..start], Some(text[start + 1..].to_string())),
+        None => (text, None),
+    };
+    let mut line = Line {
+        number,
+        comment,
+        ..Default::default()
+    };
+
+    let fields = fields(code);
+    let is_mnemonic = |span: &Range<usize>| {
+        let name = &code[span.clone()];
+        Format::lookup(name).is_some() || is_pseudo(name) || is_macro(name)
+    };
+    let starts_with_label = code.chars().next().is_some_and(|c| !c.is_whitespace())
+        && !(is_mnemonic(&fields[0]) && !fields.get(1).is_some_and(is_mnemonic));
+
+    let mut fields = fields.into_iter();
+    if starts_with_label {
+        if let Some(span) = fields.next() {
+            let name = &code[span.clone()];
+            if !is_identifier(name) {
+                return Err(error(
+                    number,
+                    span,
+                    ErrorCode::InvalidLabel,
+                    format!("Invalid label {name}."),
+                    "Labels start with a letter followed by letters, digits or _.",
+                ));
+            }
+            line.label = Some(Label {
+                name: name.to_string(),
+                span,
+            });
+        }
+    }
+
+    let Some(span) = fields.next() else {
+        return Ok(line);
+    };
+    let mnemonic = code[span.clone()].to_string();
+    // The operands run to the end of the line, they may have spaces after the commas
+    let operands = match fields.next() {
+        Some(operands) => parse_operands(number, code, operands.start..code.trim_end().len())?,
+        None => Vec::new(),
+    };
+
+    line.statement = Some(Statement {
+        mnemonic,
+        span,
+        operands,
+        expansion: None,
+    });
+    Ok(line)
+}
+
+/// Byte offset of the ; that starts a comment, ignoring any inside quotes
+pub(super) fn comment_start(text: &str) -> Option<usize> {
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == ';' => return Some(i),
+            None => {}
+        }
+    }
+    None
+}
+
+/// Spans of the whitespace separated fields of a line, whitespace inside quotes is kept
+pub(super) fn fields(code: &str) -> Vec<Range<usize>> {
+    let mut fields = Vec::new();
+    let mut start: Option<usize> = None;
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    for (i, c) in code.char_indices() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None => match (start, c.is_whitespace()) {
+                (None, false) => {
+                    start = Some(i);
+                    if c == '"' || c == '\'' {
+                        quote = Some(c);
+                    }
+                }
+                (Some(_), false) if c == '"' || c == '\'' => quote = Some(c),
+                (Some(s), true) => {
+                    fields.push(s..i);
+                    start = None;
+                }
+                _ => {}
+            },
+        }
+    }
+    if let Some(s) = start {
+        fields.push(s..code.len());
+    }
+    fields
+}
+
+pub(super) fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Parses the comma separated operand field
+///     operand := Rn | expr | expr[Rn]
+fn parse_operands(
+    number: usize,
+    code: &str,
+    field: Range<usize>,
+) -> Result<Vec<Operand>, AssemblingError> {
+    let mut tokens = Vec::new();
+    for (token, span) in Tokens::lexer(&code[field.clone()]).spanned() {
+        let span = span.start + field.start..span.end + field.start;
+        match token {
+            Ok(token) => tokens.push((token, span)),
+            Err(_) => {
+                return Err(error(
+                    number,
+                    span.clone(),
+                    ErrorCode::UnexpectedCharacter,
+                    format!("Unexpected {} in the operands.", &code[span]),
+                    "Operands are registers, numbers or labels separated by commas.",
+                ))
+            }
+        }
+    }
+
+    let mut parser = OperandParser {
+        number,
+        code,
+        field,
+        tokens,
+        position: 0,
+    };
+    let mut operands = Vec::new();
+    loop {
+        operands.push(parser.operand()?);
+        match parser.next() {
+            None => return Ok(operands),
+            Some((Tokens::Comma, _)) => {}
+            Some((_, span)) => {
+                return Err(parser.error(
+                    ErrorCode::ExpectedComma,
+                    span.start..parser.field.end,
+                    format!(
+                        "Expected a comma before {}.",
+                        &code[span.start..parser.field.end]
+                    ),
+                    "Operands are separated by commas, comments start with ;.",
+                ))
+            }
+        }
+    }
+}
+
+/// Recursive descent parser over the tokens of an operand field
+struct OperandParser<'a> {
+    number: usize,
+    code: &'a str,
+    field: Range<usize>,
+    tokens: Vec<(Tokens, Range<usize>)>,
+    position: usize,
+}
+
+impl OperandParser<'_> {
+    fn peek(&self) -> Option<&Tokens> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    fn next(&mut self) -> Option<(Tokens, Range<usize>)> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    /// End of the last token taken
+    fn end(&self) -> usize {
+        match self.tokens.get(self.position.saturating_sub(1)) {
+            Some((_, span)) => span.end,
+            None => self.field.start,
+        }
+    }
+
+    fn error(
+        &self,
+        code: ErrorCode,
+        span: Range<usize>,
+        message: String,
+        resolution: &str,
+    ) -> AssemblingError {
+        error(self.number, span, code, message, resolution)
+    }
+
+    fn operand(&mut self) -> Result<Operand, AssemblingError> {
+        let start = match self.tokens.get(self.position) {
+            Some((_, span)) => span.start,
+            None => {
+                return Err(self.error(
+                    ErrorCode::MissingOperand,
+                    self.field.clone(),
+                    format!("Missing operand after {}.", &self.code[self.field.clone()]),
+                    "Remove the trailing comma or add the missing operand.",
+                ))
+            }
+        };
+        let kind = if let Some(Tokens::Register(reg)) = self.peek() {
+            let reg = *reg;
+            self.position += 1;
+            OperandKind::Register(reg)
+        } else if let Some((Tokens::String(text), span)) = self.tokens.get(self.position).cloned() {
+            self.position += 1;
+            OperandKind::String(self.unescape(&text, &span)?)
+        } else {
+            let expr = self.expr()?;
+            if self.peek() == Some(&Tokens::LBracket) {
+                self.position += 1;
+                match (self.next(), self.next()) {
+                    (Some((Tokens::Register(reg), _)), Some((Tokens::RBracket, _))) => {
+                        OperandKind::Indexed(expr, reg)
+                    }
+                    _ => {
+                        return Err(self.error(
+                            ErrorCode::InvalidOperand,
+                            start..self.end(),
+                            format!("Expected {expr}[Rn] in {}.", &self.code[self.field.clone()]),
+                            "The index register is written in square brackets, for example x[R1].",
+                        ))
+                    }
+                }
+            } else {
+                OperandKind::Expr(expr)
+            }
+        };
+        Ok(Operand {
+            kind,
+            span: start..self.end(),
+        })
+    }
+
+    /// expr := term (+ term | - term)*
+    fn expr(&mut self) -> Result<Expr, AssemblingError> {
+        let mut expr = self.term()?;
+        loop {
+            let op = match self.peek() {
+                Some(Tokens::Plus) => BinaryOp::Add,
+                Some(Tokens::Minus) => BinaryOp::Sub,
+                _ => return Ok(expr),
+            };
+            self.position += 1;
+            expr = Expr::Binary(Box::new(expr), op, Box::new(self.term()?));
+        }
+    }
+
+    /// term := -term | (expr) | number | symbol
+    fn term(&mut self) -> Result<Expr, AssemblingError> {
+        let Some((token, span)) = self.next() else {
+            return Err(self.error(
+                ErrorCode::InvalidOperand,
+                self.field.clone(),
+                format!(
+                    "{} ends before the value is complete.",
+                    &self.code[self.field.clone()]
+                ),
+                "Add the missing number or label.",
+            ));
+        };
+        match token {
+            Tokens::Minus => Ok(Expr::Neg(Box::new(self.term()?))),
+            Tokens::LParen => {
+                let expr = self.expr()?;
+                match self.next() {
+                    Some((Tokens::RParen, _)) => Ok(expr),
+                    _ => Err(self.error(
+                        ErrorCode::InvalidOperand,
+                        self.field.clone(),
+                        format!("Missing ) in {}.", &self.code[self.field.clone()]),
+                        "Every ( needs a matching ).",
+                    )),
+                }
+            }
+            Tokens::Decimal(digits) => self.number(span, &digits, 10),
+            Tokens::Hex(digits) => self.number(span, &digits, 16),
+            Tokens::Binary(digits) => self.number(span, &digits, 2),
+            Tokens::Symbol(name) => Ok(Expr::Symbol(name)),
+            Tokens::Char(text) => {
+                let text = self.unescape(&text, &span)?;
+                let mut chars = text.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Ok(Expr::Number(c as i64)),
+                    _ => Err(self.error(
+                        ErrorCode::InvalidOperand,
+                        span.clone(),
+                        format!("{} is not a single character.", &self.code[span]),
+                        "Characters are written in single quotes like 'a', use double quotes for strings.",
+                    )),
+                }
+            }
+            _ => Err(self.error(
+                ErrorCode::InvalidOperand,
+                span.clone(),
+                format!("Expected a number or label, found {}.", &self.code[span]),
+                "Values are numbers, labels, or sums of them like x+1.",
+            )),
+        }
+    }
+
+    /// Replaces the escapes in the text between quotes
+    fn unescape(&self, text: &str, span: &Range<usize>) -> Result<String, AssemblingError> {
+        let mut result = String::new();
+        let mut chars = text.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                result.push(c);
+                continue;
+            }
+            result.push(match chars.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('0') => '\0',
+                Some(c @ ('\\' | '\'' | '"')) => c,
+                other => {
+                    return Err(self.error(
+                        ErrorCode::InvalidEscape,
+                        span.clone(),
+                        format!(
+                            "Unknown escape \\{} in {}.",
+                            other.map(String::from).unwrap_or_default(),
+                            &self.code[span.clone()]
+                        ),
+                        "The escapes are \\n, \\t, \\0, \\\\, \\' and \\\".",
+                    ))
+                }
+            });
+        }
+        Ok(result)
+    }
+
+    /// Parses the digits of a number, the range is checked when the expression is evaluated
+    fn number(
+        &self,
+        span: Range<usize>,
+        digits: &str,
+        radix: u32,
+    ) -> Result<Expr, AssemblingError> {
+        let text = &self.code[span.clone()];
+        if digits.is_empty() {
+            return Err(self.error(
+                ErrorCode::InvalidNumber,
+                span,
+                format!("{text} has no digits."),
+                "Write hexadecimal as $00ff or 0x00ff and binary as 0b1010.",
+            ));
+        }
+        match i64::from_str_radix(digits, radix) {
+            Ok(value) if value <= 0xffff_ffff => Ok(Expr::Number(value)),
+            Ok(_) => Err(self.error(
+                ErrorCode::InvalidNumber,
+                span,
+                format!("{text} does not fit in 16 bits."),
+                "Values are from -32768 to 65535.",
+            )),
+            Err(_) if digits.chars().all(|c| c.is_digit(radix)) => Err(self.error(
+                ErrorCode::InvalidNumber,
+                span,
+                format!("{text} does not fit in 16 bits."),
+                "Values are from -32768 to 65535.",
+            )),
+            Err(_) => Err(self.error(
+                ErrorCode::InvalidNumber,
+                span,
+                format!("{text} is not a base {radix} number."),
+                match radix {
+                    16 => "Hexadecimal digits are 0 to 9 and a to f.",
+                    2 => "Binary digits are 0 and 1.",
+                    _ => "Decimal digits are 0 to 9.",
+                },
+            )),
+        }
+    }
+}
+
+fn error(
+    line: usize,
+    span: Range<usize>,
+    code: ErrorCode,
+    message: String,
+    resolution: &str,
+) -> AssemblingError {
+    AssemblingError::new(code, line, Some(span), message, resolution)
+}
@@ -0,0 +1,51 @@
+/// What a symbol's value depends on
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum SymbolKind {
+    /// A number defined with equ, it never changes
+    Constant,
+    /// An address in this module, it moves when the module is placed somewhere else in memory
+    Relocatable,
+    /// Defined in another module, the value is only known once the modules are linked
+    Imported { module: String, name: String },
+}
+
+/// An entry in the symbol table
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(from = "SavedSymbol")]
+pub struct Symbol {
+    pub value: u16,
+    pub kind: SymbolKind,
+    /// Line the symbol is defined on
+    pub line: usize,
+}
+
+impl Symbol {
+    pub fn is_address(&self) -> bool {
+        self.kind == SymbolKind::Relocatable
+    }
+}
+
+/// Symbol tables used to only store the address of each label
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum SavedSymbol {
+    Symbol {
+        value: u16,
+        kind: SymbolKind,
+        line: usize,
+    },
+    Address(usize),
+}
+
+impl From<SavedSymbol> for Symbol {
+    fn from(saved: SavedSymbol) -> Self {
+        match saved {
+            SavedSymbol::Symbol { value, kind, line } => Symbol { value, kind, line },
+            SavedSymbol::Address(address) => Symbol {
+                value: address as u16,
+                kind: SymbolKind::Relocatable,
+                line: 0,
+            },
+        }
+    }
+}
@@ -1,15 +1,26 @@
+use crate::assembler::code::Code;
+use crate::assembler::linker::link;
+use crate::assembler::object;
 use crate::gui::code_editor::CodeEditor;
 use crate::gui::code_runner::CodeRunner;
 use crate::gui::data_flow;
 use crate::gui::exercises::EXERCISES;
+use crate::interpreter::breakpoint::Breakpoint;
 
 use log::{log, Level};
+use std::collections::{BTreeMap, BTreeSet};
 
 #[derive(serde::Deserialize, serde::Serialize)]
 #[serde(default)] // if we add new fields, give them default values when deserializing old state
 pub struct VisualisingSigma16 {
     show_code_editor: bool,
     pub code_editor: Vec<CodeEditor>,
+    /// Name and text of object code being pasted in
+    #[serde(skip)]
+    loading_object: Option<(String, String)>,
+    /// Which editors are picked to be linked together
+    #[serde(skip)]
+    linking: Option<Vec<bool>>,
 }
 
 impl Default for VisualisingSigma16 {
@@ -18,6 +29,8 @@ impl Default for VisualisingSigma16 {
         Self {
             show_code_editor: true,
             code_editor: vec![CodeEditor::default()],
+            loading_object: None,
+            linking: None,
         }
     }
 }
@@ -59,6 +72,8 @@ impl VisualisingSigma16 {
                                 VisualisingSigma16::code_runner_gui(
                                     runner,
                                     editor.code.clone(),
+                                    &mut editor.breakpoints,
+                                    &mut editor.conditions,
                                     ctx,
                                     &editor.name,
                                 );
@@ -88,9 +103,102 @@ impl VisualisingSigma16 {
         }
     }
 
-    fn code_runner_gui(runner: &mut CodeRunner, code: String, ctx: &egui::Context, name: &String) {
-        egui::Window::new(format!("Runner: {}", name))
-            .show(ctx, |ui| CodeRunner::gui(runner, ui, code));
+    /// Object code is pasted in and run without any source
+    fn load_object_gui(&mut self, ctx: &egui::Context) {
+        let mut open = false;
+        let mut cancel = false;
+        if let Some((name, text)) = &mut self.loading_object {
+            egui::Window::new("Load Object").show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.label("Name: ");
+                    ui.add(egui::TextEdit::singleline(name));
+                });
+                ui.add(
+                    egui::TextEdit::multiline(text)
+                        .font(egui::TextStyle::Monospace)
+                        .desired_rows(10)
+                        .hint_text("Paste Sigma16 object code here"),
+                );
+                ui.horizontal(|ui| {
+                    open = ui.add(egui::Button::new("Open")).clicked();
+                    cancel = ui.add(egui::Button::new("Cancel")).clicked();
+                });
+            });
+        }
+        if open {
+            if let Some((name, text)) = self.loading_object.take() {
+                self.code_editor.push(CodeEditor::new_object(name, text));
+            }
+        }
+        if cancel {
+            self.loading_object = None;
+        }
+    }
+
+    /// Modules from several editors are linked into one program, in the order they are picked
+    fn link_gui(&mut self, ctx: &egui::Context) {
+        let mut linking = false;
+        let mut cancel = false;
+        if let Some(picked) = &mut self.linking {
+            picked.resize(self.code_editor.len(), false);
+            egui::Window::new("Link Modules").show(ctx, |ui| {
+                ui.label("The first module is placed at address 0 and runs first.");
+                for (editor, picked) in self.code_editor.iter().zip(picked.iter_mut()) {
+                    if editor.linked.is_none() {
+                        ui.checkbox(picked, &editor.name);
+                    }
+                }
+                ui.horizontal(|ui| {
+                    linking = ui
+                        .add_enabled(picked.contains(&true), egui::Button::new("Link"))
+                        .clicked();
+                    cancel = ui.add(egui::Button::new("Cancel")).clicked();
+                });
+            });
+        }
+        if linking {
+            if let Some(picked) = self.linking.take() {
+                let modules: Vec<(&str, Code)> = self
+                    .code_editor
+                    .iter()
+                    .zip(picked)
+                    .filter(|(_, picked)| *picked)
+                    .map(|(editor, _)| {
+                        let code = if editor.object {
+                            object::load(editor.code.clone())
+                        } else {
+                            Code::new(editor.code.clone())
+                        };
+                        (editor.name.as_str(), code)
+                    })
+                    .collect();
+                let name = modules
+                    .iter()
+                    .map(|(name, _)| *name)
+                    .collect::<Vec<&str>>()
+                    .join(" + ");
+                let modules: Vec<(&str, &Code)> =
+                    modules.iter().map(|(name, code)| (*name, code)).collect();
+                let linked = link(&modules);
+                self.code_editor.push(CodeEditor::new_linked(name, linked));
+            }
+        }
+        if cancel {
+            self.linking = None;
+        }
+    }
+
+    fn code_runner_gui(
+        runner: &mut CodeRunner,
+        code: String,
+        breakpoints: &mut BTreeSet<usize>,
+        conditions: &mut BTreeMap<usize, Breakpoint>,
+        ctx: &egui::Context,
+        name: &String,
+    ) {
+        egui::Window::new(format!("Runner: {}", name)).show(ctx, |ui| {
+            CodeRunner::gui(runner, ui, code, breakpoints, conditions)
+        });
     }
 }
 
@@ -181,7 +289,17 @@ impl eframe::App for VisualisingSigma16 {
                     if new.clicked() {
                         self.code_editor.push(CodeEditor::new_windowed());
                     }
+
+                    if ui.add(egui::Button::new("Load Object")).clicked() {
+                        self.loading_object = Some(("object".to_string(), String::new()));
+                    }
+
+                    if ui.add(egui::Button::new("Link")).clicked() {
+                        self.linking = Some(vec![false; self.code_editor.len()]);
+                    }
                 });
+                self.load_object_gui(ctx);
+                self.link_gui(ctx);
                 ui.separator();
                 ui.horizontal(|ui| {
                     self.build_ui(ctx, ui);
@@ -1,8 +1,20 @@
+use crate::assembler::code::Code;
+use crate::assembler::error::{AssemblingError, Severity};
+use crate::assembler::listing::listing;
+use crate::assembler::object::{self, object};
 use crate::gui::app::VisualisingSigma16;
 use crate::gui::code_runner::CodeRunner;
-use crate::gui::syntax_highlighting::{highlight, CodeTheme};
+use crate::gui::syntax_highlighting::{highlight, underline, CodeTheme};
+use crate::gui::util::download;
+use crate::interpreter::breakpoint::{self, Breakpoint};
 use egui::{Galley, Response};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::Range;
 use std::sync::Arc;
+use std::time::Duration;
+
+/// Seconds after the last edit before the code is assembled again
+const ASSEMBLE_DELAY: f64 = 0.5;
 
 #[derive(serde::Deserialize, serde::Serialize)]
 #[serde(default)] // if we add new fields, give them default values when deserializing old state
@@ -14,6 +26,29 @@ pub struct CodeEditor {
     pub name: String,
     pub renaming: bool,
     pub deleting: bool,
+    pub tab: EditorTab,
+    /// Holds object code instead of source, it is loaded rather than assembled
+    pub object: bool,
+    /// The program linked from other editors, the code shown is the modules' sources
+    pub linked: Option<Code>,
+    /// Lines the runner stops before when running
+    pub breakpoints: BTreeSet<usize>,
+    /// Conditions and hit counts of the breakpoints that have them
+    pub conditions: BTreeMap<usize, Breakpoint>,
+    /// The assembled code and its listing, so they are only rebuilt after an edit
+    #[serde(skip)]
+    assembled: Option<(Code, String)>,
+    /// Time of the last edit that has not been assembled yet
+    #[serde(skip)]
+    edited: Option<f64>,
+}
+
+/// What the editor is showing
+#[derive(Clone, Copy, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum EditorTab {
+    #[default]
+    Code,
+    Listing,
 }
 
 impl Default for CodeEditor {
@@ -26,11 +61,38 @@ impl Default for CodeEditor {
             name: "untitled".to_string(),
             renaming: false,
             deleting: false,
+            tab: EditorTab::Code,
+            object: false,
+            linked: None,
+            breakpoints: BTreeSet::new(),
+            conditions: BTreeMap::new(),
+            assembled: None,
+            edited: None,
         }
     }
 }
 
 impl CodeEditor {
+    /// An editor for object code that is run without its source
+    pub fn new_object(name: String, object: String) -> Self {
+        let mut editor = CodeEditor::new_windowed();
+        editor.name = name;
+        editor.code = object;
+        editor.object = true;
+        editor.open_runner();
+        editor
+    }
+
+    /// A read only editor for modules linked into one program
+    pub fn new_linked(name: String, linked: Code) -> Self {
+        let mut editor = CodeEditor::new_windowed();
+        editor.name = name;
+        editor.code = linked.code.clone();
+        editor.linked = Some(linked);
+        editor.open_runner();
+        editor
+    }
+
     pub fn new_windowed() -> Self {
         let mut new_editor = CodeEditor::default();
         new_editor.windowed = true;
@@ -46,6 +108,7 @@ impl CodeEditor {
             &mut dyn for<'a, 'b> FnMut(&'a egui::Ui, &'b str, f32) -> Arc<Galley>,
         >,
     ) {
+        self.assemble_live(ui.ctx());
         ui.vertical(|ui| {
             ui.horizontal(|ui| {
                 match self.runner {
@@ -78,19 +141,173 @@ impl CodeEditor {
                 if ui.add(egui::Button::new("Delete")).clicked() {
                     self.deleting = true;
                 }
+
+                self.make_badge(ui);
             });
-            egui::ScrollArea::new([true, true]).show(ui, |ui| {
-                ui.horizontal(|ui| {
-                    CodeEditor::make_line_counter(&self.code, ui, None);
-                    CodeEditor::make_editor(&mut self.code, ui, editable);
-                });
-            });
+            if !self.object && self.linked.is_none() {
+                self.make_tabs(ui);
+            }
+            let editable = editable && self.linked.is_none();
+            match self.tab {
+                EditorTab::Code => {
+                    egui::ScrollArea::new([true, true]).show(ui, |ui| {
+                        ui.horizontal(|ui| {
+                            let errors = live_errors(&self.linked, &self.assembled);
+                            let symbols = match &self.runner {
+                                Some(runner) => runner.state.symbol_table.clone(),
+                                None => HashMap::new(),
+                            };
+                            let problems = breakpoint::problems(&mut self.conditions, &symbols);
+                            CodeEditor::make_line_counter(
+                                &self.code,
+                                ui,
+                                None,
+                                &mut self.breakpoints,
+                                &problems,
+                            );
+                            // The code before any edit this frame, for breakpoints to follow their lines
+                            let before = self.code.clone();
+                            let response =
+                                CodeEditor::make_editor(&mut self.code, ui, editable, errors);
+                            if response.changed() {
+                                self.edited = Some(ui.input(|input| input.time));
+                                self.move_breakpoints(&before);
+                            }
+                        });
+                    });
+                }
+                EditorTab::Listing => {
+                    let mut listing = self.assembled().1.as_str();
+                    egui::ScrollArea::new([true, true])
+                        .id_salt("listing")
+                        .show(ui, |ui| {
+                            ui.add(
+                                egui::TextEdit::multiline(&mut listing)
+                                    .font(egui::TextStyle::Monospace)
+                                    .code_editor()
+                                    .desired_rows(10)
+                                    .desired_width(f32::INFINITY),
+                            );
+                        });
+                }
+            }
         });
     }
 
+    /// Tabs for the code and its listing, and saving what the code assembles to
+    /// The code is only assembled here when it is saved, typing is left to assemble_live
+    fn make_tabs(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.selectable_value(&mut self.tab, EditorTab::Code, "Code");
+            ui.selectable_value(&mut self.tab, EditorTab::Listing, "Listing");
+            let name = self.name.clone();
+            if ui.button("Save Listing").clicked() {
+                download(&format!("{name}.lst"), &self.assembled().1);
+            }
+            let errors = live_errors(&self.linked, &self.assembled);
+            let assembled = !errors.iter().any(|error| error.is_error());
+            if ui
+                .add_enabled(assembled, egui::Button::new("Save Object"))
+                .on_disabled_hover_text("Fix the errors before saving the object code.")
+                .clicked()
+            {
+                // The code may have changed since it was last assembled
+                let (code, _) = self.assembled();
+                if !code.errors.iter().any(|error| error.is_error()) {
+                    download(&format!("{name}.obj"), &object(code));
+                }
+            }
+        });
+    }
+
+    /// The assembled code and its listing, assembling again if the code has changed
+    fn assembled(&mut self) -> &(Code, String) {
+        if self
+            .assembled
+            .as_ref()
+            .is_some_and(|(code, _)| code.code != self.code)
+        {
+            self.assembled = None;
+        }
+        let source = &self.code;
+        let is_object = self.object;
+        self.assembled.get_or_insert_with(|| {
+            let code = if is_object {
+                object::load(source.clone())
+            } else {
+                Code::new(source.clone())
+            };
+            let listing = listing(&code);
+            (code, listing)
+        })
+    }
+
+    /// Assembles the code once typing has paused, so errors show without running the program
+    fn assemble_live(&mut self, ctx: &egui::Context) {
+        if self.linked.is_some() {
+            return;
+        }
+        let now = ctx.input(|input| input.time);
+        match assemble_wait(self.edited, now) {
+            Some(wait) => ctx.request_repaint_after(Duration::from_secs_f64(wait)),
+            None => {
+                self.edited = None;
+                self.assembled();
+            }
+        }
+    }
+
+    /// Moves breakpoints and their conditions to follow their lines after an edit
+    fn move_breakpoints(&mut self, before: &str) {
+        let moved = |line: usize| follow_edit(before, &self.code, line);
+        self.breakpoints = self
+            .breakpoints
+            .iter()
+            .filter_map(|line| moved(*line))
+            .collect();
+        self.conditions = std::mem::take(&mut self.conditions)
+            .into_iter()
+            .filter_map(|(line, condition)| Some((moved(line)?, condition)))
+            .collect();
+    }
+
+    /// Number of errors and warnings, with each of them listed on hover
+    fn make_badge(&self, ui: &mut egui::Ui) {
+        let errors = live_errors(&self.linked, &self.assembled);
+        let count = |severity: Severity| errors.iter().filter(|e| e.severity == severity).count();
+        let list = errors
+            .iter()
+            .map(|error| match error.line {
+                0 => format!("{} {}: {}", error.severity, error.code, error.message),
+                line => format!("Line {line}: {}", error.message),
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+        let badges = [(Severity::Error, "error"), (Severity::Warning, "warning")];
+        for (severity, kind) in badges {
+            let count = count(severity);
+            if count == 0 {
+                continue;
+            }
+            let plural = if count == 1 { "" } else { "s" };
+            ui.label(
+                egui::RichText::new(format!(" {count} {kind}{plural} "))
+                    .color(egui::Color32::BLACK)
+                    .background_color(severity_colour(severity, ui)),
+            )
+            .on_hover_text(&list);
+        }
+    }
+
     fn open_runner(&mut self) {
         let mut runner = CodeRunner::default();
-        runner.reset(self.code.clone());
+        if let Some(linked) = &self.linked {
+            runner.reset_linked(linked.clone());
+        } else if self.object {
+            runner.reset_object(self.code.clone());
+        } else {
+            runner.reset(self.code.clone());
+        }
         self.runner = Some(runner);
     }
 
@@ -98,21 +315,61 @@ impl CodeEditor {
         self.runner = None;
     }
 
-    pub fn make_editor(code: &mut String, ui: &mut egui::Ui, editable: bool) -> Response {
-        ui.add(
-            egui::TextEdit::multiline(code)
-                .font(egui::TextStyle::Monospace) // for cursor height
-                .code_editor()
-                .desired_rows(10)
-                .lock_focus(true)
-                .desired_width(f32::INFINITY)
-                .layouter(&mut |ui: &egui::Ui, string: &str, _wrap_width: f32| {
-                    CodeEditor::layouter(&ui, string, _wrap_width)
-                })
-                .interactive(editable),
-        )
+    /// The code with errors underlined, hovering over an error shows its message and resolution
+    pub fn make_editor(
+        code: &mut String,
+        ui: &mut egui::Ui,
+        editable: bool,
+        errors: &[AssemblingError],
+    ) -> Response {
+        let output = egui::TextEdit::multiline(code)
+            .font(egui::TextStyle::Monospace) // for cursor height
+            .code_editor()
+            .desired_rows(10)
+            .lock_focus(true)
+            .desired_width(f32::INFINITY)
+            .layouter(&mut |ui: &egui::Ui, string: &str, _wrap_width: f32| {
+                CodeEditor::layouter(&ui, string, _wrap_width, errors)
+            })
+            .interactive(editable)
+            .show(ui);
+
+        let Some(pos) = output.response.hover_pos() else {
+            return output.response;
+        };
+        let cursor = output
+            .galley
+            .cursor_from_pos(pos - output.galley_pos)
+            .pcursor;
+        let line = cursor.paragraph + 1;
+        // Spans are byte offsets, the cursor counts characters
+        let byte = code
+            .lines()
+            .nth(cursor.paragraph)
+            .and_then(|text| text.char_indices().nth(cursor.offset))
+            .map(|(byte, _)| byte);
+        let hovered = errors.iter().find(|error| {
+            error.line == line
+                && match (&error.span, byte) {
+                    (Some(span), Some(byte)) => span.contains(&byte),
+                    (Some(_), None) => false,
+                    (None, _) => true,
+                }
+        });
+        match hovered {
+            Some(error) => output.response.on_hover_ui_at_pointer(|ui| {
+                ui.colored_label(
+                    severity_colour(error.severity, ui),
+                    format!("{} {}", error.severity, error.code),
+                );
+                ui.label(&error.message);
+                ui.label(&error.resolution);
+            }),
+            None => output.response,
+        }
     }
 
+    /// Line numbers, clicking one adds or removes a breakpoint on that line
     pub fn make_line_counter(
         code: &String,
         ui: &mut egui::Ui,
@@ -120,6 +377,8 @@ impl CodeEditor {
             &mut dyn for<'a, 'b> FnMut(&'a egui::Ui, &'b str, f32) -> Arc<Galley>,
             &usize,
         )>,
+        breakpoints: &mut BTreeSet<usize>,
+        problems: &BTreeMap<usize, String>,
     ) -> Response {
         let line_count = code.as_str().lines().count();
         let mut line_numbers_builder: Vec<String> = Vec::with_capacity(line_count);
@@ -128,7 +387,12 @@ impl CodeEditor {
             line_numbers_builder.push(format!("{line},"));
         }
         for i in 1..line_count + 1 {
-            line_numbers_builder.push(format!("{:>indent$}\n", i,));
+            let marker = match (breakpoints.contains(&i), problems.contains_key(&i)) {
+                (true, true) => '!',
+                (true, false) => '●',
+                (false, _) => ' ',
+            };
+            line_numbers_builder.push(format!("{marker}{:>indent$}\n", i,));
         }
         let _line_numbers = line_numbers_builder.concat();
         let mut line_numbers = _line_numbers.as_str();
@@ -137,17 +401,201 @@ impl CodeEditor {
             .font(egui::TextStyle::Monospace)
             .code_editor()
             .desired_rows(10)
-            .desired_width(6.9 * indent as f32)
+            .desired_width(6.9 * (indent + 1) as f32)
             .lock_focus(false);
         if let Some((line_number_layouter, _)) = line_number_layouter {
             lines = lines.layouter(line_number_layouter);
         };
-        ui.add(lines)
+        let output = lines.show(ui);
+        if let Some(pos) = output.response.interact_pointer_pos() {
+            if output.response.clicked() {
+                let cursor = output.galley.cursor_from_pos(pos - output.galley_pos);
+                let line = cursor.pcursor.paragraph + 1;
+                if line <= line_count && !breakpoints.remove(&line) {
+                    breakpoints.insert(line);
+                }
+            }
+        }
+        // Conditions that can not be evaluated are listed with the line they are on
+        let mut hover = "Click a line number to add or remove a breakpoint.".to_string();
+        for (line, problem) in problems {
+            if breakpoints.contains(line) {
+                hover.push_str(&format!("\nLine {line}: {problem}"));
+            }
+        }
+        output.response.on_hover_text(hover)
     }
 
-    pub fn layouter(ui: &egui::Ui, string: &str, _wrap_width: f32) -> Arc<Galley> {
-        let layout_job = highlight(ui.ctx(), &CodeTheme::default(), string, "Sigma16");
+    pub fn layouter(
+        ui: &egui::Ui,
+        string: &str,
+        _wrap_width: f32,
+        errors: &[AssemblingError],
+    ) -> Arc<Galley> {
+        let mut layout_job = highlight(ui.ctx(), &CodeTheme::default(), string, "Sigma16");
+        let ranges: Vec<(Range<usize>, egui::Color32)> = error_ranges(string, errors)
+            .into_iter()
+            .map(|(range, severity)| (range, severity_colour(severity, ui)))
+            .collect();
+        underline(&mut layout_job, &ranges);
         // layout_job.wrap.max_width = wrap_width; // no wrapping
         ui.fonts(|font| font.layout_job(layout_job))
     }
 }
+
+/// Errors are red and warnings yellow, wherever they are shown
+pub fn severity_colour(severity: Severity, ui: &egui::Ui) -> egui::Color32 {
+    match severity {
+        Severity::Error => egui::Color32::RED,
+        Severity::Warning => egui::Color32::YELLOW,
+        Severity::Note => ui.visuals().text_color(),
+    }
+}
+
+/// Seconds left to wait after an edit before assembling, None once it is time to assemble
+fn assemble_wait(edited: Option<f64>, now: f64) -> Option<f64> {
+    let since = now - edited?;
+    (since < ASSEMBLE_DELAY).then(|| ASSEMBLE_DELAY - since)
+}
+
+/// The line a line of the old text is on in the new text, None if it was deleted
+/// Lines above an edit stay where they are and lines below it move with the lines added or removed
+fn follow_edit(old: &str, new: &str, line: usize) -> Option<usize> {
+    let prefix = old
+        .bytes()
+        .zip(new.bytes())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut suffix = old
+        .bytes()
+        .rev()
+        .zip(new.bytes().rev())
+        .take(old.len().min(new.len()) - prefix)
+        .take_while(|(a, b)| a == b)
+        .count();
+    // Characters that only share some of their bytes count as edited
+    let mut prefix = prefix;
+    while !(old.is_char_boundary(prefix) && new.is_char_boundary(prefix)) {
+        prefix -= 1;
+    }
+    while !(old.is_char_boundary(old.len() - suffix) && new.is_char_boundary(new.len() - suffix)) {
+        suffix -= 1;
+    }
+    let removed = &old[prefix..old.len() - suffix];
+    let added = &new[prefix..new.len() - suffix];
+
+    let mut starts = vec![0];
+    starts.extend(old.match_indices('\n').map(|(i, _)| i + 1));
+    let start = *starts.get(line.checked_sub(1)?)?;
+    let end = starts.get(line).copied().unwrap_or(old.len());
+    if start < prefix {
+        return Some(line);
+    }
+    let newlines = |text: &str| text.matches('\n').count();
+    if start >= prefix + removed.len() {
+        return Some(line + newlines(added) - newlines(removed));
+    }
+    // The line starts in the text that was replaced, it is kept if some of it is left after
+    if end <= prefix + removed.len() {
+        return None;
+    }
+    Some(old[..prefix].matches('\n').count() + 1 + newlines(added))
+}
+
+/// Errors from the last time the code was assembled
+fn live_errors<'a>(
+    linked: &'a Option<Code>,
+    assembled: &'a Option<(Code, String)>,
+) -> &'a [AssemblingError] {
+    match (linked, assembled) {
+        (Some(linked), _) => &linked.errors,
+        (None, Some((code, _))) => &code.errors,
+        (None, None) => &[],
+    }
+}
+
+/// Byte ranges of the text to underline for each error, an error without a span underlines its line
+fn error_ranges(text: &str, errors: &[AssemblingError]) -> Vec<(Range<usize>, Severity)> {
+    let mut starts = vec![0];
+    starts.extend(text.match_indices('\n').map(|(i, _)| i + 1));
+    errors
+        .iter()
+        .filter_map(|error| {
+            let start = *starts.get(error.line.checked_sub(1)?)?;
+            let line = text[start..].lines().next()?;
+            let span = match &error.span {
+                Some(span) if span.is_empty() => span.start.saturating_sub(1)..span.start,
+                Some(span) => span.clone(),
+                None => line.len() - line.trim_start().len()..line.trim_end().len(),
+            };
+            line.get(span.clone()).filter(|text| !text.is_empty())?;
+            Some((start + span.start..start + span.end, error.severity))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_for_typing_to_pause() {
+        assert_eq!(assemble_wait(None, 3.0), None);
+        assert_eq!(assemble_wait(Some(3.0), 3.0), Some(ASSEMBLE_DELAY));
+        assert_eq!(assemble_wait(Some(3.0), 3.0 + ASSEMBLE_DELAY), None);
+
+        let ctx = egui::Context::default();
+        let mut editor = CodeEditor::default();
+        editor.code = "     add R1,R2\n".to_string();
+        editor.edited = Some(0.0);
+        editor.assemble_live(&ctx);
+        assert!(editor.assembled.is_none());
+
+        editor.edited = Some(-ASSEMBLE_DELAY);
+        editor.assemble_live(&ctx);
+        assert!(editor.edited.is_none());
+        let errors = live_errors(&editor.linked, &editor.assembled);
+        assert!(errors.iter().any(|error| error.is_error()));
+    }
+
+    #[test]
+    fn breakpoints_follow_their_lines() {
+        let old = "a\nb\nc\n";
+        let moved = |new: &str| -> Vec<Option<usize>> {
+            (1..=3).map(|line| follow_edit(old, new, line)).collect()
+        };
+        // Typing in a line
+        assert_eq!(moved("a\nbx\nc\n"), vec![Some(1), Some(2), Some(3)]);
+        assert_eq!(moved("a\nxb\nc\n"), vec![Some(1), Some(2), Some(3)]);
+        assert_eq!(moved("a\ny\nc\n"), vec![Some(1), Some(2), Some(3)]);
+        // Inserting lines above
+        assert_eq!(moved("a\nx\ny\nb\nc\n"), vec![Some(1), Some(4), Some(5)]);
+        assert_eq!(moved("a\n\nb\nc\n"), vec![Some(1), Some(3), Some(4)]);
+        assert_eq!(moved("x\na\nb\nc\n"), vec![Some(2), Some(3), Some(4)]);
+        // Deleting a line
+        assert_eq!(moved("a\nc\n"), vec![Some(1), None, Some(2)]);
+        // Joining two lines keeps the first
+        assert_eq!(moved("ab\nc\n"), vec![Some(1), Some(1), Some(2)]);
+
+        // Characters sharing their first or last bytes
+        let old = "a\n;é\nc\n";
+        assert_eq!(follow_edit(old, "a\n;èé\nc\n", 3), Some(3));
+        assert_eq!(follow_edit(old, "x\na\n;é\nc\n", 2), Some(3));
+        assert_eq!(follow_edit("a\n;é\n", "a\n;ñé\n", 2), Some(2));
+        assert_eq!(follow_edit("a\n;è\n", "a\n;é\n", 2), Some(2));
+    }
+
+    #[test]
+    fn underlines_error_spans() {
+        let text = "     add R1,R2,R3\nx    lea R1,y[R0]\n";
+        let code = Code::new(text.to_string());
+        let ranges = error_ranges(text, &code.errors);
+        let underlined: Vec<(&str, Severity)> = ranges
+            .iter()
+            .map(|(range, severity)| (&text[range.clone()], *severity))
+            .collect();
+        assert!(underlined.contains(&("y[R0]", Severity::Error)));
+        assert!(underlined.contains(&("x", Severity::Warning)));
+        assert!(ranges.iter().all(|(range, _)| range.start >= 18));
+    }
+}
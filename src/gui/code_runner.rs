@@ -1,12 +1,16 @@
 use crate::assembler::code::Code;
-use crate::gui::code_editor::CodeEditor;
+use crate::assembler::error::Severity;
+use crate::assembler::object;
+use crate::gui::code_editor::{severity_colour, CodeEditor};
 use crate::gui::syntax_highlighting_runner::{highlight, CodeTheme};
+use crate::interpreter::breakpoint::{self, Breakpoint};
 use crate::interpreter::interpreter;
+use crate::interpreter::memory::MemoryStep;
 use crate::interpreter::state::{RunningState, State};
 use egui::Galley;
 use log::{log, Level};
 use serde_diff::{Apply, Diff};
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::sync::Arc;
 
 #[derive(serde::Deserialize, serde::Serialize)]
@@ -14,10 +18,16 @@ use std::sync::Arc;
 pub struct CodeRunner {
     pub state: State,
     pub history: VecDeque<String>,
+    /// What each step in the history did to memory, which is left out of the diffs
+    pub memory_history: VecDeque<MemoryStep>,
     pub running: bool,
     pub code: Code,
     pub data_flow: bool,
     pub name: String,
+    /// The pc of an instruction to run without stopping at its breakpoint,
+    /// set when continuing from a breakpoint or a blocking read
+    #[serde(skip)]
+    resume_from: Option<u16>,
 }
 
 impl Default for CodeRunner {
@@ -28,16 +38,27 @@ impl Default for CodeRunner {
         Self {
             state,
             history,
+            memory_history: VecDeque::new(),
             running: false,
             code: Code::new("".to_string()),
             data_flow: false,
             name: uuid::Uuid::new_v4().to_string(),
+            resume_from: None,
         }
     }
 }
 
 impl CodeRunner {
-    pub fn gui(&mut self, ui: &mut egui::Ui, code: String) {
+    pub fn gui(
+        &mut self,
+        ui: &mut egui::Ui,
+        code: String,
+        breakpoints: &mut BTreeSet<usize>,
+        conditions: &mut BTreeMap<usize, Breakpoint>,
+    ) {
+        // Conditions are dropped with their breakpoint
+        conditions.retain(|line, _| breakpoints.contains(line));
+        let stops = self.breakpoint_addresses(breakpoints);
         ui.vertical(|v_ui| {
             v_ui.horizontal(|h_ui| {
                 let mut selected = self.state.state.clone();
@@ -46,14 +67,42 @@ impl CodeRunner {
                 // Allow reset at any time
                 let reset = h_ui.add(egui::Button::new("Reset"));
 
-                if self.state.state == RunningState::Error {
+                if self.state.state == RunningState::Waiting {
+                    // A blocking read is waiting on the console input
+                    let resume = h_ui.add_enabled(
+                        self.state.console.has_input(),
+                        egui::Button::new("Continue"),
+                    );
+                    if resume.clicked() {
+                        self.resume(&stops, conditions);
+                    }
+
+                    let step_back = h_ui.add(egui::Button::new("Step Back"));
+                    if step_back.clicked() {
+                        self.step_back();
+                    }
+                } else if self.state.state == RunningState::Error {
+                    // A fault happened while running, allow stepping back to before it
+                    if self.state.fault.is_some() {
+                        let step_back = h_ui.add(egui::Button::new("Step Back"));
+                        if step_back.clicked() {
+                            self.step_back();
+                        }
+                    }
                 } else {
                     // If we are haulted, we should not be able to step
                     if selected == RunningState::Step {
                         let step = h_ui.add_enabled(!self.running, egui::Button::new("Step"));
 
                         if step.clicked() {
-                            self.step()
+                            self.step(&stops, conditions)
+                        }
+                    }
+                    if self.state.state == RunningState::Breakpoint {
+                        if h_ui.add(egui::Button::new("Continue")).clicked() {
+                            self.running = true;
+                            self.resume_from = Some(self.state.pc.get_ui());
+                            self.state.state = RunningState::Running;
                         }
                     }
                     if selected == RunningState::Running {
@@ -64,7 +113,7 @@ impl CodeRunner {
                         }
 
                         if self.running {
-                            self.step();
+                            self.step(&stops, conditions);
                         }
                     }
 
@@ -83,6 +132,7 @@ impl CodeRunner {
                     self.reset(code);
                 }
             });
+            let problems = breakpoint::problems(conditions, &self.state.symbol_table);
             egui::ScrollArea::new([true, true])
                 .max_height(v_ui.available_height() - 30.0)
                 .show(v_ui, |ui| {
@@ -102,14 +152,86 @@ impl CodeRunner {
                                 },
                                 line,
                             )),
+                            breakpoints,
+                            &problems,
+                        );
+                        CodeEditor::make_editor(
+                            &mut self.code.get_code(),
+                            h_ui,
+                            false,
+                            &self.code.errors,
                         );
-                        CodeEditor::make_editor(&mut self.code.get_code(), h_ui, false);
                     });
                 });
+            self.make_console(v_ui);
+            self.make_breakpoints(v_ui, breakpoints, conditions);
             self.make_errors(v_ui);
         });
     }
 
+    /// Conditions and hit counts for each breakpoint, with any errors in the conditions
+    fn make_breakpoints(
+        &mut self,
+        ui: &mut egui::Ui,
+        breakpoints: &BTreeSet<usize>,
+        conditions: &mut BTreeMap<usize, Breakpoint>,
+    ) {
+        if breakpoints.is_empty() {
+            return;
+        }
+        egui::CollapsingHeader::new("Breakpoints").show(ui, |ui| {
+            for line in breakpoints {
+                let location = self.location(*line);
+                let hits = self.state.hits.get(line).copied().unwrap_or(0);
+                let breakpoint = conditions.entry(*line).or_default();
+                ui.horizontal(|ui| {
+                    ui.label(location);
+                    ui.add(
+                        egui::TextEdit::singleline(&mut breakpoint.condition)
+                            .font(egui::TextStyle::Monospace)
+                            .desired_width(140.0)
+                            .hint_text("Always stop"),
+                    );
+                    ui.label("after");
+                    ui.add(egui::DragValue::new(&mut breakpoint.after));
+                    ui.label(format!("hits, hit {hits} times"));
+                });
+                if let Some(error) = breakpoint.error(&self.state.symbol_table) {
+                    ui.colored_label(severity_colour(Severity::Error, ui), error);
+                }
+            }
+            ui.label("Conditions can use registers, pc, symbols and mem[address].");
+        });
+        ui.separator();
+    }
+
+    fn make_console(&mut self, ui: &mut egui::Ui) {
+        ui.vertical(|ui| {
+            ui.label("Console Output");
+            egui::ScrollArea::vertical()
+                .id_salt("console output")
+                .max_height(80.0)
+                .stick_to_bottom(true)
+                .show(ui, |ui| {
+                    ui.add(
+                        egui::TextEdit::multiline(&mut self.state.console.output.as_str())
+                            .font(egui::TextStyle::Monospace)
+                            .desired_rows(3)
+                            .desired_width(f32::INFINITY),
+                    );
+                });
+            ui.label("Console Input");
+            ui.add(
+                egui::TextEdit::multiline(&mut self.state.console.input)
+                    .font(egui::TextStyle::Monospace)
+                    .desired_rows(1)
+                    .desired_width(f32::INFINITY)
+                    .hint_text("Characters for trap reads"),
+            );
+            ui.separator();
+        });
+    }
+
     pub fn layouter(ui: &egui::Ui, string: &str, _wrap_width: f32) -> Arc<Galley> {
         let layout_job = highlight(ui.ctx(), &mut CodeTheme::default(), string);
         // layout_job.wrap.max_width = wrap_width; // no wrapping
@@ -118,15 +240,60 @@ impl CodeRunner {
 
     fn make_errors(&mut self, ui: &mut egui::Ui) {
         ui.vertical(|ui| {
+            if let Some(fault) = &self.state.fault {
+                match self.code.memory_to_code.get(&(fault.pc() as usize)) {
+                    Some(line) => ui.label(format!("Fault on {}", self.location(*line))),
+                    None => ui.label("Fault outside of the program"),
+                };
+                ui.label(fault.to_string());
+                ui.label("Step back to return to the instruction before the fault.");
+                ui.separator();
+            }
             for error in &self.code.errors {
-                ui.label(format!("Error on line {:?}", error.line));
+                let colour = severity_colour(error.severity, ui);
+                let heading = match (error.line, error.column(&self.code.code)) {
+                    (0, _) => format!("{} {} in the program", error.severity, error.code),
+                    (line, Some(column)) => format!(
+                        "{} {} on {}, column {column}",
+                        error.severity,
+                        error.code,
+                        self.location(line)
+                    ),
+                    (line, None) => {
+                        format!(
+                            "{} {} on {}",
+                            error.severity,
+                            error.code,
+                            self.location(line)
+                        )
+                    }
+                };
+                ui.colored_label(colour, heading);
+                if let Some(underline) = error.underline(&self.code.code) {
+                    ui.label(egui::RichText::new(underline).monospace());
+                }
                 ui.label(&error.message);
                 ui.label(&error.resolution);
+                for note in &error.notes {
+                    ui.label(format!(
+                        "Note on {}: {}",
+                        self.location(note.line),
+                        note.message
+                    ));
+                }
                 ui.separator();
             }
         });
     }
 
+    /// A line of the code, for linked code this is the line in the editor it came from
+    fn location(&self, line: usize) -> String {
+        match self.code.source(line) {
+            Some((editor, source)) if source > 0 => format!("line {source} of {editor}"),
+            _ => format!("line {line}"),
+        }
+    }
+
     fn make_state(&mut self, ui: &mut egui::Ui, mut selected: RunningState) -> RunningState {
         ui.add(egui::Label::new("State: "));
 
@@ -137,6 +304,19 @@ impl CodeRunner {
             RunningState::Error => {
                 ui.add(egui::Label::new("Error"));
             }
+            RunningState::Waiting => {
+                ui.add(egui::Label::new("Waiting for input"));
+            }
+            RunningState::Breakpoint => {
+                // Stopped before a line with a breakpoint, step or continue running from here
+                ui.add(egui::Label::new("Breakpoint"));
+                selected = RunningState::Step;
+            }
+            RunningState::Interrupted => {
+                // The next step runs the handler in the same mode as before the interrupt
+                ui.add(egui::Label::new("Interrupted"));
+                selected = self.mode();
+            }
             _ => {
                 egui::ComboBox::from_id_salt("Run Type")
                     .selected_text(format!("{:?}", selected))
@@ -151,38 +331,146 @@ impl CodeRunner {
     }
 
     pub fn reset(&mut self, code: String) {
-        self.code = Code::new(code);
+        // Linked code is only rebuilt by linking again, so resetting keeps it
+        if self.code.modules.is_empty() {
+            self.code = if self.code.object {
+                object::load(code)
+            } else {
+                Code::new(code)
+            };
+        }
         self.state = State::new(&self.code);
         self.history = VecDeque::new();
+        self.memory_history = VecDeque::new();
         self.state.verbose = true;
         self.running = false;
+        self.resume_from = None;
     }
 
-    fn step(&mut self) {
-        let base = self.state.clone();
+    /// Runs object code, later resets load it again rather than assembling it
+    pub fn reset_object(&mut self, object: String) {
+        self.code.object = true;
+        self.reset(object);
+    }
+
+    /// Runs a program linked from several modules
+    pub fn reset_linked(&mut self, linked: Code) {
+        self.code = linked;
+        self.reset(String::new());
+    }
+
+    /// Continue after a blocking read once there is input
+    fn resume(
+        &mut self,
+        stops: &HashMap<usize, usize>,
+        conditions: &mut BTreeMap<usize, Breakpoint>,
+    ) {
+        // The read is run again, it already stopped at any breakpoint on its line
+        self.resume_from = Some(self.state.pc.get_ui());
+        self.state.state = self.mode();
+        if !self.running {
+            self.step(stops, conditions);
+        }
+    }
+
+    /// The mode to continue in after an interrupt
+    fn mode(&self) -> RunningState {
+        if self.running {
+            RunningState::Running
+        } else {
+            RunningState::Step
+        }
+    }
+
+    /// First address of each line with a breakpoint, with the line
+    fn breakpoint_addresses(&self, breakpoints: &BTreeSet<usize>) -> HashMap<usize, usize> {
+        let mut first: HashMap<usize, usize> = HashMap::new();
+        for (&address, line) in &self.code.memory_to_code {
+            if breakpoints.contains(line) {
+                let start = first.entry(*line).or_insert(address);
+                *start = address.min(*start);
+            }
+        }
+        first
+            .into_iter()
+            .map(|(line, address)| (address, line))
+            .collect()
+    }
+
+    fn step(
+        &mut self,
+        stops: &HashMap<usize, usize>,
+        conditions: &mut BTreeMap<usize, Breakpoint>,
+    ) {
+        let base = self.state.snapshot();
+        let memory_step = self.state.memory.start_step();
         self.state.reset_altered();
 
-        match self.state.state {
-            RunningState::Running | RunningState::Step => interpreter::step(&mut self.state),
-            _ => {
-                log!(Level::Warn, "Unknown Sigma16 interpreter state");
+        // Only the instruction the program is stopped before runs without checking its breakpoint
+        let pc = self.state.pc.get_ui();
+        let resuming =
+            self.state.state == RunningState::Breakpoint || self.resume_from.take() == Some(pc);
+
+        if self.state.state == RunningState::Interrupted
+            || self.state.state == RunningState::Breakpoint
+        {
+            self.state.state = self.mode();
+        }
+
+        // Running stops before an instruction on a line with a breakpoint whose condition holds,
+        // hits are counted before the diff so stepping back undoes them
+        if self.running && !resuming && self.at_breakpoint(stops, conditions) {
+            self.running = false;
+        } else {
+            match self.state.state {
+                RunningState::Running | RunningState::Step => interpreter::step(&mut self.state),
+                _ => {
+                    log!(Level::Warn, "Unknown Sigma16 interpreter state");
+                }
+            }
+            // Stepping onto a line with a breakpoint shows that it was reached
+            if !self.running && self.state.state == RunningState::Step {
+                self.at_breakpoint(stops, conditions);
             }
         }
 
-        self.diff(base);
+        self.diff(base, memory_step);
 
-        if self.state.state == RunningState::Haulted {
+        // A breakpoint trap stops the program after it, like a breakpoint on the next instruction
+        if matches!(
+            self.state.state,
+            RunningState::Haulted | RunningState::Error | RunningState::Breakpoint
+        ) {
             self.running = false;
         }
 
         self.state.print_verbose();
     }
 
-    fn diff(&mut self, old: State) {
+    /// Whether the program is stopped before a breakpoint, the hit is counted if so
+    fn at_breakpoint(
+        &mut self,
+        stops: &HashMap<usize, usize>,
+        conditions: &mut BTreeMap<usize, Breakpoint>,
+    ) -> bool {
+        let pc = self.state.pc.get_ui() as usize;
+        let Some(line) = stops.get(&pc) else {
+            return false;
+        };
+        let stopped = breakpoint::hit(&mut self.state, *line, conditions.get_mut(line));
+        if stopped {
+            self.state.state = RunningState::Breakpoint;
+        }
+        stopped
+    }
+
+    fn diff(&mut self, old: State, memory_step: MemoryStep) {
         let diff = serde_json::to_string(&Diff::serializable(&self.state, &old));
         match diff {
             Ok(diff) => {
                 self.history.push_back(diff);
+                let memory_step = self.state.memory.end_step(memory_step);
+                self.memory_history.push_back(memory_step);
             }
             Err(error) => {
                 log!(
@@ -203,7 +491,15 @@ impl CodeRunner {
         if self.state.state != RunningState::Step {
             self.state.state = RunningState::Step;
         }
+        // The pc changes, so a breakpoint there is checked again when running
+        self.resume_from = None;
 
+        // History saved before memory was left out of the diffs has no memory steps
+        if self.memory_history.len() == self.history.len() {
+            if let Some(memory_step) = self.memory_history.pop_back() {
+                self.state.memory.undo(memory_step);
+            }
+        }
         let diff = self.history.pop_back().unwrap();
         let mut deserializer = serde_json::Deserializer::from_str(&diff);
 
@@ -216,3 +512,144 @@ impl CodeRunner {
         self.state.print_verbose();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM: &str = "     lea R1,1[R0]
+loop lea R1,1[R1]
+     jump loop[R0]
+     trap R0,R0,R0
+";
+
+    fn runner(breakpoints: &[usize]) -> (CodeRunner, HashMap<usize, usize>) {
+        let mut runner = CodeRunner::default();
+        runner.reset(PROGRAM.to_string());
+        let stops = runner.breakpoint_addresses(&breakpoints.iter().copied().collect());
+        (runner, stops)
+    }
+
+    #[test]
+    fn stops_at_the_first_address_of_each_line() {
+        let (_, stops) = runner(&[1, 2, 3, 4]);
+        assert_eq!(stops, HashMap::from([(0, 1), (2, 2), (4, 3), (6, 4)]));
+        let (_, stops) = runner(&[]);
+        assert!(stops.is_empty());
+    }
+
+    #[test]
+    fn running_stops_before_breakpoints() {
+        let mut conditions = BTreeMap::new();
+        let (mut runner, stops) = runner(&[1, 2]);
+        runner.running = true;
+        runner.state.state = RunningState::Running;
+
+        // A breakpoint on the first instruction stops before it runs
+        runner.step(&stops, &mut conditions);
+        assert_eq!(runner.state.state, RunningState::Breakpoint);
+        assert_eq!(runner.state.pc.get_ui(), 0);
+        assert!(!runner.running);
+
+        // Continuing runs the instruction stopped at, then stops at the next breakpoint
+        runner.running = true;
+        runner.resume_from = Some(0);
+        runner.state.state = RunningState::Running;
+        runner.step(&stops, &mut conditions);
+        assert_eq!(runner.state.pc.get_ui(), 2);
+        runner.step(&stops, &mut conditions);
+        assert_eq!(runner.state.state, RunningState::Breakpoint);
+        assert_eq!(runner.state.pc.get_ui(), 2);
+        assert_eq!(runner.state.hits[&2], 1);
+
+        // The loop comes back round to the same breakpoint
+        runner.running = true;
+        runner.resume_from = Some(2);
+        runner.state.state = RunningState::Running;
+        for _ in 0..3 {
+            runner.step(&stops, &mut conditions);
+        }
+        assert_eq!(runner.state.state, RunningState::Breakpoint);
+        assert_eq!(runner.state.hits[&2], 2);
+        assert_eq!(runner.state.r[1].get_ui(), 2);
+
+        // Stepping back takes the hit back off
+        runner.step_back();
+        assert_eq!(runner.state.hits[&2], 1);
+        assert_eq!(runner.history.len(), runner.memory_history.len());
+
+        // Continuing is forgotten after a reset, so the first breakpoint stops again
+        runner.resume_from = Some(0);
+        runner.reset(PROGRAM.to_string());
+        assert_eq!(runner.resume_from, None);
+        runner.running = true;
+        runner.state.state = RunningState::Running;
+        runner.step(&stops, &mut conditions);
+        assert_eq!(runner.state.state, RunningState::Breakpoint);
+        assert_eq!(runner.state.pc.get_ui(), 0);
+    }
+
+    #[test]
+    fn breakpoint_traps_stop_running() {
+        let mut conditions = BTreeMap::new();
+        let mut runner = CodeRunner::default();
+        runner.reset(
+            "     lea R1,4[R0]
+     trap R1,R0,R0
+     trap R0,R0,R0
+"
+            .to_string(),
+        );
+        let stops = HashMap::new();
+        runner.running = true;
+        runner.state.state = RunningState::Running;
+        runner.step(&stops, &mut conditions);
+        runner.step(&stops, &mut conditions);
+        assert_eq!(runner.state.state, RunningState::Breakpoint);
+        assert_eq!(runner.state.pc.get_ui(), 3);
+        assert!(!runner.running);
+    }
+
+    #[test]
+    fn stepping_shows_breakpoints_reached() {
+        let mut conditions = BTreeMap::new();
+        let (mut runner, stops) = runner(&[2]);
+        runner.step(&stops, &mut conditions);
+        assert_eq!(runner.state.state, RunningState::Breakpoint);
+        assert_eq!(runner.state.pc.get_ui(), 2);
+        runner.step(&stops, &mut conditions);
+        assert_eq!(runner.state.state, RunningState::Step);
+        assert_eq!(runner.state.pc.get_ui(), 4);
+    }
+
+    #[test]
+    fn stepping_back_undoes_memory() {
+        let mut conditions = BTreeMap::new();
+        let mut runner = CodeRunner::default();
+        runner.reset(
+            "     lea R1,5[R0]
+     store R1,x[R0]
+     load R2,x[R0]
+     trap R0,R0,R0
+x    data 2
+"
+            .to_string(),
+        );
+        let stops = HashMap::new();
+        for _ in 0..3 {
+            runner.step(&stops, &mut conditions);
+        }
+        assert_eq!(runner.state.memory[7], 5);
+        assert!(runner.state.memory.is_read(7));
+
+        runner.step_back();
+        assert_eq!(runner.state.memory[7], 5);
+        assert!(runner.state.memory.is_written(7));
+        assert!(!runner.state.memory.is_read(7));
+        runner.step_back();
+        assert_eq!(runner.state.memory[7], 2);
+        assert!(!runner.state.memory.is_written(7));
+        assert_eq!(runner.state.pc.get_ui(), 2);
+        assert!(runner.memory_history.len() == 1 && runner.history.len() == 1);
+    }
+}
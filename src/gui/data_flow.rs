@@ -1,4 +1,8 @@
-use crate::{gui::code_runner::CodeRunner, interpreter::memory::U16_MAX};
+use crate::{
+    assembler::symbol::{Symbol, SymbolKind},
+    gui::code_runner::CodeRunner,
+    interpreter::{control::CONTROL_REGISTERS, disassembler, memory::U16_MAX},
+};
 use egui_extras::{Column, TableBuilder};
 use log::{log, Level};
 
@@ -27,6 +31,22 @@ pub fn make(ui: &mut egui::Ui, runner: &mut CodeRunner) {
 fn make_registers(ui: &mut egui::Ui, runner: &mut CodeRunner) {
     ui.vertical(|ui| {
         ui.heading("Registers");
+        // The PC is only highlighted when an instruction jumps
+        ui.horizontal(|ui| {
+            ui.add(egui::Label::new("PC: "));
+            let value = format!("{:#06X}", runner.state.pc.get_ui())
+                .split_at(2)
+                .1
+                .to_string();
+
+            if runner.state.pc.get_altered() {
+                ui.label(egui::RichText::new(value).color(GREEN_TEXT));
+            } else if runner.state.pc.get_accessed() {
+                ui.label(egui::RichText::new(value).color(RED_TEXT));
+            } else {
+                ui.label(egui::RichText::new(value));
+            }
+        });
         for reg in &runner.code.used_registers {
             ui.horizontal(|ui| {
                 ui.add(egui::Label::new(format!("R{:?}: ", reg)));
@@ -46,25 +66,65 @@ fn make_registers(ui: &mut egui::Ui, runner: &mut CodeRunner) {
                 }
             });
         }
+        make_control_registers(ui, runner);
     });
 }
 
+fn make_control_registers(ui: &mut egui::Ui, runner: &mut CodeRunner) {
+    ui.heading("Control Registers");
+    // Control registers can be edited to set up interrupts without assembling the code again
+    for (i, name) in CONTROL_REGISTERS.iter().enumerate() {
+        ui.horizontal(|ui| {
+            ui.add(egui::Label::new(format!("{name}: ")));
+            let reg = &mut runner.state.ctl[i];
+            let mut value = reg.get_ui();
+
+            let color = if reg.get_altered() {
+                Some(GREEN_TEXT)
+            } else if reg.get_accessed() {
+                Some(RED_TEXT)
+            } else {
+                None
+            };
+            let drag = egui::DragValue::new(&mut value)
+                .hexadecimal(4, false, true)
+                .speed(0.0);
+            let response = ui
+                .scope(|ui| {
+                    if let Some(color) = color {
+                        ui.visuals_mut().override_text_color = Some(color);
+                    }
+                    ui.add(drag)
+                })
+                .inner;
+
+            if response.changed() {
+                reg.set(value);
+            }
+        });
+    }
+}
+
 fn make_memory(ui: &mut egui::Ui, runner: &mut CodeRunner) {
     let mut last_line = U16_MAX as usize + 1;
     let mut scroll_row: usize = 0;
+    // Memory is disassembled as it is now, so code the program writes is shown too
+    let labels = disassembler::labels(&runner.code.addresses());
+    let mut next_instruction = 0;
     ui.vertical(|ui| {
-        ui.set_min_width(160.0);
+        ui.set_min_width(320.0);
         ui.heading("Memory");
         TableBuilder::new(ui)
             .id_salt(format!("memory-{:?}", runner.name))
             //.cell_layout(make_table_layout())
             .striped(true)
-            .columns(Column::remainder(), 3)
+            .columns(Column::remainder(), 4)
             .resizable(false)
             //.scroll_to_row(runner.state.pc.get() as usize, Some(egui::Align::TOP))
             .header(15.0, |mut header| {
                 header.col(|ui| {
-                    ui.label("Line");
+                    // Object code has no source, so words come from lines of records
+                    ui.label(if runner.code.object { "Record" } else { "Line" });
                 });
                 header.col(|ui| {
                     ui.label("Location");
@@ -72,9 +132,13 @@ fn make_memory(ui: &mut egui::Ui, runner: &mut CodeRunner) {
                 header.col(|ui| {
                     ui.label("Content");
                 });
+                header.col(|ui| {
+                    ui.label("Disassembly");
+                });
             })
             .body(|mut body| {
-                for mem in runner.state.memory.get_used().clone().to_owned() {
+                let used: Vec<usize> = runner.state.memory.used().collect();
+                for mem in used {
                     body.row(15.0, |mut row| {
                         row.col(|ui| {
                             let line = runner.code.memory_to_code.get(&mem);
@@ -107,12 +171,23 @@ fn make_memory(ui: &mut egui::Ui, runner: &mut CodeRunner) {
                                 .split_at(2)
                                 .1
                                 .to_string();
-                            if runner.state.memory.get_altered_i().contains(&mem) {
+                            if runner.state.memory.is_written(mem) {
                                 ui.label(egui::RichText::new(value).color(GREEN_TEXT));
+                            } else if runner.state.memory.is_read(mem) {
+                                ui.label(egui::RichText::new(value).color(RED_TEXT));
                             } else {
                                 ui.label(egui::RichText::new(value));
                             }
                         });
+                        row.col(|ui| {
+                            // The second word of an instruction is left blank
+                            if mem >= next_instruction {
+                                let (text, words) =
+                                    disassembler::instruction(&runner.state.memory, mem, &labels);
+                                next_instruction = mem + words;
+                                ui.label(egui::RichText::new(text).monospace());
+                            }
+                        });
                     });
                 }
             });
@@ -135,23 +210,27 @@ fn make_symbol_table(ui: &mut egui::Ui, runner: &mut CodeRunner) {
                     ui.label("Line");
                 });
                 header.col(|ui| {
-                    ui.label("Location");
+                    ui.label("Value");
                 });
             })
             .body(|mut body| {
-                for (symbol, location) in &runner.code.symbol_table {
+                let mut symbols: Vec<(&String, &Symbol)> =
+                    runner.code.symbol_table.iter().collect();
+                symbols.sort_by_key(|(name, symbol)| (symbol.value, *name));
+                for (name, symbol) in symbols {
                     body.row(15.0, |mut row| {
                         row.col(|ui| {
-                            ui.label(symbol);
+                            ui.label(name);
                         });
                         row.col(|ui| {
-                            ui.label(format!(
-                                "{:?}",
-                                runner.code.memory_to_code.get(location).unwrap()
-                            ));
+                            ui.label(format!("{:?}", symbol.line));
                         });
                         row.col(|ui| {
-                            let value = format!("{:#06X}", location).split_at(2).1.to_string();
+                            let value = match &symbol.kind {
+                                SymbolKind::Relocatable => format!("{:04X}", symbol.value),
+                                SymbolKind::Constant => format!("{:04X} (equ)", symbol.value),
+                                SymbolKind::Imported { module, name } => format!("{module}.{name}"),
+                            };
                             ui.label(value);
                         });
                     });
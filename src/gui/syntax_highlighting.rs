@@ -1,6 +1,7 @@
 use egui::text::LayoutJob;
 use log::{log, Level};
 use regex::Regex;
+use std::ops::Range;
 
 pub fn highlight(ctx: &egui::Context, theme: &CodeTheme, code: &str, language: &str) -> LayoutJob {
     impl egui::util::cache::ComputerMut<(&CodeTheme, &str, &str), LayoutJob> for Highlighter {
@@ -21,6 +22,39 @@ pub fn highlight(ctx: &egui::Context, theme: &CodeTheme, code: &str, language: &
     })
 }
 
+/// Underlines byte ranges of the text, splitting the sections the ranges start and end in
+pub fn underline(job: &mut LayoutJob, ranges: &[(Range<usize>, egui::Color32)]) {
+    for (range, colour) in ranges {
+        let mut sections = Vec::with_capacity(job.sections.len() + 2);
+        for section in job.sections.drain(..) {
+            let whole = section.byte_range.clone();
+            let start = range.start.clamp(whole.start, whole.end);
+            let end = range.end.clamp(whole.start, whole.end);
+            if start == end {
+                sections.push(section);
+                continue;
+            }
+            let parts = [
+                (whole.start..start, false),
+                (start..end, true),
+                (end..whole.end, false),
+            ];
+            for (part, underlined) in parts {
+                if part.is_empty() {
+                    continue;
+                }
+                let mut split = section.clone();
+                split.byte_range = part;
+                if underlined {
+                    split.format.underline = egui::Stroke::new(1.5, *colour);
+                }
+                sections.push(split);
+            }
+        }
+        job.sections = sections;
+    }
+}
+
 #[derive(Clone, Copy, PartialEq)]
 // #[derive(serde::Deserialize, serde::Serialize)]
 #[derive(enum_map::Enum)]
@@ -110,7 +144,7 @@ impl Highlighter {
                     .find(|c: char| !c.is_ascii_alphanumeric())
                     .map_or_else(|| text.len(), |i| i + 1);
                 let word = &text[..end];
-                let tt = if is_keyword(word) {
+                let tt = if is_keyword(&word.to_ascii_lowercase()) {
                     TokenType::Keyword
                 } else {
                     TokenType::Literal
@@ -174,7 +208,32 @@ fn is_keyword(word: &str) -> bool {
         | "jumpge"
         | "jumpgt"
         | "testset"
+        // EXP Instructions
+        | "resume"
+        | "save"
+        | "restore"
+        | "push"
+        | "pop"
+        | "top"
+        | "shiftl"
+        | "shiftr"
+        | "logicw"
+        | "logicf"
+        | "extract"
+        | "getctl"
+        | "putctl"
+        | "brc0"
+        | "brc1"
+        | "dsptch"
+        // Pseudo Instructions
+        | "nop"
+        | "mov"
+        | "clear"
+        | "inc"
+        | "dec"
         // Non-Instructions
         | "data"
+        | "macro"
+        | "endm"
     )
 }
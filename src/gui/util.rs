@@ -8,4 +8,38 @@ pub fn format_code(source: Vec<u16>) -> String {
         formatted.push_str(&format!("{:#06x}: {:#06x}\n", i, item));
     }
     formatted
-}
\ No newline at end of file
+}
+
+/// Saves text to a file through the browser's downloads
+pub fn download(file_name: &str, text: &str) {
+    use web_sys::wasm_bindgen::closure::Closure;
+    use web_sys::wasm_bindgen::{JsCast, JsValue};
+
+    let Some(window) = web_sys::window() else {
+        return;
+    };
+    let Some(document) = window.document() else {
+        return;
+    };
+    let parts = web_sys::js_sys::Array::of1(&JsValue::from_str(text));
+    let options = web_sys::BlobPropertyBag::new();
+    options.set_type("text/plain");
+    let Ok(blob) = web_sys::Blob::new_with_str_sequence_and_options(&parts, &options) else {
+        return;
+    };
+    let Ok(url) = web_sys::Url::create_object_url_with_blob(&blob) else {
+        return;
+    };
+    if let Ok(element) = document.create_element("a") {
+        if let Ok(anchor) = element.dyn_into::<web_sys::HtmlAnchorElement>() {
+            anchor.set_href(&url);
+            anchor.set_download(file_name);
+            anchor.click();
+        }
+    }
+    // Some browsers only start the download after click returns, so the url is revoked later
+    let revoke = Closure::once_into_js(move || {
+        let _ = web_sys::Url::revoke_object_url(&url);
+    });
+    let _ = window.set_timeout_with_callback_and_timeout_and_arguments_0(revoke.unchecked_ref(), 1000);
+}
@@ -0,0 +1,450 @@
+use crate::interpreter::state::State;
+use std::collections::{BTreeMap, HashMap};
+
+/// A condition on a line with a breakpoint, the program only stops there when it holds
+#[derive(serde::Serialize, serde::Deserialize, Clone, Default, Debug, PartialEq)]
+#[serde(default)]
+pub struct Breakpoint {
+    /// An expression such as `R1 == 5` or `mem[x+3] > 100`, empty to always stop
+    pub condition: String,
+    /// Number of times the condition holds before the program stops
+    pub after: usize,
+    /// The condition parsed, with the text it was parsed from
+    #[serde(skip)]
+    parsed: Option<(String, Result<Option<Expression>, String>)>,
+}
+
+impl Breakpoint {
+    pub fn new(condition: &str, after: usize) -> Breakpoint {
+        let mut breakpoint = Breakpoint {
+            condition: condition.to_string(),
+            after,
+            parsed: None,
+        };
+        breakpoint.update();
+        breakpoint
+    }
+
+    /// Parses the condition again if it has been edited since it was last parsed
+    pub fn update(&mut self) {
+        if self
+            .parsed
+            .as_ref()
+            .is_some_and(|(text, _)| *text == self.condition)
+        {
+            return;
+        }
+        self.parsed = Some((self.condition.clone(), parse(&self.condition)));
+    }
+
+    /// The parsed condition, None when it is empty
+    pub fn expression(&mut self) -> Result<Option<&Expression>, &str> {
+        self.update();
+        match &self.parsed {
+            Some((_, Ok(expression))) => Ok(expression.as_ref()),
+            Some((_, Err(error))) => Err(error),
+            None => Ok(None),
+        }
+    }
+
+    /// Why the condition can not be evaluated, if it can not
+    pub fn error(&mut self, symbols: &HashMap<String, usize>) -> Option<String> {
+        match self.expression() {
+            Ok(Some(expression)) => expression.unknown_symbol(symbols),
+            Ok(None) => None,
+            Err(error) => Some(error.to_string()),
+        }
+    }
+}
+
+/// Lines with a breakpoint whose condition can not be evaluated, with the reason
+pub fn problems(
+    conditions: &mut BTreeMap<usize, Breakpoint>,
+    symbols: &HashMap<String, usize>,
+) -> BTreeMap<usize, String> {
+    conditions
+        .iter_mut()
+        .filter_map(|(line, breakpoint)| Some((*line, breakpoint.error(symbols)?)))
+        .collect()
+}
+
+/// Counts a hit on a line with a breakpoint and whether the program stops there
+/// Hits are kept in the state so stepping back takes them back off
+/// A condition that can not be evaluated always stops, its error is shown with the breakpoint
+pub fn hit(state: &mut State, line: usize, breakpoint: Option<&mut Breakpoint>) -> bool {
+    let after = match breakpoint {
+        Some(breakpoint) => {
+            match breakpoint.expression() {
+                Ok(Some(condition)) => match condition.evaluate(state) {
+                    Ok(0) => return false,
+                    Ok(_) => {}
+                    Err(_) => return true,
+                },
+                Ok(None) => {}
+                Err(_) => return true,
+            }
+            breakpoint.after
+        }
+        None => 0,
+    };
+    let hits = state.hits.entry(line).or_insert(0);
+    *hits += 1;
+    *hits > after
+}
+
+fn parse(condition: &str) -> Result<Option<Expression>, String> {
+    if condition.trim().is_empty() {
+        return Ok(None);
+    }
+    let mut parser = Parser {
+        tokens: tokenise(condition)?,
+        next: 0,
+    };
+    let expression = parser.or()?;
+    match parser.peek() {
+        None => Ok(Some(expression)),
+        Some((token, column)) => Err(format!("Unexpected {token} at column {column}")),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    Number(i64),
+    Register(usize),
+    Pc,
+    /// The address of a label, with the column it is at
+    Symbol(String, usize),
+    /// The word at an address
+    Memory(Box<Expression>),
+    Negate(Box<Expression>),
+    Not(Box<Expression>),
+    Binary(Box<Expression>, Operator, Box<Expression>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    Add,
+    Sub,
+    Mul,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    And,
+    Or,
+}
+
+impl Expression {
+    /// Words are two's complement, comparisons are 1 when true and 0 when false
+    /// Equality is modulo 2^16, so 40000, -25536 and $9c40 are all the same word
+    /// Registers and memory are read without marking them as accessed
+    pub fn evaluate(&self, state: &State) -> Result<i64, String> {
+        let bool = |value: bool| value as i64;
+        Ok(match self {
+            Expression::Number(value) => *value,
+            Expression::Register(register) => state.r[*register].get_ui() as i16 as i64,
+            Expression::Pc => state.pc.get_ui() as i64,
+            Expression::Symbol(name, column) => match state.symbol_table.get(name) {
+                Some(address) => *address as i64,
+                None => return Err(format!("Unknown symbol {name} at column {column}")),
+            },
+            Expression::Memory(address) => {
+                state.memory[address.evaluate(state)? as u16 as usize] as i16 as i64
+            }
+            Expression::Negate(value) => value.evaluate(state)?.wrapping_neg(),
+            Expression::Not(value) => bool(value.evaluate(state)? == 0),
+            Expression::Binary(left, operator, right) => {
+                let left = left.evaluate(state)?;
+                // Logical operators only evaluate the right side when they need it
+                match operator {
+                    Operator::And if left == 0 => return Ok(0),
+                    Operator::Or if left != 0 => return Ok(1),
+                    _ => {}
+                }
+                let right = right.evaluate(state)?;
+                match operator {
+                    Operator::Add => left.wrapping_add(right),
+                    Operator::Sub => left.wrapping_sub(right),
+                    Operator::Mul => left.wrapping_mul(right),
+                    Operator::Equal => bool(left as u16 == right as u16),
+                    Operator::NotEqual => bool(left as u16 != right as u16),
+                    Operator::Less => bool(left < right),
+                    Operator::LessEqual => bool(left <= right),
+                    Operator::Greater => bool(left > right),
+                    Operator::GreaterEqual => bool(left >= right),
+                    Operator::And | Operator::Or => bool(right != 0),
+                }
+            }
+        })
+    }
+
+    /// The first symbol used that is not in the symbol table
+    fn unknown_symbol(&self, symbols: &HashMap<String, usize>) -> Option<String> {
+        match self {
+            Expression::Symbol(name, column) if !symbols.contains_key(name) => {
+                Some(format!("Unknown symbol {name} at column {column}"))
+            }
+            Expression::Memory(value) | Expression::Negate(value) | Expression::Not(value) => {
+                value.unknown_symbol(symbols)
+            }
+            Expression::Binary(left, _, right) => left
+                .unknown_symbol(symbols)
+                .or_else(|| right.unknown_symbol(symbols)),
+            _ => None,
+        }
+    }
+}
+
+/// Tokens with the column they start at
+fn tokenise(text: &str) -> Result<Vec<(String, usize)>, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let start = i;
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        if c.is_ascii_alphanumeric() || c == '_' || c == '$' || c == '.' {
+            i += 1;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || "_.".contains(chars[i])) {
+                i += 1;
+            }
+        } else {
+            let pair: String = chars[i..(i + 2).min(chars.len())].iter().collect();
+            i += match pair.as_str() {
+                "==" | "!=" | "<=" | ">=" | "&&" | "||" => 2,
+                _ if "+-*<>!()[]".contains(c) => 1,
+                _ => return Err(format!("Unexpected '{c}' at column {}", start + 1)),
+            };
+        }
+        tokens.push((chars[start..i].iter().collect(), start + 1));
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(String, usize)>,
+    next: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<(&str, usize)> {
+        self.tokens
+            .get(self.next)
+            .map(|(token, column)| (token.as_str(), *column))
+    }
+
+    /// Takes the next token if it is one of the operators
+    fn operator(&mut self, operators: &[(&str, Operator)]) -> Option<Operator> {
+        let (token, _) = self.peek()?;
+        let (_, operator) = operators.iter().find(|(text, _)| *text == token)?;
+        self.next += 1;
+        Some(*operator)
+    }
+
+    /// Each level of precedence is parsed by a function calling the one for the level above
+    fn binary(
+        &mut self,
+        operators: &[(&str, Operator)],
+        operand: fn(&mut Self) -> Result<Expression, String>,
+    ) -> Result<Expression, String> {
+        let mut left = operand(self)?;
+        while let Some(operator) = self.operator(operators) {
+            let right = operand(self)?;
+            left = Expression::Binary(Box::new(left), operator, Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn or(&mut self) -> Result<Expression, String> {
+        self.binary(&[("||", Operator::Or)], Self::and)
+    }
+
+    fn and(&mut self) -> Result<Expression, String> {
+        self.binary(&[("&&", Operator::And)], Self::comparison)
+    }
+
+    fn comparison(&mut self) -> Result<Expression, String> {
+        self.binary(
+            &[
+                ("==", Operator::Equal),
+                ("!=", Operator::NotEqual),
+                ("<=", Operator::LessEqual),
+                (">=", Operator::GreaterEqual),
+                ("<", Operator::Less),
+                (">", Operator::Greater),
+            ],
+            Self::sum,
+        )
+    }
+
+    fn sum(&mut self) -> Result<Expression, String> {
+        self.binary(&[("+", Operator::Add), ("-", Operator::Sub)], Self::product)
+    }
+
+    fn product(&mut self) -> Result<Expression, String> {
+        self.binary(&[("*", Operator::Mul)], Self::unary)
+    }
+
+    fn unary(&mut self) -> Result<Expression, String> {
+        match self.peek() {
+            Some(("-", _)) => {
+                self.next += 1;
+                Ok(Expression::Negate(Box::new(self.unary()?)))
+            }
+            Some(("!", _)) => {
+                self.next += 1;
+                Ok(Expression::Not(Box::new(self.unary()?)))
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        match self.peek() {
+            Some((token, _)) if token == expected => {
+                self.next += 1;
+                Ok(())
+            }
+            Some((token, column)) => Err(format!(
+                "Expected {expected} but found {token} at column {column}"
+            )),
+            None => Err(format!("Expected {expected} at the end of the condition")),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expression, String> {
+        let (token, column) = match self.peek() {
+            Some((token, column)) => (token.to_string(), column),
+            None => return Err("The condition ends too early".to_string()),
+        };
+        self.next += 1;
+        if token == "(" {
+            let expression = self.or()?;
+            self.expect(")")?;
+            return Ok(expression);
+        }
+        if token.eq_ignore_ascii_case("mem") {
+            self.expect("[")?;
+            let address = self.or()?;
+            self.expect("]")?;
+            return Ok(Expression::Memory(Box::new(address)));
+        }
+        if token.eq_ignore_ascii_case("pc") {
+            return Ok(Expression::Pc);
+        }
+        if let Some(hex) = token.strip_prefix('$') {
+            return match u16::from_str_radix(hex, 16) {
+                Ok(value) => Ok(Expression::Number(value as i16 as i64)),
+                Err(_) => Err(format!("{token} at column {column} is not a hex word")),
+            };
+        }
+        // Numbers are words like register values, so 40000 is read as -25536
+        if token.starts_with(|c: char| c.is_ascii_digit()) {
+            return match token.parse::<i64>() {
+                Ok(value) if value <= u16::MAX as i64 => {
+                    Ok(Expression::Number(value as u16 as i16 as i64))
+                }
+                Ok(_) => Err(format!("{token} at column {column} does not fit in a word")),
+                Err(_) => Err(format!("{token} at column {column} is not a number")),
+            };
+        }
+        if let Some(register) = token
+            .strip_prefix(['R', 'r'])
+            .and_then(|number| number.parse::<usize>().ok())
+        {
+            return match register {
+                0..=15 => Ok(Expression::Register(register)),
+                _ => Err(format!("There is no register {token}, at column {column}")),
+            };
+        }
+        if token.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+            return Ok(Expression::Symbol(token, column));
+        }
+        Err(format!("Unexpected {token} at column {column}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::code::Code;
+
+    fn condition(text: &str, state: &State) -> Result<i64, String> {
+        let mut breakpoint = Breakpoint::new(text, 0);
+        if let Some(error) = breakpoint.error(&state.symbol_table) {
+            return Err(error);
+        }
+        breakpoint.expression()?.unwrap().evaluate(state)
+    }
+
+    #[test]
+    fn evaluates_conditions() {
+        let code = Code::new("x    data 1\n     data 2\n     data 3\n     data 200\n".to_string());
+        let mut state = State::new(&code);
+        state.r[1].set(5);
+        state.r[2].set(0xffff);
+
+        assert_eq!(condition("R1 == 5", &state), Ok(1));
+        assert_eq!(condition("r1 != 5 || R2 < 0", &state), Ok(1));
+        assert_eq!(condition("mem[x+3] > 100", &state), Ok(1));
+        assert_eq!(condition("mem[x] * 2 + -R2 >= $0003", &state), Ok(1));
+        assert_eq!(condition("!(R1 <= 4) && pc == 0", &state), Ok(1));
+        assert_eq!(condition("R2 == $ffff", &state), Ok(1));
+        assert_eq!(condition("R1 > 5", &state), Ok(0));
+
+        // Numbers are compared as words
+        state.r[3].set(40000);
+        state.memory[2] = 50000;
+        assert_eq!(condition("R3 == 40000", &state), Ok(1));
+        assert_eq!(condition("R3 == $9c40 && R3 == -25536", &state), Ok(1));
+        assert_eq!(condition("mem[x+2] == 50000", &state), Ok(1));
+        assert_eq!(condition("R2 + 1 == 0 && R2 == 65535", &state), Ok(1));
+        assert!(condition("R1 == 65536", &state).is_err());
+
+        assert!(condition("R16 == 1", &state).is_err());
+        assert!(condition("y == 1", &state).is_err());
+        assert!(condition("mem[x > 1", &state).is_err());
+        assert!(condition("R1 == ", &state).is_err());
+        assert!(condition("R1 = 5", &state).is_err());
+        assert!(condition("R1 5", &state).is_err());
+    }
+
+    #[test]
+    fn stops_after_hits() {
+        let mut state = State::new(&Code::new("     data 0\n".to_string()));
+        let mut breakpoint = Breakpoint::new("R1 == 1", 2);
+        assert!(!hit(&mut state, 1, Some(&mut breakpoint)));
+        state.r[1].set(1);
+        assert!(!hit(&mut state, 1, Some(&mut breakpoint)));
+        assert!(!hit(&mut state, 1, Some(&mut breakpoint)));
+        assert!(hit(&mut state, 1, Some(&mut breakpoint)));
+        assert_eq!(state.hits[&1], 3);
+
+        assert!(hit(&mut state, 2, None));
+        let mut broken = Breakpoint::new("R1 ==", 5);
+        assert!(hit(&mut state, 3, Some(&mut broken)));
+        let mut unknown = Breakpoint::new("y == 1", 5);
+        assert!(hit(&mut state, 4, Some(&mut unknown)));
+    }
+
+    #[test]
+    fn parses_once_per_edit() {
+        let mut breakpoint = Breakpoint::new("R1 ==", 0);
+        let mut conditions = BTreeMap::from([(3, breakpoint.clone())]);
+        let problems = problems(&mut conditions, &HashMap::new());
+        assert_eq!(problems.keys().collect::<Vec<_>>(), vec![&3]);
+
+        breakpoint.condition = "R1 == x".to_string();
+        assert!(breakpoint.error(&HashMap::new()).is_some());
+        assert_eq!(breakpoint.parsed.as_ref().unwrap().0, "R1 == x");
+        assert!(breakpoint
+            .error(&HashMap::from([("x".to_string(), 4)]))
+            .is_none());
+    }
+}
@@ -0,0 +1,57 @@
+#[derive(serde::Serialize, serde::Deserialize, serde_diff::SerdeDiff, Clone, Default)]
+/// A console device for the trap instruction
+/// Input is a queue of characters typed by the user, output is everything the program has written
+pub struct Console {
+    pub input: String,
+    pub output: String,
+}
+
+impl Console {
+    /// Takes up to count characters from the front of the input queue, one word per character
+    pub fn read(&mut self, count: usize) -> Vec<u16> {
+        let taken: String = self.input.chars().take(count).collect();
+        self.input.drain(..taken.len());
+        taken
+            .chars()
+            .map(|c| u16::try_from(c as u32).unwrap_or('?' as u16))
+            .collect()
+    }
+
+    /// Appends one character per word to the output
+    pub fn write(&mut self, words: &[u16]) {
+        for word in words {
+            self.output
+                .push(char::from_u32(*word as u32).unwrap_or(char::REPLACEMENT_CHARACTER));
+        }
+    }
+
+    pub fn has_input(&self) -> bool {
+        !self.input.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_from_the_front_of_the_queue() {
+        let mut console = Console {
+            input: "hé!".to_string(),
+            output: String::new(),
+        };
+        assert_eq!(console.read(2), vec!['h' as u16, 'é' as u16]);
+        assert_eq!(console.input, "!");
+        assert_eq!(console.read(5), vec!['!' as u16]);
+        assert!(!console.has_input());
+        assert!(console.read(1).is_empty());
+    }
+
+    #[test]
+    fn writes_one_character_per_word() {
+        let mut console = Console::default();
+        console.write(&['o' as u16, 'k' as u16]);
+        console.write(&[0xd800, '\n' as u16]);
+        assert_eq!(console.output, "ok\u{fffd}\n");
+    }
+}
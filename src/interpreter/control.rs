@@ -0,0 +1,32 @@
+// Control register indices
+pub const STATUS: usize = 0;
+pub const MASK: usize = 1;
+pub const REQ: usize = 2;
+pub const ISTAT: usize = 3;
+pub const IPC: usize = 4;
+pub const VECT: usize = 5;
+pub const TIMER: usize = 6;
+
+/// Names of the control registers as written in getctl and putctl instructions
+pub const CONTROL_REGISTERS: [&str; 7] = ["status", "mask", "req", "istat", "ipc", "vect", "timer"];
+
+// Status bit masks
+pub const STATUS_IE: u16 = 0b1; // Interrupts enabled
+
+// Interrupt bit masks, used in the mask and req control registers
+// The bit number is the offset of the handler address in the interrupt vector
+pub const INT_TIMER: u16 = 0b1;
+pub const INT_TRAP: u16 = 0b10;
+pub const INT_OVERFLOW: u16 = 0b100;
+pub const INT_ZDIV: u16 = 0b1000;
+pub const INT_INVALID: u16 = 0b1_0000;
+
+/// Names of the interrupts in order of their bit number, which is also their priority
+pub const INTERRUPTS: [&str; 5] = ["timer", "trap", "overflow", "zdiv", "invalid"];
+
+/// Find the index of a control register from its name, ignoring case
+pub fn control_register(name: &str) -> Option<usize> {
+    CONTROL_REGISTERS
+        .iter()
+        .position(|reg| reg.eq_ignore_ascii_case(name))
+}
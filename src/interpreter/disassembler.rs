@@ -0,0 +1,184 @@
+use crate::interpreter::control::CONTROL_REGISTERS;
+use crate::interpreter::memory::{Memory, MEMORY_SIZE};
+use crate::interpreter::opcodes::{decode, length, OpCodes};
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// Labels to show in place of addresses, the first name in order is used when
+/// several labels are at the same address
+pub fn labels(addresses: &HashMap<String, usize>) -> HashMap<usize, String> {
+    let mut labels: HashMap<usize, String> = HashMap::new();
+    for (name, address) in addresses {
+        let label = labels.entry(*address).or_insert_with(|| name.clone());
+        if name < label {
+            *label = name.clone();
+        }
+    }
+    labels
+}
+
+/// The instructions in a range of memory, with the address each one starts at
+/// Memory is read without marking it as accessed, so this can be used while a program runs
+pub fn disassemble(
+    memory: &Memory,
+    range: Range<usize>,
+    labels: &HashMap<usize, String>,
+) -> Vec<(usize, String)> {
+    let mut instructions = Vec::new();
+    let mut address = range.start;
+    while address < range.end.min(MEMORY_SIZE) {
+        let (text, words) = instruction(memory, address, labels);
+        instructions.push((address, text));
+        address += words;
+    }
+    instructions
+}
+
+/// The instruction at an address in assembly language and the number of words it takes
+/// Words that are not instructions are shown as data
+pub fn instruction(
+    memory: &Memory,
+    address: usize,
+    labels: &HashMap<usize, String>,
+) -> (String, usize) {
+    let word = memory[address];
+    let words = length(word);
+    let word2 = memory[(address + 1) % MEMORY_SIZE];
+    let text = match decode(word, word2) {
+        Some(op) => text(op, address, labels),
+        None => return (format!("data ${word:04x}"), 1),
+    };
+    (text, words)
+}
+
+fn text(op: OpCodes, address: usize, labels: &HashMap<usize, String>) -> String {
+    let rx = |mnemonic: &str, rd: u8, ra: u8, disp: u16| {
+        format!("{mnemonic} R{rd},{}[R{ra}]", value(disp, labels))
+    };
+    match op {
+        OpCodes::Add(d, a, b) => format!("add R{d},R{a},R{b}"),
+        OpCodes::Sub(d, a, b) => format!("sub R{d},R{a},R{b}"),
+        OpCodes::Mul(d, a, b) => format!("mul R{d},R{a},R{b}"),
+        OpCodes::Div(d, a, b) => format!("div R{d},R{a},R{b}"),
+        OpCodes::Addc(d, a, b) => format!("addc R{d},R{a},R{b}"),
+        OpCodes::Muln(d, a, b) => format!("muln R{d},R{a},R{b}"),
+        OpCodes::Divn(d, a, b) => format!("divn R{d},R{a},R{b}"),
+        OpCodes::Rrr1(d, a, b) => format!("rrr1 R{d},R{a},R{b}"),
+        OpCodes::Rrr2(d, a, b) => format!("rrr2 R{d},R{a},R{b}"),
+        OpCodes::Rrr3(d, a, b) => format!("rrr3 R{d},R{a},R{b}"),
+        OpCodes::Rrr4(d, a, b) => format!("rrr4 R{d},R{a},R{b}"),
+        OpCodes::Trap(d, a, b) => format!("trap R{d},R{a},R{b}"),
+        OpCodes::Cmp(a, b) => format!("cmp R{a},R{b}"),
+        OpCodes::Lea(d, a, disp) => rx("lea", d, a, disp),
+        OpCodes::Load(d, a, disp) => rx("load", d, a, disp),
+        OpCodes::Store(d, a, disp) => rx("store", d, a, disp),
+        OpCodes::Jal(d, a, disp) => rx("jal", d, a, disp),
+        OpCodes::Jumpz(d, a, disp) => rx("jumpz", d, a, disp),
+        OpCodes::Jumpnz(d, a, disp) => rx("jumpnz", d, a, disp),
+        OpCodes::Testset(d, a, disp) => rx("testset", d, a, disp),
+        OpCodes::Jump(_, a, disp) => format!("jump {}[R{a}]", value(disp, labels)),
+        // The condition code bits tested by the named jumps
+        OpCodes::Jumpc0(bit, a, disp) | OpCodes::Jumpc1(bit, a, disp) => {
+            let jumpc1 = matches!(op, OpCodes::Jumpc1(..));
+            let target = format!("{}[R{a}]", value(disp, labels));
+            match (jumpc1, bit) {
+                (false, 0) => format!("jumple {target}"),
+                (false, 2) => format!("jumpne {target}"),
+                (false, 4) => format!("jumpge {target}"),
+                (true, 0) => format!("jumpgt {target}"),
+                (true, 2) => format!("jumpeq {target}"),
+                (true, 4) => format!("jumplt {target}"),
+                (false, _) => format!("jumpc0 {bit},{target}"),
+                (true, _) => format!("jumpc1 {bit},{target}"),
+            }
+        }
+        OpCodes::Resume => "resume".to_string(),
+        OpCodes::Save(d, e, f, disp) => format!("save R{d},R{e},{disp}[R{f}]"),
+        OpCodes::Restore(d, e, f, disp) => format!("restore R{d},R{e},{disp}[R{f}]"),
+        OpCodes::Push(d, a, b) => format!("push R{d},R{a},R{b}"),
+        OpCodes::Pop(d, a, b) => format!("pop R{d},R{a},R{b}"),
+        OpCodes::Top(d, a, b) => format!("top R{d},R{a},R{b}"),
+        OpCodes::Shiftl(d, a, k) => format!("shiftl R{d},R{a},{k}"),
+        OpCodes::Shiftr(d, a, k) => format!("shiftr R{d},R{a},{k}"),
+        OpCodes::Logicw(d, a, b, k) => format!("logicw R{d},R{a},R{b},{k}"),
+        OpCodes::Logicf(d, fd, fa, fb, k) => format!("logicf R{d},{fd},{fa},{fb},{k}"),
+        OpCodes::Extract(d, a, start, width) => format!("extract R{d},R{a},{start},{width}"),
+        OpCodes::Getctl(d, ctl) => format!("getctl R{d},{}", CONTROL_REGISTERS[ctl as usize]),
+        OpCodes::Putctl(d, ctl) => format!("putctl R{d},{}", CONTROL_REGISTERS[ctl as usize]),
+        // Branches are relative to the address after the instruction
+        OpCodes::Brc0(d, bit, offset) | OpCodes::Brc1(d, bit, offset) => {
+            let mnemonic = match op {
+                OpCodes::Brc0(..) => "brc0",
+                _ => "brc1",
+            };
+            let target = (address as u16).wrapping_add(2).wrapping_add(offset as u16);
+            format!("{mnemonic} R{d},{bit},{}", value(target, labels))
+        }
+        OpCodes::Dsptch(d, n) => format!("dsptch R{d},{n}"),
+    }
+}
+
+/// An address or displacement, as a label if there is one at that address
+fn value(word: u16, labels: &HashMap<usize, String>) -> String {
+    match labels.get(&(word as usize)) {
+        Some(label) => label.clone(),
+        None => format!("${word:04x}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::code::Code;
+
+    #[test]
+    fn disassembles_every_instruction() {
+        let source = "loop add R1,R2,R3
+     cmp R1,R2
+     trap R0,R0,R0
+     lea R1,$0012[R0]
+     load R4,x[R1]
+     store R4,$ffff[R2]
+     jump loop[R0]
+     jumplt x[R0]
+     jumpc0 5,loop[R3]
+     jal R13,loop[R0]
+     testset R2,x[R0]
+     resume
+     save R1,R4,2[R14]
+     push R1,R2,R3
+     shiftl R1,R2,3
+     logicw R1,R2,R3,6
+     logicf R1,2,3,4,6
+     extract R1,R2,3,4
+     getctl R1,timer
+     brc1 R2,4,loop
+     dsptch R3,10
+x    data $d000
+";
+        let code = Code::new(source.to_string());
+        assert!(code.errors.is_empty());
+        let memory = Memory::new(Some(&code.memory));
+        let labels = labels(&code.addresses());
+        let instructions = disassemble(&memory, 0..code.memory.len(), &labels);
+        let lines: Vec<String> = source
+            .lines()
+            .map(|line| {
+                line.trim_start_matches("loop")
+                    .trim_start_matches('x')
+                    .trim()
+                    .to_string()
+            })
+            .collect();
+        let texts: Vec<String> = instructions.iter().map(|(_, text)| text.clone()).collect();
+        assert_eq!(texts.len(), lines.len());
+        for (text, line) in texts.iter().zip(&lines).take(lines.len() - 1) {
+            assert_eq!(text, line);
+        }
+        assert_eq!(texts[texts.len() - 1], "data $d000");
+        assert_eq!(instructions[4].0, 5);
+
+        // Reading does not count as an access
+        assert!(!memory.is_read(0));
+    }
+}
@@ -0,0 +1,28 @@
+use std::fmt::Display;
+
+#[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug, Clone, serde_diff::SerdeDiff)]
+/// Faults that stop the machine, recorded in the state so they can be shown and stepped back out of
+#[serde_diff(opaque)]
+pub enum Fault {
+    /// The word at pc does not decode to a Sigma16 instruction
+    InvalidInstruction { pc: u16, word: u16 },
+}
+
+impl Fault {
+    /// The address of the instruction that caused the fault
+    pub fn pc(&self) -> u16 {
+        match self {
+            Fault::InvalidInstruction { pc, .. } => *pc,
+        }
+    }
+}
+
+impl Display for Fault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Fault::InvalidInstruction { pc, word } => {
+                write!(f, "Invalid instruction {word:#06x} at {pc:#06x}")
+            }
+        }
+    }
+}
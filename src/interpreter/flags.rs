@@ -0,0 +1,275 @@
+#![allow(non_upper_case_globals)]
+// Condition codes set in R15 by the arithmetic and comparison instructions
+//
+// Every arithmetic instruction replaces R15 with a condition code describing its result:
+//   g, G   result > 0 as a two's complement (g) and as a binary (G) number
+//   eq     result = 0
+//   lt     result < 0 as a two's complement number
+//   v      the two's complement result does not fit in 16 bits
+//   V      the binary result does not fit in 16 bits
+//   C      there was a carry out of the most significant bit
+//   f      division by zero, the destination is left unchanged and f is the only bit set
+// cmp compares two registers instead of a result with 0, and also sets L for < as binary numbers
+
+// R15 bit masks
+pub(crate) const R15_g: u16 = 0b1;
+pub(crate) const R15_G: u16 = 0b10;
+pub(crate) const R15_eq: u16 = 0b100;
+pub(crate) const R15_L: u16 = 0b1000;
+pub(crate) const R15_lt: u16 = 0b1_0000;
+pub(crate) const R15_v: u16 = 0b10_0000;
+pub(crate) const R15_V: u16 = 0b100_0000;
+pub(crate) const R15_C: u16 = 0b1000_0000;
+pub(crate) const R15_S: u16 = 0b1_0000_0000;
+pub(crate) const R15_s: u16 = 0b10_0000_0000;
+pub(crate) const R15_f: u16 = 0b100_0000_0000;
+
+/// Comparison bits of a result against 0
+fn compare_zero(result: u16) -> u16 {
+    let mut cc = 0_u16;
+    if result == 0 {
+        cc |= R15_eq;
+    } else {
+        cc |= R15_G;
+    }
+    if (result as i16) > 0 {
+        cc |= R15_g;
+    }
+    if (result as i16) < 0 {
+        cc |= R15_lt;
+    }
+    cc
+}
+
+/// Checks a two's complement result fits in 16 bits
+fn fits_i16(result: i32) -> bool {
+    (i16::MIN as i32..=i16::MAX as i32).contains(&result)
+}
+
+/// a + b + carry, returns (result, condition code)
+/// A binary overflow is always a carry out for addition, so V and C are set together
+pub fn add(a: u16, b: u16, carry: bool) -> (u16, u16) {
+    let sum = a as u32 + b as u32 + carry as u32;
+    let result = sum as u16;
+
+    let mut cc = compare_zero(result);
+    if sum > u16::MAX as u32 {
+        cc |= R15_V | R15_C;
+    }
+    if !fits_i16(a as i16 as i32 + b as i16 as i32 + carry as i32) {
+        cc |= R15_v;
+    }
+    (result, cc)
+}
+
+/// a - b, returns (result, condition code)
+/// Calculated as a + !b + 1, so there is a carry out unless a < b as binary numbers,
+/// in which case the binary result is negative and V is set instead
+pub fn sub(a: u16, b: u16) -> (u16, u16) {
+    let sum = a as u32 + (!b) as u32 + 1;
+    let result = sum as u16;
+
+    let mut cc = compare_zero(result);
+    if sum > u16::MAX as u32 {
+        cc |= R15_C;
+    }
+    if a < b {
+        cc |= R15_V;
+    }
+    if !fits_i16(a as i16 as i32 - b as i16 as i32) {
+        cc |= R15_v;
+    }
+    (result, cc)
+}
+
+/// a * b as two's complement numbers, returns (result, condition code)
+/// The lower 16 bits of the product are the same for binary and two's complement numbers,
+/// V is set if the binary product does not fit
+pub fn mul(a: u16, b: u16) -> (u16, u16) {
+    let product = a as i16 as i32 * b as i16 as i32;
+    let result = product as u16;
+
+    let mut cc = compare_zero(result);
+    if a as u32 * b as u32 > u16::MAX as u32 {
+        cc |= R15_V;
+    }
+    if !fits_i16(product) {
+        cc |= R15_v;
+    }
+    (result, cc)
+}
+
+/// a / b as two's complement numbers rounding towards 0, returns (result, condition code)
+/// Returns None when dividing by zero
+/// -32768 / -1 is the only division that overflows, the result stays -32768
+pub fn div(a: u16, b: u16) -> Option<(u16, u16)> {
+    if b == 0 {
+        return None;
+    }
+    let (quotient, overflow) = (a as i16).overflowing_div(b as i16);
+    let result = quotient as u16;
+
+    let mut cc = compare_zero(result);
+    if overflow {
+        cc |= R15_v;
+    }
+    Some((result, cc))
+}
+
+/// a * b as binary numbers, returns the 32 bit product as (low word, high word)
+pub fn muln(a: u16, b: u16) -> (u16, u16) {
+    let product = a as u32 * b as u32;
+    (product as u16, (product >> 16) as u16)
+}
+
+/// high:low / b as binary numbers, where the dividend is the 32 bit number high:low
+/// Returns (quotient low word, quotient high word, remainder), or None when dividing by zero
+pub fn divn(high: u16, low: u16, b: u16) -> Option<(u16, u16, u16)> {
+    if b == 0 {
+        return None;
+    }
+    let dividend = (high as u32) << 16 | low as u32;
+    let quotient = dividend / b as u32;
+    let remainder = dividend % b as u32;
+    Some((quotient as u16, (quotient >> 16) as u16, remainder as u16))
+}
+
+/// Compares a with b, returns the condition code
+pub fn cmp(a: u16, b: u16) -> u16 {
+    let mut cc = 0_u16;
+    if a == b {
+        cc |= R15_eq;
+    }
+    if a > b {
+        cc |= R15_G;
+    }
+    if a < b {
+        cc |= R15_L;
+    }
+    if (a as i16) > (b as i16) {
+        cc |= R15_g;
+    }
+    if (a as i16) < (b as i16) {
+        cc |= R15_lt;
+    }
+    cc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_sets_comparison_bits() {
+        assert_eq!(add(2, 3, false), (5, R15_G | R15_g));
+        assert_eq!(add(0, 0, false), (0, R15_eq));
+        assert_eq!(add(0xfffe, 0, false), (0xfffe, R15_G | R15_lt));
+    }
+
+    #[test]
+    fn add_binary_overflow_is_carry() {
+        // 65535 + 1 overflows as binary, -1 + 1 = 0 as two's complement
+        assert_eq!(add(0xffff, 1, false), (0, R15_eq | R15_V | R15_C));
+    }
+
+    #[test]
+    fn add_twos_complement_overflow() {
+        // 32767 + 1 fits as binary, overflows as two's complement
+        assert_eq!(add(0x7fff, 1, false), (0x8000, R15_G | R15_lt | R15_v));
+        // -32768 + -1 overflows both
+        assert_eq!(
+            add(0x8000, 0xffff, false),
+            (0x7fff, R15_G | R15_g | R15_v | R15_V | R15_C)
+        );
+    }
+
+    #[test]
+    fn add_with_carry_in() {
+        assert_eq!(add(1, 1, true), (3, R15_G | R15_g));
+        assert_eq!(add(0xffff, 0, true), (0, R15_eq | R15_V | R15_C));
+    }
+
+    #[test]
+    fn sub_does_not_underflow() {
+        // 3 - 5 = -2, negative as binary so V, no carry out
+        assert_eq!(sub(3, 5), (0xfffe, R15_G | R15_lt | R15_V));
+    }
+
+    #[test]
+    fn sub_carry_when_no_borrow() {
+        assert_eq!(sub(5, 3), (2, R15_G | R15_g | R15_C));
+        assert_eq!(sub(5, 5), (0, R15_eq | R15_C));
+    }
+
+    #[test]
+    fn sub_twos_complement_overflow() {
+        // -32768 - 1 overflows as two's complement
+        assert_eq!(sub(0x8000, 1), (0x7fff, R15_G | R15_g | R15_v | R15_C));
+        // 32767 - -1 overflows as two's complement
+        assert_eq!(sub(0x7fff, 0xffff), (0x8000, R15_G | R15_lt | R15_v | R15_V));
+    }
+
+    #[test]
+    fn mul_overflow() {
+        assert_eq!(mul(7, 6), (42, R15_G | R15_g));
+        // -2 * 3 = -6 overflows as binary but not as two's complement
+        assert_eq!(mul(0xfffe, 3), (0xfffa, R15_G | R15_lt | R15_V));
+        // 256 * 256 overflows both
+        assert_eq!(mul(256, 256), (0, R15_eq | R15_V | R15_v));
+        // 200 * 200 fits as binary but not two's complement
+        assert_eq!(mul(200, 200), (40000, R15_G | R15_lt | R15_v));
+    }
+
+    #[test]
+    fn mul_twos_complement() {
+        // -3 * -4 = 12
+        assert_eq!(mul(0xfffd, 0xfffc), (12, R15_G | R15_g | R15_V));
+        // -300 * 200 = -60000 does not fit
+        assert_eq!(mul(0xfed4, 200).1 & R15_v, R15_v);
+    }
+
+    #[test]
+    fn div_twos_complement() {
+        assert_eq!(div(7, 2), Some((3, R15_G | R15_g)));
+        assert_eq!(div(1, 2), Some((0, R15_eq)));
+        // -7 / 2 = -3, rounded towards 0
+        assert_eq!(div(0xfff9, 2), Some((0xfffd, R15_G | R15_lt)));
+        // -8 / -2 = 4
+        assert_eq!(div(0xfff8, 0xfffe), Some((4, R15_G | R15_g)));
+    }
+
+    #[test]
+    fn div_overflow() {
+        assert_eq!(div(0x8000, 0xffff), Some((0x8000, R15_G | R15_lt | R15_v)));
+    }
+
+    #[test]
+    fn div_by_zero() {
+        assert_eq!(div(5, 0), None);
+        assert_eq!(divn(0, 5, 0), None);
+    }
+
+    #[test]
+    fn muln_extends_into_high_word() {
+        assert_eq!(muln(0xffff, 0xffff), (0x0001, 0xfffe));
+        assert_eq!(muln(300, 300), (0x5f90, 0x0001));
+    }
+
+    #[test]
+    fn divn_uses_high_word() {
+        // 0x0001_0000 / 2 = 0x8000
+        assert_eq!(divn(1, 0, 2), Some((0x8000, 0, 0)));
+        // 0x0003_0001 / 3 = 0x1_0000 remainder 1
+        assert_eq!(divn(3, 1, 3), Some((0, 1, 1)));
+    }
+
+    #[test]
+    fn cmp_binary_and_twos_complement() {
+        assert_eq!(cmp(4, 4), R15_eq);
+        assert_eq!(cmp(5, 3), R15_G | R15_g);
+        assert_eq!(cmp(3, 5), R15_L | R15_lt);
+        // -1 is the largest binary number and less than 1 as two's complement
+        assert_eq!(cmp(0xffff, 1), R15_G | R15_lt);
+        assert_eq!(cmp(1, 0xffff), R15_L | R15_g);
+    }
+}
@@ -1,28 +1,124 @@
-#![allow(non_upper_case_globals)]
 use crate::interpreter::{
+    control::{
+        INTERRUPTS, INT_INVALID, INT_OVERFLOW, INT_TIMER, INT_TRAP, INT_ZDIV, IPC, ISTAT, MASK,
+        REQ, STATUS, STATUS_IE, TIMER, VECT,
+    },
+    flags::{self, R15_C, R15_S, R15_f, R15_s, R15_v},
     opcodes::{next_op, OpCodes},
     state::{RunningState, State},
 };
 use log::{log, Level};
 
-// R15 bit masks
-const TC_MASK: u16 = 0b1000_0000_0000_0000;
-pub(crate) const R15_g: u16 = 0b1;
-const R15_G: u16 = 0b10;
-pub(crate) const R15_eq: u16 = 0b100;
-pub(crate) const R15_L: u16 = 0b1000;
-pub(crate) const R15_lt: u16 = 0b1_0000;
-const R15_v: u16 = 0b10_0000;
-const R15_V: u16 = 0b100_0000;
-const R15_C: u16 = 0b1000_0000;
-const R15_S: u16 = 0b1_0000_0000;
-const R15_s: u16 = 0b10_0000_0000;
-const R15_f: u16 = 0b100_0000_0000;
-
 pub fn step(state: &mut State) {
-    let opcode = next_op(&state.memory, &mut state.pc, state.verbose).unwrap();
+    // An enabled interrupt takes a step of its own, so the jump to the handler can be seen
+    if let Some(interrupt) = pending_interrupt(state) {
+        dispatch(state, interrupt);
+        return;
+    }
+
+    let opcode = match next_op(&mut state.memory, &mut state.pc, state.verbose) {
+        Ok(opcode) => opcode,
+        Err(fault) => {
+            // Invalid instructions only stop the machine if there is no handler for them,
+            // the interrupt is not requested otherwise so it can not go off later
+            if enabled(state, INT_INVALID) {
+                request(state, INT_INVALID);
+                if let Some(interrupt) = pending_interrupt(state) {
+                    dispatch(state, interrupt);
+                }
+            } else {
+                log!(Level::Error, "{fault}. Haulting execution");
+                state.fault = Some(fault);
+                state.state = RunningState::Error;
+            }
+            return;
+        }
+    };
+    // Only highlight the PC when the instruction changes it, not when it is fetched
+    state.pc.reset_altered();
     log!(Level::Info, "{:?}", opcode);
     execute(opcode, state);
+    tick_timer(state);
+}
+
+/// Whether interrupts are enabled and this one is masked in
+fn enabled(state: &State, interrupt: u16) -> bool {
+    state.ctl[STATUS].get_ui() & STATUS_IE != 0 && state.ctl[MASK].get_ui() & interrupt != 0
+}
+
+/// Sets an interrupt request bit, it is dispatched before the next instruction
+/// Interrupts that are not enabled are not recorded, so they do not all go off at once
+/// when a program enables interrupts later
+fn request(state: &mut State, interrupt: u16) {
+    if enabled(state, interrupt) {
+        let req = state.ctl[REQ].get_ui() | interrupt;
+        state.ctl[REQ].set(req);
+    }
+}
+
+/// The bit number of the highest priority interrupt that is requested, masked in and enabled
+fn pending_interrupt(state: &State) -> Option<u16> {
+    if state.ctl[STATUS].get_ui() & STATUS_IE == 0 {
+        return None;
+    }
+    let pending = state.ctl[REQ].get_ui() & state.ctl[MASK].get_ui();
+    if pending == 0 {
+        None
+    } else {
+        Some(pending.trailing_zeros() as u16)
+    }
+}
+
+/// Saves the pc and status, disables interrupts and jumps to the handler in the vector
+/// resume returns to the interrupted program
+fn dispatch(state: &mut State, interrupt: u16) {
+    let req = state.ctl[REQ].get() & !(1 << interrupt);
+    state.ctl[REQ].set(req);
+
+    let status = state.ctl[STATUS].get();
+    state.ctl[ISTAT].set(status);
+    state.ctl[STATUS].set(status & !STATUS_IE);
+    let pc = state.pc.get();
+    state.ctl[IPC].set(pc);
+
+    let vector = state.ctl[VECT].get().wrapping_add(interrupt);
+    let handler = state.memory.read(vector as usize);
+    state.pc.set(handler);
+    state.state = RunningState::Interrupted;
+
+    if state.verbose {
+        // Bits without a named interrupt can still be requested with putctl
+        let name = match INTERRUPTS.get(interrupt as usize) {
+            Some(name) => name.to_string(),
+            None => format!("bit {interrupt}"),
+        };
+        log!(Level::Info, "Interrupt: {}, handler {:#06x}", name, handler);
+    }
+}
+
+/// Counts the timer down once per instruction, requesting an interrupt when it reaches 0
+fn tick_timer(state: &mut State) {
+    let timer = state.ctl[TIMER].get_ui();
+    if timer > 0 {
+        state.ctl[TIMER].set(timer - 1);
+        if timer == 1 {
+            request(state, INT_TIMER);
+        }
+    }
+}
+
+/// The effective address of an RX instruction, disp + R[index] wrapping around modulo 2^16
+fn effective_address(state: &mut State, index: u8, disp: u16) -> u16 {
+    disp.wrapping_add(state.r[index as usize].get())
+}
+
+/// Sets R15 to the condition code of an arithmetic instruction
+/// Two's complement overflow requests an overflow interrupt
+fn set_condition(state: &mut State, r15: u16) {
+    state.r[15].set(r15);
+    if r15 & R15_v > 0 {
+        request(state, INT_OVERFLOW);
+    }
 }
 
 fn execute(opcode: OpCodes, state: &mut State) {
@@ -32,77 +128,41 @@ fn execute(opcode: OpCodes, state: &mut State) {
         // =====================
         OpCodes::Add(..) => {
             if let OpCodes::Add(rd, ra, rb) = opcode {
-                let result: u32 =
-                    state.r[ra as usize].get() as u32 + state.r[rb as usize].get() as u32;
-                state.r[rd as usize].set(result as u16);
-
-                // Set R15 bits
-                let mut r15 = 0_u16;
-                if result as u16 & TC_MASK == 0 {
-                    r15 |= R15_g;
-                }
-                if result > 0 {
-                    r15 |= R15_G;
-                }
-                if result as u16 == 0 {
-                    r15 |= R15_eq;
-                }
-                if result as u16 & TC_MASK > 0 {
-                    r15 |= R15_lt;
-                } // if negative number
-                if result & 0xffff_0000 > 0 {
-                    r15 = r15 | R15_V | R15_C;
-                }
-                state.r[15].set(r15);
+                let (result, r15) =
+                    flags::add(state.r[ra as usize].get(), state.r[rb as usize].get(), false);
+                state.r[rd as usize].set(result);
+                set_condition(state, r15);
             }
         }
         OpCodes::Addc(..) => {
             if let OpCodes::Addc(rd, ra, rb) = opcode {
                 // Is carry bit set?
                 let carry_set: bool = (state.r[15].get() & R15_C) > 0;
-                let mut result: u32 =
-                    state.r[ra as usize].get() as u32 + state.r[rb as usize].get() as u32;
-
-                if carry_set {
-                    result += 1;
-                }
+                let (result, r15) = flags::add(
+                    state.r[ra as usize].get(),
+                    state.r[rb as usize].get(),
+                    carry_set,
+                );
 
-                state.r[rd as usize].set(result as u16);
+                state.r[rd as usize].set(result);
                 if state.verbose {
                     println!(
-                        "  {} + {} = {} Into R{}",
+                        "  {} + {} + {} = {} Into R{}",
                         state.r[ra as usize].get(),
                         state.r[rb as usize].get(),
+                        carry_set as u16,
                         result,
                         rd
                     );
                 }
-
-                // Set R15 bits
-                let mut r15 = 0_u16;
-                if result as u16 & TC_MASK == 0 {
-                    r15 |= R15_g;
-                }
-                if result > 0 {
-                    r15 |= R15_G;
-                }
-                if result as u16 == 0 {
-                    r15 |= R15_eq;
-                }
-                if result as u16 & TC_MASK > 0 {
-                    r15 |= R15_lt;
-                } // if negative number
-                if result & 0xffff_0000 > 0 {
-                    r15 = r15 | R15_V | R15_C;
-                }
-                state.r[15].set(r15);
+                set_condition(state, r15);
             }
         }
         OpCodes::Sub(..) => {
             if let OpCodes::Sub(rd, ra, rb) = opcode {
-                // let mut rd_temp = state.r[rd as usize];
-                let result = state.r[ra as usize].get() as u32 - state.r[rb as usize].get() as u32;
-                state.r[rd as usize].set(result as u16);
+                let (result, r15) =
+                    flags::sub(state.r[ra as usize].get(), state.r[rb as usize].get());
+                state.r[rd as usize].set(result);
                 if state.verbose {
                     println!(
                         "  {} - {} = {} Into R{}",
@@ -112,30 +172,13 @@ fn execute(opcode: OpCodes, state: &mut State) {
                         rd
                     );
                 }
-
-                // Set R15 bits
-                let mut r15 = 0_u16;
-                if result as u16 & TC_MASK == 0 {
-                    r15 |= R15_g;
-                }
-                if result > 0 {
-                    r15 |= R15_G;
-                }
-                if result as u16 == 0 {
-                    r15 |= R15_eq;
-                }
-                if result as u16 & TC_MASK > 0 {
-                    r15 |= R15_lt;
-                } // if negative number
-                if result & 0xffff_0000 > 0 {
-                    r15 = r15 | R15_V | R15_C;
-                }
-                state.r[15].set(r15);
+                set_condition(state, r15);
             }
         }
         OpCodes::Mul(..) => {
             if let OpCodes::Mul(rd, ra, rb) = opcode {
-                let result = state.r[ra as usize].get() as u32 * state.r[rb as usize].get() as u32;
+                let (result, r15) =
+                    flags::mul(state.r[ra as usize].get(), state.r[rb as usize].get());
                 if state.verbose {
                     println!(
                         "  {} * {} = {} Into R{}",
@@ -146,103 +189,64 @@ fn execute(opcode: OpCodes, state: &mut State) {
                     );
                 }
 
-                state.r[rd as usize].set(result as u16);
-
-                // Set R15 bits
-                let mut r15 = 0_u16;
-                if result & 0xffff_0000 > 0 {
-                    r15 |= R15_v;
-                }
-                state.r[15].set(r15);
+                state.r[rd as usize].set(result);
+                set_condition(state, r15);
             }
         }
         OpCodes::Muln(..) => {
             if let OpCodes::Muln(rd, ra, rb) = opcode {
-                let temp: u32 =
-                    state.r[ra as usize].get() as u32 * state.r[rd as usize].get() as u32;
-                state.r[rb as usize].set(temp as u16);
-                state.r[15].set((temp >> 16) as u16);
+                // The product is extended into R15, R15:Rd := Ra * Rb
+                let (low, high) =
+                    flags::muln(state.r[ra as usize].get(), state.r[rb as usize].get());
+                state.r[rd as usize].set(low);
+                state.r[15].set(high);
             }
         }
         OpCodes::Div(..) => {
             if let OpCodes::Div(rd, ra, rb) = opcode {
-                let ra_value = state.r[ra as usize].get();
-                let rd_value = state.r[rd as usize].get();
-                state.r[rb as usize].set(ra_value / rd_value);
-                state.r[15].set(ra_value % rd_value);
-                if state.verbose {
-                    println!(
-                        "  {} / {} = {} Into R{}, Remainder {}",
-                        state.r[ra as usize].get(),
-                        state.r[rb as usize].get(),
-                        state.r[rb as usize].get(),
-                        rd,
-                        state.r[15].get()
-                    );
+                match flags::div(state.r[ra as usize].get(), state.r[rb as usize].get()) {
+                    Some((result, r15)) => {
+                        state.r[rd as usize].set(result);
+                        if state.verbose {
+                            println!(
+                                "  {} / {} = {} Into R{}",
+                                state.r[ra as usize].get() as i16,
+                                state.r[rb as usize].get() as i16,
+                                result as i16,
+                                rd
+                            );
+                        }
+                        set_condition(state, r15);
+                    }
+                    None => divide_by_zero(state),
                 }
             }
         }
         OpCodes::Divn(..) => {
             if let OpCodes::Divn(rd, ra, rb) = opcode {
-                let dividend_mask: u32 = 0xffff_0000;
-
                 // The left most 16 bits of dividend is contents of R15
                 // The right most 16 bits is Ra
-                let mut dividend: u32 = u32::from(state.r[15].get()) << 16;
-                // left most 16 bits is R15, bitwise or with 0xffff[Ra] where Ra is 2 bytes
-                dividend |= u32::from(state.r[ra as usize].get()) | dividend_mask;
-
-                let quotient: u32 = dividend / u32::from(state.r[rd as usize].get());
-                let remainder: u16 = (dividend % u32::from(state.r[rd as usize].get())) as u16;
-
-                state.r[15].set((quotient >> 16) as u16);
-                state.r[rb as usize].set(quotient as u16);
-                state.r[ra as usize].set(remainder);
+                // R15:Rd := R15:Ra / Rb, Ra := R15:Ra % Rb
+                match flags::divn(
+                    state.r[15].get(),
+                    state.r[ra as usize].get(),
+                    state.r[rb as usize].get(),
+                ) {
+                    Some((low, high, remainder)) => {
+                        state.r[rd as usize].set(low);
+                        state.r[15].set(high);
+                        state.r[ra as usize].set(remainder);
+                    }
+                    None => divide_by_zero(state),
+                }
             }
         }
         OpCodes::Cmp(..) => {
-            if let OpCodes::Cmp(ra, rd) = opcode {
-                let mut r15: u16 = 0;
-                println!("R{ra} cmp R{rd}");
-                if state.r[ra as usize].get() == state.r[rd as usize].get() {
-                    print!("eq");
-                    r15 |= R15_eq; // Ra == Rb
-                    state.r[15].set(r15);
-                } else {
-                    if state.r[ra as usize].get() > state.r[rd as usize].get() {
-                        print!("G");
-                        r15 |= R15_G; // Ra > Rb (binary)
-                    } else {
-                        print!("lt");
-                        r15 |= R15_lt; // Ra < Rb (binary)
-                    }
-
-                    // If either Ra or Rd is twos complement,
-                    //      then if Ra is bigger than Rd
-                    //          then Ra is smaller than Rd
-                    //      otherwise, Rd is smaller than Ra
-                    // otherwise if Ra is less than Rd
-                    //      then Ra is smaller than Rd
-                    // otherwise Rd is smaller than Ra
-                    if (state.r[ra as usize].get() & TC_MASK) > 0
-                        || (state.r[rd as usize].get() & TC_MASK) > 0
-                    {
-                        if state.r[ra as usize].get() > state.r[rd as usize].get() {
-                            print!("L");
-                            r15 |= R15_L; // Ra < Rb (twos complement)
-                        } else {
-                            print!("g");
-                            r15 |= R15_g; // Ra > Rb (twos complement)
-                        }
-                    } else if state.r[ra as usize].get() < state.r[rd as usize].get() {
-                        print!("L");
-                        r15 |= R15_L; // Ra < Rb (twos complement)
-                    } else {
-                        print!("g");
-                        r15 |= R15_g; // Ra > Rb (twos complement)
-                    }
+            if let OpCodes::Cmp(ra, rb) = opcode {
+                let r15 = flags::cmp(state.r[ra as usize].get(), state.r[rb as usize].get());
+                if state.verbose {
+                    println!("  R{ra} cmp R{rb}, R15 := {r15:#06x}");
                 }
-                print!("\n");
                 state.r[15].set(r15);
             }
         }
@@ -252,48 +256,58 @@ fn execute(opcode: OpCodes, state: &mut State) {
         OpCodes::Rrr4(..) => {}
         OpCodes::Trap(..) => {
             if let OpCodes::Trap(ra, rb, rc) = opcode {
-                if ra < 255 {
-                    match state.r[ra as usize].get() {
-                        0 => {
-                            // Hault
-                            if state.verbose {
-                                println!("  Trap: Hault");
-                            }
-                            state.state = RunningState::Haulted;
+                // Ra holds the trap code
+                // For console I/O, Rb holds the buffer address and Rc holds its length
+                match state.r[ra as usize].get() {
+                    0 => {
+                        // Hault
+                        if state.verbose {
+                            println!("  Trap: Hault");
                         }
-                        1 => {
-                            // Non-blocking read
-                            if state.verbose {
-                                println!("  Trap: Non-blocking read");
-                            }
+                        state.state = RunningState::Haulted;
+                    }
+                    1 => {
+                        // Non-blocking read
+                        if state.verbose {
+                            println!("  Trap: Non-blocking read");
                         }
-                        2 => {
-                            // Non-blocking write
-                            if state.verbose {
-                                println!("  Trap: Non-blocking write");
-                            }
+                        trap_read(state, rb, rc);
+                    }
+                    2 => {
+                        // Non-blocking write
+                        if state.verbose {
+                            println!("  Trap: Non-blocking write");
                         }
-                        3 => {
-                            // Blocking read
-                            if state.verbose {
-                                println!("  Trap: Blocking read");
-                            }
+                        trap_write(state, rb, rc);
+                    }
+                    3 => {
+                        // Blocking read
+                        if state.verbose {
+                            println!("  Trap: Blocking read");
                         }
-                        4 => {
-                            // Breakpoint
-                            if state.verbose {
-                                println!("  Trap: Breakpoint");
-                            }
+                        if state.console.has_input() {
+                            trap_read(state, rb, rc);
+                        } else {
+                            // Wait on this instruction, it is run again once there is input
+                            let addr = state.pc.get_ui().wrapping_sub(1);
+                            state.pc.set(addr);
+                            state.state = RunningState::Waiting;
                         }
-                        _ => {
-                            //
-                            if state.verbose {
-                                println!("  Trap: Unknown trap");
-                            }
+                    }
+                    4 => {
+                        // Breakpoint, the runner stops after the trap like at a breakpoint
+                        if state.verbose {
+                            println!("  Trap: Breakpoint");
                         }
+                        state.state = RunningState::Breakpoint;
+                    }
+                    _ => {
+                        // User defined trap, handled by the trap interrupt if it is enabled
+                        if state.verbose {
+                            println!("  Trap: User trap");
+                        }
+                        request(state, INT_TRAP);
                     }
-                } else {
-                    // User defined trap
                 }
             }
         }
@@ -302,7 +316,7 @@ fn execute(opcode: OpCodes, state: &mut State) {
         // ================
         OpCodes::Lea(..) => {
             if let OpCodes::Lea(dst, disp, v) = opcode {
-                let result = (u32::from(v) + state.r[disp as usize].get() as u32) as u16;
+                let result = effective_address(state, disp, v);
                 state.r[dst as usize].set(result);
                 if state.verbose {
                     log!(
@@ -316,11 +330,8 @@ fn execute(opcode: OpCodes, state: &mut State) {
         }
         OpCodes::Load(..) => {
             if let OpCodes::Load(dst, disp, addr) = opcode {
-                let mut temp_addr = (state.r[disp as usize].get() as u32) + u32::from(addr);
-                if temp_addr > 65534 {
-                    temp_addr -= 65534;
-                }
-                state.r[dst as usize].set(state.memory[temp_addr as usize]);
+                let temp_addr = effective_address(state, disp, addr);
+                state.r[dst as usize].set(state.memory.read(temp_addr as usize));
                 if state.verbose {
                     println!(
                         "  Load {:#06x} from {:#06x} into R{}",
@@ -333,7 +344,7 @@ fn execute(opcode: OpCodes, state: &mut State) {
         }
         OpCodes::Store(..) => {
             if let OpCodes::Store(src, disp, addr) = opcode {
-                let dst_addr = addr + state.r[disp as usize].get();
+                let dst_addr = effective_address(state, disp, addr);
                 state.memory[dst_addr as usize] = state.r[src as usize].get();
                 if state.verbose {
                     println!(
@@ -345,32 +356,810 @@ fn execute(opcode: OpCodes, state: &mut State) {
                 }
             }
         }
-        OpCodes::Jumpc(..) => {
-            if let OpCodes::Jumpc(cond, disp, dest) = opcode {
-                if cond(state.r[15].get()) {
-                    let mut addr = dest as u32 + state.r[disp as usize].get() as u32;
-                    if addr > u16::MAX as u32 {
-                        addr -= u16::MAX as u32;
-                    }
-                    state.pc.set(addr as u16)
-                }
+        OpCodes::Jumpc0(..) | OpCodes::Jumpc1(..) => {
+            let (bit, disp, dest, jump_on) = match opcode {
+                OpCodes::Jumpc0(bit, disp, dest) => (bit, disp, dest, 0),
+                OpCodes::Jumpc1(bit, disp, dest) => (bit, disp, dest, 1),
+                _ => unreachable!(),
+            };
+            // Jump if the condition code bit in R15 is 0 for jumpc0 and 1 for jumpc1
+            if (state.r[15].get() >> bit) & 1 == jump_on {
+                let addr = effective_address(state, disp, dest);
+                state.pc.set(addr)
             }
         }
         OpCodes::Jump(..) => {
             if let OpCodes::Jump(_, disp, dest) = opcode {
-                let mut addr = dest as u32 + state.r[disp as usize].get() as u32;
-                if addr > u16::MAX as u32 {
-                    addr -= u16::MAX as u32;
+                let addr = effective_address(state, disp, dest);
+                state.pc.set(addr)
+            }
+        }
+        OpCodes::Jal(..) => {
+            if let OpCodes::Jal(link, disp, dest) = opcode {
+                // Work out the destination before the link register is written,
+                // the link register may also be the displacement register
+                let addr = effective_address(state, disp, dest);
+                let return_addr = state.pc.get();
+                state.r[link as usize].set(return_addr);
+                state.pc.set(addr);
+                if state.verbose {
+                    println!("  Jump to {addr:#06x}, return address {return_addr:#06x} into R{link}");
+                }
+            }
+        }
+        OpCodes::Jumpz(..) => {
+            if let OpCodes::Jumpz(test, disp, dest) = opcode {
+                if state.r[test as usize].get() == 0 {
+                    let addr = effective_address(state, disp, dest);
+                    state.pc.set(addr);
+                }
+            }
+        }
+        OpCodes::Jumpnz(..) => {
+            if let OpCodes::Jumpnz(test, disp, dest) = opcode {
+                if state.r[test as usize].get() != 0 {
+                    let addr = effective_address(state, disp, dest);
+                    state.pc.set(addr);
+                }
+            }
+        }
+        OpCodes::Testset(..) => {
+            if let OpCodes::Testset(dst, disp, addr) = opcode {
+                // Read the old value and set the word to 1 in a single instruction
+                let addr = effective_address(state, disp, addr);
+                state.r[dst as usize].set(state.memory.read(addr as usize));
+                state.memory[addr as usize] = 1;
+                if state.verbose {
+                    println!(
+                        "  Test and set {:#06x}, old value {:#06x} into R{}",
+                        addr,
+                        state.r[dst as usize].get_ui(),
+                        dst
+                    );
                 }
-                state.pc.set(addr as u16)
             }
         }
 
-        _ => {
-            if state.verbose {
-                log!(Level::Error, "Unknown instruction. Haulting execution");
+        // ================
+        // EXP Instructions
+        // ================
+        OpCodes::Resume => {
+            // Return from an interrupt handler
+            let ipc = state.ctl[IPC].get();
+            let istat = state.ctl[ISTAT].get();
+            state.pc.set(ipc);
+            state.ctl[STATUS].set(istat);
+        }
+        OpCodes::Save(..) => {
+            if let OpCodes::Save(start, end, base, disp) = opcode {
+                let mut addr = state.r[base as usize].get().wrapping_add(disp as u16);
+                for reg in start..=end {
+                    state.memory[addr as usize] = state.r[reg as usize].get();
+                    addr = addr.wrapping_add(1);
+                }
+            }
+        }
+        OpCodes::Restore(..) => {
+            if let OpCodes::Restore(start, end, base, disp) = opcode {
+                let mut addr = state.r[base as usize].get().wrapping_add(disp as u16);
+                for reg in start..=end {
+                    state.r[reg as usize].set(state.memory.read(addr as usize));
+                    addr = addr.wrapping_add(1);
+                }
+            }
+        }
+        OpCodes::Push(..) => {
+            if let OpCodes::Push(src, top, limit) = opcode {
+                // Ra points at the top of the stack, Rb is the last usable address
+                if state.r[top as usize].get() < state.r[limit as usize].get() {
+                    let addr = state.r[top as usize].get() + 1;
+                    state.r[top as usize].set(addr);
+                    state.memory[addr as usize] = state.r[src as usize].get();
+                } else {
+                    let r15 = state.r[15].get() | R15_S;
+                    state.r[15].set(r15);
+                }
+            }
+        }
+        OpCodes::Pop(..) => {
+            if let OpCodes::Pop(dst, top, base) = opcode {
+                // Ra points at the top of the stack, Rb is the first address of the stack
+                if state.r[top as usize].get() >= state.r[base as usize].get() {
+                    let addr = state.r[top as usize].get();
+                    state.r[dst as usize].set(state.memory.read(addr as usize));
+                    state.r[top as usize].set(addr.wrapping_sub(1));
+                } else {
+                    let r15 = state.r[15].get() | R15_s;
+                    state.r[15].set(r15);
+                }
+            }
+        }
+        OpCodes::Top(..) => {
+            if let OpCodes::Top(dst, top, base) = opcode {
+                if state.r[top as usize].get() >= state.r[base as usize].get() {
+                    let addr = state.r[top as usize].get();
+                    state.r[dst as usize].set(state.memory.read(addr as usize));
+                } else {
+                    let r15 = state.r[15].get() | R15_s;
+                    state.r[15].set(r15);
+                }
+            }
+        }
+        OpCodes::Shiftl(..) => {
+            if let OpCodes::Shiftl(dst, src, amount) = opcode {
+                let result = state.r[src as usize].get() << amount;
+                state.r[dst as usize].set(result);
             }
-            state.state = RunningState::Haulted;
         }
+        OpCodes::Shiftr(..) => {
+            if let OpCodes::Shiftr(dst, src, amount) = opcode {
+                let result = state.r[src as usize].get() >> amount;
+                state.r[dst as usize].set(result);
+            }
+        }
+        OpCodes::Logicw(..) => {
+            if let OpCodes::Logicw(dst, ra, rb, function) = opcode {
+                let result = logic(
+                    function,
+                    state.r[ra as usize].get(),
+                    state.r[rb as usize].get(),
+                );
+                state.r[dst as usize].set(result);
+            }
+        }
+        OpCodes::Logicf(..) => {
+            if let OpCodes::Logicf(reg, dst_bit, a_bit, b_bit, function) = opcode {
+                // Operates on single bits of one register, useful for combining R15 flags
+                let value = state.r[reg as usize].get();
+                let x = if value & (1 << a_bit) > 0 { 0xffff } else { 0 };
+                let y = if value & (1 << b_bit) > 0 { 0xffff } else { 0 };
+                let result = if logic(function, x, y) & 1 > 0 {
+                    value | (1 << dst_bit)
+                } else {
+                    value & !(1 << dst_bit)
+                };
+                state.r[reg as usize].set(result);
+            }
+        }
+        OpCodes::Extract(..) => {
+            if let OpCodes::Extract(dst, src, start, width) = opcode {
+                let mask = ((1_u32 << width) - 1) as u16;
+                let result = (state.r[src as usize].get() >> start) & mask;
+                state.r[dst as usize].set(result);
+            }
+        }
+        OpCodes::Getctl(..) => {
+            if let OpCodes::Getctl(dst, ctl) = opcode {
+                let value = state.ctl[ctl as usize].get();
+                state.r[dst as usize].set(value);
+            }
+        }
+        OpCodes::Putctl(..) => {
+            if let OpCodes::Putctl(src, ctl) = opcode {
+                let value = state.r[src as usize].get();
+                state.ctl[ctl as usize].set(value);
+            }
+        }
+        OpCodes::Brc0(..) | OpCodes::Brc1(..) => {
+            let (reg, bit, offset, branch_on) = match opcode {
+                OpCodes::Brc0(reg, bit, offset) => (reg, bit, offset, 0),
+                OpCodes::Brc1(reg, bit, offset) => (reg, bit, offset, 1),
+                _ => unreachable!(),
+            };
+            if (state.r[reg as usize].get() >> bit) & 1 == branch_on {
+                // Offset is relative to the instruction after the branch
+                let addr = state.pc.get().wrapping_add(offset as u16);
+                state.pc.set(addr);
+            }
+        }
+        OpCodes::Dsptch(..) => {
+            if let OpCodes::Dsptch(reg, size) = opcode {
+                // A table of two word jump instructions follows the dispatch
+                // Out of range values skip over the table
+                let index = state.r[reg as usize].get().min(size);
+                let addr = state.pc.get().wrapping_add(index.wrapping_mul(2));
+                state.pc.set(addr);
+            }
+        }
+    }
+}
+
+/// Division by zero leaves the destination unchanged and sets the f flag in R15
+fn divide_by_zero(state: &mut State) {
+    if state.verbose {
+        println!("  Division by zero");
+    }
+    state.r[15].set(R15_f);
+    request(state, INT_ZDIV);
+}
+
+/// Reads characters from the console input into the buffer at R[addr]
+/// At most R[len] characters are read, the number actually read is put into R[len]
+fn trap_read(state: &mut State, addr: u8, len: u8) {
+    let start = state.r[addr as usize].get();
+    let words = state.console.read(state.r[len as usize].get() as usize);
+    for (i, word) in words.iter().enumerate() {
+        state.memory[start.wrapping_add(i as u16) as usize] = *word;
+    }
+    state.r[len as usize].set(words.len() as u16);
+}
+
+/// Writes R[len] characters from the buffer at R[addr] to the console output
+fn trap_write(state: &mut State, addr: u8, len: u8) {
+    let start = state.r[addr as usize].get();
+    let words: Vec<u16> = (0..state.r[len as usize].get())
+        .map(|i| state.memory.read(start.wrapping_add(i) as usize))
+        .collect();
+    state.console.write(&words);
+}
+
+/// Applies a logic function bitwise to x and y
+/// The function is a truth table, the most significant bit gives the result for x=0, y=0
+/// and the least significant bit gives the result for x=1, y=1
+/// For example: and = 0b0001, or = 0b0111, xor = 0b0110
+fn logic(function: u8, x: u16, y: u16) -> u16 {
+    let table = |bit: u8| if function & bit > 0 { 0xffff_u16 } else { 0 };
+    (!x & !y & table(0b1000)) | (!x & y & table(0b0100)) | (x & !y & table(0b0010)) | (x & y & table(0b0001))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::code::Code;
+    use crate::interpreter::fault::Fault;
+
+    fn new_state() -> State {
+        State::new(&Code::new(String::new()))
+    }
+
+    #[test]
+    fn effective_address_wraps() {
+        let mut state = new_state();
+        state.r[1].set(0xffff);
+        assert_eq!(effective_address(&mut state, 1, 0), 0xffff);
+        assert_eq!(effective_address(&mut state, 1, 1), 0);
+        assert_eq!(effective_address(&mut state, 1, 0xffff), 0xfffe);
+        // R0 is always 0, so the address is just the displacement
+        assert_eq!(effective_address(&mut state, 0, 0xffff), 0xffff);
+    }
+
+    #[test]
+    fn lea_wraps() {
+        let mut state = new_state();
+        state.r[1].set(0xfffe);
+        execute(OpCodes::Lea(2, 1, 3), &mut state);
+        assert_eq!(state.r[2].get(), 1);
+    }
+
+    #[test]
+    fn load_near_end_of_memory() {
+        let mut state = new_state();
+        state.memory[0xffff] = 42;
+        state.memory[0] = 7;
+        state.r[1].set(0xffff);
+        execute(OpCodes::Load(2, 1, 0), &mut state);
+        execute(OpCodes::Load(3, 1, 1), &mut state);
+        assert_eq!(state.r[2].get(), 42);
+        assert_eq!(state.r[3].get(), 7);
+    }
+
+    #[test]
+    fn store_near_end_of_memory() {
+        let mut state = new_state();
+        state.r[1].set(0xfffe);
+        state.r[2].set(9);
+        execute(OpCodes::Store(2, 1, 1), &mut state);
+        execute(OpCodes::Store(2, 1, 3), &mut state);
+        assert_eq!(state.memory[0xffff], 9);
+        assert_eq!(state.memory[1], 9);
+    }
+
+    #[test]
+    fn jumps_wrap() {
+        let mut state = new_state();
+        state.r[1].set(0xffff);
+        execute(OpCodes::Jump(0, 1, 2), &mut state);
+        assert_eq!(state.pc.get(), 1);
+
+        // jumpc1 on the eq bit
+        state.r[15].set(flags::cmp(0, 0));
+        execute(OpCodes::Jumpc1(2, 1, 0xffff), &mut state);
+        assert_eq!(state.pc.get(), 0xfffe);
+
+        state.pc.set(0x10);
+        execute(OpCodes::Jal(13, 1, 1), &mut state);
+        assert_eq!(state.pc.get(), 0);
+        assert_eq!(state.r[13].get(), 0x10);
+    }
+
+    #[test]
+    fn jal_saves_the_return_address() {
+        let mut state = new_state();
+        state.pc.set(0x10);
+        state.r[1].set(0x20);
+        execute(OpCodes::Jal(13, 1, 5), &mut state);
+        assert_eq!(state.pc.get(), 0x25);
+        assert_eq!(state.r[13].get(), 0x10);
+
+        // The destination uses the link register before it is overwritten
+        state.pc.set(0x30);
+        execute(OpCodes::Jal(1, 1, 5), &mut state);
+        assert_eq!(state.pc.get(), 0x25);
+        assert_eq!(state.r[1].get(), 0x30);
+    }
+
+    #[test]
+    fn jumpz_and_jumpnz() {
+        let mut state = new_state();
+        state.pc.set(0x10);
+        state.r[1].set(0);
+        state.r[2].set(3);
+
+        execute(OpCodes::Jumpnz(1, 0, 0x40), &mut state);
+        assert_eq!(state.pc.get(), 0x10);
+        execute(OpCodes::Jumpz(1, 0, 0x40), &mut state);
+        assert_eq!(state.pc.get(), 0x40);
+
+        execute(OpCodes::Jumpz(2, 0, 0x50), &mut state);
+        assert_eq!(state.pc.get(), 0x40);
+        execute(OpCodes::Jumpnz(2, 2, 0x50), &mut state);
+        assert_eq!(state.pc.get(), 0x53);
+    }
+
+    #[test]
+    fn testset_returns_the_old_value() {
+        let mut state = new_state();
+        state.memory[8] = 0;
+        execute(OpCodes::Testset(1, 0, 8), &mut state);
+        assert_eq!(state.r[1].get(), 0);
+        assert_eq!(state.memory[8], 1);
+
+        execute(OpCodes::Testset(1, 0, 8), &mut state);
+        assert_eq!(state.r[1].get(), 1);
+        assert_eq!(state.memory[8], 1);
+
+        state.memory[9] = 7;
+        execute(OpCodes::Testset(2, 0, 9), &mut state);
+        assert_eq!(state.r[2].get(), 7);
+        assert_eq!(state.memory[9], 1);
+    }
+
+    #[test]
+    fn runs_assembled_jumps() {
+        // Checks the opcodes the assembler gives match the ones the interpreter decodes
+        let code = Code::new(
+            "     jal R13,sub[R0]
+     jumpz R2,done[R0]
+     trap R0,R0,R0
+sub  testset R2,lock[R0]
+     jumpnz R13,0[R13]
+done trap R0,R0,R0
+lock data 0
+"
+            .to_string(),
+        );
+        assert!(!code.errors.iter().any(|error| error.is_error()));
+        let mut state = State::new(&code);
+        let mut pcs = Vec::new();
+        while state.state == RunningState::Step && pcs.len() < 10 {
+            pcs.push(state.pc.get_ui());
+            step(&mut state);
+        }
+        assert_eq!(pcs, vec![0, 5, 7, 2, 9]);
+        assert_eq!(state.state, RunningState::Haulted);
+        assert_eq!(state.r[13].get(), 2);
+        assert_eq!(state.memory[10], 1);
+    }
+
+    #[test]
+    fn testset_near_end_of_memory() {
+        let mut state = new_state();
+        state.r[1].set(0xffff);
+        execute(OpCodes::Testset(2, 1, 1), &mut state);
+        assert_eq!(state.r[2].get(), 0);
+        assert_eq!(state.memory[0], 1);
+    }
+
+    #[test]
+    fn fetch_wraps_pc() {
+        let mut state = new_state();
+        // lea R1,$0005[R0] split across the end of memory
+        state.memory[0xffff] = 0xf100;
+        state.memory[0] = 0x0005;
+        state.pc.set(0xffff);
+        state.state = RunningState::Step;
+        step(&mut state);
+        assert_eq!(state.r[1].get(), 5);
+        assert_eq!(state.pc.get(), 1);
+        // Both instruction words are highlighted as read
+        assert!(state.memory.is_read(0xffff));
+        assert!(state.memory.is_read(0));
+    }
+
+    /// Enables the interrupts in mask with the vector at 0x100, handler i at 0x200 + i
+    fn with_handlers(mask: u16) -> State {
+        let mut state = new_state();
+        state.ctl[STATUS].set(STATUS_IE);
+        state.ctl[MASK].set(mask);
+        state.ctl[VECT].set(0x100);
+        for i in 0..5 {
+            state.memory[0x100 + i] = 0x200 + i as u16;
+        }
+        state.state = RunningState::Step;
+        state
+    }
+
+    #[test]
+    fn dispatches_through_the_vector() {
+        let mut state = with_handlers(INT_OVERFLOW | INT_ZDIV);
+        state.pc.set(0x10);
+        state.r[1].set(0x7fff);
+        state.r[2].set(1);
+        execute(OpCodes::Add(3, 1, 2), &mut state);
+        assert_eq!(state.ctl[REQ].get_ui(), INT_OVERFLOW);
+
+        step(&mut state);
+        assert_eq!(state.pc.get_ui(), 0x202);
+        assert_eq!(state.state, RunningState::Interrupted);
+        assert_eq!(state.ctl[REQ].get_ui(), 0);
+        assert_eq!(state.ctl[IPC].get_ui(), 0x10);
+        assert_eq!(state.ctl[ISTAT].get_ui(), STATUS_IE);
+        assert_eq!(state.ctl[STATUS].get_ui() & STATUS_IE, 0);
+
+        // Lower bits have priority
+        state.ctl[STATUS].set(STATUS_IE);
+        state.ctl[REQ].set(INT_OVERFLOW | INT_ZDIV);
+        step(&mut state);
+        assert_eq!(state.pc.get_ui(), 0x202);
+    }
+
+    #[test]
+    fn resume_restores_pc_and_status() {
+        let mut state = with_handlers(INT_TRAP);
+        state.pc.set(0x10);
+        state.r[1].set(7);
+        execute(OpCodes::Trap(1, 0, 0), &mut state);
+        step(&mut state);
+        assert_eq!(state.pc.get_ui(), 0x201);
+        assert_eq!(state.ctl[STATUS].get_ui() & STATUS_IE, 0);
+
+        // The handler can not be interrupted until it returns
+        execute(OpCodes::Trap(1, 0, 0), &mut state);
+        assert_eq!(state.ctl[REQ].get_ui(), 0);
+
+        execute(OpCodes::Resume, &mut state);
+        assert_eq!(state.pc.get_ui(), 0x10);
+        assert_eq!(state.ctl[STATUS].get_ui(), STATUS_IE);
+    }
+
+    #[test]
+    fn dispatches_unnamed_bits() {
+        let mut state = with_handlers(1 << 5);
+        state.verbose = true;
+        state.memory[0x105] = 0x205;
+        state.ctl[REQ].set(1 << 5);
+        assert_eq!(pending_interrupt(&state), Some(5));
+        step(&mut state);
+        assert_eq!(state.pc.get_ui(), 0x205);
+        assert_eq!(state.state, RunningState::Interrupted);
+    }
+
+    #[test]
+    fn masked_interrupts_are_not_requested() {
+        let mut state = with_handlers(INT_TIMER);
+        state.r[2].set(0);
+        execute(OpCodes::Div(1, 1, 2), &mut state);
+        assert_eq!(state.ctl[REQ].get_ui(), 0);
+        assert_eq!(pending_interrupt(&state), None);
+
+        // Nothing is left over to go off when interrupts are enabled later
+        let mut state = new_state();
+        state.r[1].set(0x7fff);
+        state.r[2].set(1);
+        execute(OpCodes::Add(3, 1, 2), &mut state);
+        execute(OpCodes::Div(3, 1, 0), &mut state);
+        assert_eq!(state.ctl[REQ].get_ui(), 0);
+        state.ctl[STATUS].set(STATUS_IE);
+        state.ctl[MASK].set(0xffff);
+        assert_eq!(pending_interrupt(&state), None);
+    }
+
+    #[test]
+    fn timer_counts_down_to_an_interrupt() {
+        let mut state = with_handlers(INT_TIMER);
+        state.ctl[TIMER].set(2);
+        // nop
+        step(&mut state);
+        assert_eq!(state.ctl[TIMER].get_ui(), 1);
+        assert_eq!(state.pc.get_ui(), 1);
+        step(&mut state);
+        assert_eq!(state.ctl[TIMER].get_ui(), 0);
+        assert_eq!(state.ctl[REQ].get_ui(), INT_TIMER);
+        step(&mut state);
+        assert_eq!(state.pc.get_ui(), 0x200);
+        assert_eq!(state.ctl[IPC].get_ui(), 2);
+
+        // The timer stays at 0 until it is set again
+        state.state = RunningState::Step;
+        step(&mut state);
+        assert_eq!(state.ctl[TIMER].get_ui(), 0);
+        assert_eq!(state.ctl[REQ].get_ui(), 0);
+    }
+
+    #[test]
+    fn invalid_instructions_fault() {
+        let mut state = new_state();
+        state.memory[4] = 0xd000;
+        state.pc.set(4);
+        state.state = RunningState::Step;
+        step(&mut state);
+        assert_eq!(state.state, RunningState::Error);
+        assert_eq!(
+            state.fault,
+            Some(Fault::InvalidInstruction { pc: 4, word: 0xd000 })
+        );
+        assert_eq!(state.ctl[REQ].get_ui(), 0);
+
+        // The second word of an instruction at the end of memory is read from address 0
+        let mut state = new_state();
+        state.memory[0xffff] = 0xe1ff;
+        state.pc.set(0xffff);
+        state.state = RunningState::Step;
+        step(&mut state);
+        assert_eq!(state.state, RunningState::Error);
+        assert_eq!(
+            state.fault,
+            Some(Fault::InvalidInstruction {
+                pc: 0xffff,
+                word: 0xe1ff
+            })
+        );
+        assert_eq!(state.ctl[REQ].get_ui(), 0);
+    }
+
+    #[test]
+    fn invalid_instructions_interrupt_when_handled() {
+        let mut state = with_handlers(INT_INVALID);
+        state.memory[4] = 0xd000;
+        state.pc.set(4);
+        step(&mut state);
+        assert_eq!(state.state, RunningState::Interrupted);
+        assert_eq!(state.fault, None);
+        assert_eq!(state.pc.get_ui(), 0x204);
+        assert_eq!(state.ctl[REQ].get_ui(), 0);
+    }
+
+    #[test]
+    fn trap_writes_to_the_console() {
+        let mut state = new_state();
+        for (i, c) in "Hi".chars().enumerate() {
+            state.memory[0x20 + i] = c as u16;
+        }
+        state.r[1].set(2);
+        state.r[2].set(0x20);
+        state.r[3].set(2);
+        execute(OpCodes::Trap(1, 2, 3), &mut state);
+        assert_eq!(state.console.output, "Hi");
+    }
+
+    #[test]
+    fn breakpoint_trap_stops() {
+        let mut state = new_state();
+        state.state = RunningState::Running;
+        state.r[1].set(4);
+        execute(OpCodes::Trap(1, 0, 0), &mut state);
+        assert_eq!(state.state, RunningState::Breakpoint);
+    }
+
+    #[test]
+    fn blocking_read_waits_for_input() {
+        let mut state = new_state();
+        // trap R1,R2,R3 at address 4
+        state.memory[4] = 0xc123;
+        state.pc.set(4);
+        state.r[1].set(3);
+        state.r[2].set(0x20);
+        state.r[3].set(5);
+        state.state = RunningState::Step;
+        step(&mut state);
+        assert_eq!(state.state, RunningState::Waiting);
+        assert_eq!(state.pc.get_ui(), 4);
+        assert_eq!(state.r[3].get_ui(), 5);
+
+        // The read runs again once there is input
+        state.console.input = "ab".to_string();
+        state.state = RunningState::Step;
+        step(&mut state);
+        assert_eq!(state.state, RunningState::Step);
+        assert_eq!(state.pc.get_ui(), 5);
+        assert_eq!(state.r[3].get_ui(), 2);
+        assert_eq!(state.memory[0x20], 'a' as u16);
+        assert_eq!(state.memory[0x21], 'b' as u16);
+        assert!(!state.console.has_input());
+
+        // A non-blocking read of an empty queue reads nothing
+        state.r[1].set(1);
+        state.r[3].set(5);
+        execute(OpCodes::Trap(1, 2, 3), &mut state);
+        assert_eq!(state.r[3].get_ui(), 0);
+    }
+
+    #[test]
+    fn push_pop_and_top_stay_in_bounds() {
+        let mut state = new_state();
+        // The stack is 0x20 to 0x21, R2 points at the top and starts below it
+        state.r[2].set(0x1f);
+        state.r[3].set(0x20);
+        state.r[4].set(0x21);
+        state.r[1].set(7);
+        execute(OpCodes::Push(1, 2, 4), &mut state);
+        state.r[1].set(8);
+        execute(OpCodes::Push(1, 2, 4), &mut state);
+        assert_eq!(state.r[2].get_ui(), 0x21);
+        assert_eq!((state.memory[0x20], state.memory[0x21]), (7, 8));
+
+        // Full
+        execute(OpCodes::Push(1, 2, 4), &mut state);
+        assert_eq!(state.r[2].get_ui(), 0x21);
+        assert_eq!(state.memory[0x22], 0);
+        assert_eq!(state.r[15].get_ui() & R15_S, R15_S);
+
+        execute(OpCodes::Top(5, 2, 3), &mut state);
+        assert_eq!(state.r[5].get_ui(), 8);
+        assert_eq!(state.r[2].get_ui(), 0x21);
+        execute(OpCodes::Pop(5, 2, 3), &mut state);
+        execute(OpCodes::Pop(6, 2, 3), &mut state);
+        assert_eq!((state.r[5].get_ui(), state.r[6].get_ui()), (8, 7));
+        assert_eq!(state.r[2].get_ui(), 0x1f);
+
+        // Empty
+        state.r[15].set(0);
+        state.r[5].set(0);
+        execute(OpCodes::Pop(5, 2, 3), &mut state);
+        assert_eq!(state.r[2].get_ui(), 0x1f);
+        assert_eq!(state.r[5].get_ui(), 0);
+        assert_eq!(state.r[15].get_ui() & R15_s, R15_s);
+        state.r[15].set(0);
+        execute(OpCodes::Top(5, 2, 3), &mut state);
+        assert_eq!(state.r[5].get_ui(), 0);
+        assert_eq!(state.r[15].get_ui() & R15_s, R15_s);
+    }
+
+    #[test]
+    fn save_and_restore_registers() {
+        let mut state = new_state();
+        for i in 1..=4 {
+            state.r[i].set(i as u16 * 10);
+        }
+        state.r[14].set(0xfffe);
+        // Saving past the end of memory wraps around to address 0
+        execute(OpCodes::Save(1, 4, 14, 1), &mut state);
+        assert_eq!(state.memory[0xffff], 10);
+        assert_eq!(state.memory[0], 20);
+        assert_eq!(state.memory[2], 40);
+
+        for i in 1..=4 {
+            state.r[i].set(0);
+        }
+        execute(OpCodes::Restore(2, 3, 14, 2), &mut state);
+        assert_eq!(state.r[1].get_ui(), 0);
+        assert_eq!(state.r[2].get_ui(), 20);
+        assert_eq!(state.r[3].get_ui(), 30);
+        assert_eq!(state.r[4].get_ui(), 0);
+    }
+
+    #[test]
+    fn shifts() {
+        let mut state = new_state();
+        state.r[1].set(0x8421);
+        execute(OpCodes::Shiftl(2, 1, 4), &mut state);
+        assert_eq!(state.r[2].get_ui(), 0x4210);
+        execute(OpCodes::Shiftr(2, 1, 4), &mut state);
+        assert_eq!(state.r[2].get_ui(), 0x0842);
+        execute(OpCodes::Shiftl(2, 1, 15), &mut state);
+        assert_eq!(state.r[2].get_ui(), 0x8000);
+        execute(OpCodes::Shiftr(2, 1, 15), &mut state);
+        assert_eq!(state.r[2].get_ui(), 1);
+        execute(OpCodes::Shiftl(2, 1, 0), &mut state);
+        assert_eq!(state.r[2].get_ui(), 0x8421);
+    }
+
+    #[test]
+    fn logic_functions() {
+        let mut state = new_state();
+        state.r[1].set(0b1100);
+        state.r[2].set(0b1010);
+        let cases = [(0b0001, 0b1000), (0b0111, 0b1110), (0b0110, 0b0110)];
+        for (function, expected) in cases {
+            execute(OpCodes::Logicw(3, 1, 2, function), &mut state);
+            assert_eq!(state.r[3].get_ui(), expected);
+        }
+        // nand sets every bit that is not in both
+        execute(OpCodes::Logicw(3, 1, 2, 0b1110), &mut state);
+        assert_eq!(state.r[3].get_ui(), !0b1000);
+
+        // Bits 3 and 2 are 1 and 1, bits 1 and 0 are 0 and 0
+        state.r[4].set(0b1100);
+        execute(OpCodes::Logicf(4, 0, 3, 2, 0b0001), &mut state);
+        assert_eq!(state.r[4].get_ui(), 0b1101);
+        execute(OpCodes::Logicf(4, 3, 1, 2, 0b0001), &mut state);
+        assert_eq!(state.r[4].get_ui(), 0b0101);
+        execute(OpCodes::Logicf(4, 15, 1, 3, 0b0111), &mut state);
+        assert_eq!(state.r[4].get_ui(), 0b0101);
+        execute(OpCodes::Logicf(4, 15, 1, 2, 0b0111), &mut state);
+        assert_eq!(state.r[4].get_ui(), 0x8005);
+    }
+
+    #[test]
+    fn extract_fields() {
+        let mut state = new_state();
+        state.r[1].set(0xabcd);
+        execute(OpCodes::Extract(2, 1, 4, 8), &mut state);
+        assert_eq!(state.r[2].get_ui(), 0xbc);
+        // The whole word
+        execute(OpCodes::Extract(2, 1, 0, 16), &mut state);
+        assert_eq!(state.r[2].get_ui(), 0xabcd);
+        // Single bits at each end
+        execute(OpCodes::Extract(2, 1, 0, 1), &mut state);
+        assert_eq!(state.r[2].get_ui(), 1);
+        execute(OpCodes::Extract(2, 1, 15, 1), &mut state);
+        assert_eq!(state.r[2].get_ui(), 1);
+        execute(OpCodes::Extract(2, 1, 1, 1), &mut state);
+        assert_eq!(state.r[2].get_ui(), 0);
+        // A field running off the top of the word only has the bits that are there
+        execute(OpCodes::Extract(2, 1, 12, 8), &mut state);
+        assert_eq!(state.r[2].get_ui(), 0xa);
+    }
+
+    #[test]
+    fn control_registers() {
+        let mut state = new_state();
+        state.r[1].set(0x1234);
+        execute(OpCodes::Putctl(1, TIMER as u8), &mut state);
+        assert_eq!(state.ctl[TIMER].get_ui(), 0x1234);
+        execute(OpCodes::Getctl(2, TIMER as u8), &mut state);
+        assert_eq!(state.r[2].get_ui(), 0x1234);
+    }
+
+    #[test]
+    fn branches_are_relative() {
+        let mut state = new_state();
+        state.r[1].set(0b100);
+        state.pc.set(0x10);
+        execute(OpCodes::Brc1(1, 2, 5), &mut state);
+        assert_eq!(state.pc.get_ui(), 0x15);
+        execute(OpCodes::Brc0(1, 2, 5), &mut state);
+        assert_eq!(state.pc.get_ui(), 0x15);
+        execute(OpCodes::Brc0(1, 0, -0x15), &mut state);
+        assert_eq!(state.pc.get_ui(), 0);
+        // Backwards past address 0 wraps around
+        execute(OpCodes::Brc1(1, 2, -1), &mut state);
+        assert_eq!(state.pc.get_ui(), 0xffff);
+        execute(OpCodes::Brc1(1, 1, 4), &mut state);
+        assert_eq!(state.pc.get_ui(), 0xffff);
+    }
+
+    #[test]
+    fn dispatch_into_a_jump_table() {
+        let mut state = new_state();
+        for (value, pc) in [(0, 0x10), (2, 0x14), (3, 0x16), (9, 0x16)] {
+            state.pc.set(0x10);
+            state.r[1].set(value);
+            execute(OpCodes::Dsptch(1, 3), &mut state);
+            assert_eq!(state.pc.get_ui(), pc);
+        }
+    }
+
+    #[test]
+    fn reads_and_writes_are_separate() {
+        let mut state = new_state();
+        state.memory[4] = 3;
+        state.reset_altered();
+        execute(OpCodes::Load(1, 0, 4), &mut state);
+        execute(OpCodes::Store(1, 0, 5), &mut state);
+        assert!(state.memory.is_read(4));
+        assert!(!state.memory.is_written(4));
+        assert!(state.memory.is_written(5));
+        assert!(!state.memory.is_read(5));
     }
 }
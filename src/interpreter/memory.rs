@@ -1,60 +1,90 @@
-use log::{log, Level};
-
 pub const U16_MAX: u16 = 65535;
 
+/// Number of words in memory, every u16 is a valid address
+pub const MEMORY_SIZE: usize = U16_MAX as usize + 1;
+
 #[derive(serde::Serialize, serde::Deserialize, serde_diff::SerdeDiff, Clone)]
+#[serde(try_from = "SavedMemory")]
 pub struct Memory {
-    contents: Vec<u16>,
-    accessed_i: Vec<usize>,
-    mem_used: Vec<usize>,
+    #[serde(serialize_with = "sparse::serialize")]
+    words: Vec<u16>,
+    used: BitSet,
+    read: BitSet,
+    written: BitSet,
     monitored: Vec<usize>,
+    /// Words written since the last reset with what they held before, so a step can be undone
+    #[serde(skip)]
+    #[serde_diff(skip)]
+    overwritten: Vec<(usize, u16, bool)>,
+}
+
+/// What a step did to memory, memory is too big to copy every step so it is undone from this
+#[derive(serde::Serialize, serde::Deserialize, Clone, Default, Debug, PartialEq)]
+pub struct MemoryStep {
+    /// Address, word before the step and whether the address was used before it
+    overwritten: Vec<(usize, u16, bool)>,
+    /// Addresses read and written by the step before, so they are highlighted again
+    read: Vec<usize>,
+    written: Vec<usize>,
 }
 
 impl std::ops::Index<usize> for Memory {
     type Output = u16;
 
     fn index(&self, i: usize) -> &u16 {
-        if self.mem_used.contains(&i) {
-            &self.contents[i]
-        } else {
-            &0_u16
-        }
+        &self.words[i]
     }
 }
 
 impl std::ops::IndexMut<usize> for Memory {
     fn index_mut(&mut self, i: usize) -> &mut u16 {
-        self.accessed_i.push(i);
-
-        if !self.mem_used.contains(&i) {
-            self.mem_used.push(i);
-            self.mem_used.sort_unstable();
-        }
-        if self.contents.len() < i + 1 {
-            self.contents.resize(i + 1, 0_u16);
+        if !self.written.contains(i) {
+            self.overwritten
+                .push((i, self.words[i], self.used.contains(i)));
         }
+        self.used.insert(i);
+        self.written.insert(i);
+        &mut self.words[i]
+    }
+}
 
-        &mut self.contents[i]
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new(None)
     }
 }
 
 impl Memory {
     pub fn new(init: Option<&[u16]>) -> Memory {
         let mut mem = Memory {
-            mem_used: Vec::new(),
-            contents: Vec::with_capacity(U16_MAX as usize),
-            accessed_i: Vec::new(),
+            words: vec![0; MEMORY_SIZE],
+            used: BitSet::new(),
+            read: BitSet::new(),
+            written: BitSet::new(),
             monitored: Vec::new(),
+            overwritten: Vec::new(),
         };
         if let Some(init) = init {
-            for i in 0..init.len() {
-                mem.contents.push(init[i]);
-                mem.mem_used.push(i);
+            for (i, word) in init.iter().enumerate().take(MEMORY_SIZE) {
+                mem.words[i] = *word;
+                mem.used.insert(i);
             }
         }
         mem
     }
 
+    /// Loads assembled code, only the addresses the code uses are marked as used
+    /// so gaps left by org are not shown
+    pub fn load(init: &[u16], used: impl Iterator<Item = usize>) -> Memory {
+        let mut mem = Memory::new(None);
+        let len = init.len().min(MEMORY_SIZE);
+        mem.words[..len].copy_from_slice(&init[..len]);
+        for i in used.filter(|i| *i < MEMORY_SIZE) {
+            mem.used.insert(i);
+        }
+        mem
+    }
+
     pub fn monitor(&mut self, address: usize) {
         match self.monitored.iter().position(|a| *a == address) {
             Some(index) => {
@@ -67,12 +97,12 @@ impl Memory {
     }
 
     pub fn get_monitored(&self) -> Option<Vec<usize>> {
-        let mut result: Vec<usize> = Vec::new();
-        self.monitored.iter().for_each(|n| {
-            if self.accessed_i.contains(n) {
-                result.push(*n)
-            }
-        });
+        let result: Vec<usize> = self
+            .monitored
+            .iter()
+            .filter(|n| self.written.contains(**n))
+            .copied()
+            .collect();
         if result.len() > 0 {
             Some(result)
         } else {
@@ -80,16 +110,193 @@ impl Memory {
         }
     }
 
+    /// Reads a word as part of executing an instruction, so it is highlighted as read
+    /// Indexing reads without recording the access, for displaying memory
+    pub fn read(&mut self, i: usize) -> u16 {
+        self.read.insert(i);
+        self.words[i]
+    }
+
     pub fn reset_accessed(&mut self) {
-        self.accessed_i = Vec::new();
+        self.read.clear();
+        self.written.clear();
+        self.overwritten.clear();
     }
 
-    pub fn get_altered_i(&self) -> &[usize] {
-        &self.accessed_i
+    /// An empty stand in while memory is moved out of the state, it holds no words
+    pub fn detached() -> Memory {
+        Memory {
+            words: Vec::new(),
+            used: BitSet { bits: Vec::new() },
+            read: BitSet { bits: Vec::new() },
+            written: BitSet { bits: Vec::new() },
+            monitored: Vec::new(),
+            overwritten: Vec::new(),
+        }
+    }
+
+    /// Starts a step, the accesses of the last step are kept to be shown again on undo
+    pub fn start_step(&mut self) -> MemoryStep {
+        let step = MemoryStep {
+            overwritten: Vec::new(),
+            read: self.read.iter().collect(),
+            written: self.written.iter().collect(),
+        };
+        self.reset_accessed();
+        step
     }
 
-    pub fn get_used(&self) -> &[usize] {
-        &self.mem_used
+    /// Finishes a step started by start_step with the words it overwrote
+    pub fn end_step(&mut self, mut step: MemoryStep) -> MemoryStep {
+        step.overwritten = std::mem::take(&mut self.overwritten);
+        step
+    }
+
+    /// Puts memory back to how it was before a step
+    pub fn undo(&mut self, step: MemoryStep) {
+        for (i, word, used) in step.overwritten.into_iter().rev() {
+            self.words[i] = word;
+            if !used {
+                self.used.remove(i);
+            }
+        }
+        self.reset_accessed();
+        for i in step.read {
+            self.read.insert(i);
+        }
+        for i in step.written {
+            self.written.insert(i);
+        }
+    }
+
+    /// Whether the address was read since the last reset
+    pub fn is_read(&self, i: usize) -> bool {
+        self.read.contains(i)
+    }
+
+    /// Whether the address was written since the last reset
+    pub fn is_written(&self, i: usize) -> bool {
+        self.written.contains(i)
+    }
+
+    /// Addresses that have been loaded with code or written to, in order
+    pub fn used(&self) -> impl Iterator<Item = usize> + '_ {
+        self.used.iter()
+    }
+}
+
+/// Set of memory addresses, one bit per word
+#[derive(serde::Serialize, serde::Deserialize, serde_diff::SerdeDiff, Clone)]
+pub struct BitSet {
+    bits: Vec<u64>,
+}
+
+impl Default for BitSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BitSet {
+    pub fn new() -> BitSet {
+        BitSet {
+            bits: vec![0; MEMORY_SIZE / 64],
+        }
+    }
+
+    pub fn insert(&mut self, i: usize) {
+        self.bits[i / 64] |= 1 << (i % 64);
+    }
+
+    pub fn remove(&mut self, i: usize) {
+        self.bits[i / 64] &= !(1 << (i % 64));
+    }
+
+    pub fn contains(&self, i: usize) -> bool {
+        self.bits[i / 64] & (1 << (i % 64)) != 0
+    }
+
+    pub fn clear(&mut self) {
+        self.bits.fill(0);
+    }
+
+    /// The addresses in the set in ascending order
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.bits.iter().enumerate().flat_map(|(i, bits)| {
+            let mut bits = *bits;
+            std::iter::from_fn(move || {
+                if bits == 0 {
+                    return None;
+                }
+                let bit = bits.trailing_zeros() as usize;
+                bits &= bits - 1;
+                Some(i * 64 + bit)
+            })
+        })
+    }
+}
+
+/// Saves memory as (address, word) pairs for the words that are not 0,
+/// so saved programs do not store all 65536 words
+mod sparse {
+    use serde::Serializer;
+
+    pub fn serialize<S: Serializer>(words: &[u16], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(
+            words
+                .iter()
+                .enumerate()
+                .filter(|(_, word)| **word != 0)
+                .map(|(i, word)| (i as u16, *word)),
+        )
+    }
+}
+
+/// Memory as it is saved, either as (address, word) pairs or in the format from before
+/// memory was a fixed array, which had a list of words and a list of the addresses used
+#[derive(serde::Deserialize)]
+struct SavedMemory {
+    words: Option<Vec<(u16, u16)>>,
+    used: Option<BitSet>,
+    read: Option<BitSet>,
+    written: Option<BitSet>,
+    #[serde(default)]
+    monitored: Vec<usize>,
+    contents: Option<Vec<u16>>,
+    mem_used: Option<Vec<usize>>,
+}
+
+impl TryFrom<SavedMemory> for Memory {
+    type Error = String;
+
+    fn try_from(saved: SavedMemory) -> Result<Memory, String> {
+        let mut memory = match (saved.words, saved.contents) {
+            (Some(words), _) => {
+                let mut memory = Memory::new(None);
+                for (i, word) in words {
+                    memory.words[i as usize] = word;
+                }
+                let sets = [
+                    (saved.used, &mut memory.used),
+                    (saved.read, &mut memory.read),
+                    (saved.written, &mut memory.written),
+                ];
+                for (saved, set) in sets {
+                    match saved {
+                        Some(saved) if saved.bits.len() == set.bits.len() => *set = saved,
+                        Some(_) => return Err("saved memory has the wrong size".to_string()),
+                        None => {}
+                    }
+                }
+                memory
+            }
+            (None, Some(contents)) => {
+                Memory::load(&contents, saved.mem_used.unwrap_or_default().into_iter())
+            }
+            (None, None) => return Err("saved memory has no words".to_string()),
+        };
+        memory.monitored = saved.monitored;
+        Ok(memory)
     }
 }
 
@@ -101,3 +308,83 @@ pub fn word_to_nibbles(word: u16) -> [u8; 4] {
         ((word >> 12) & 0b0000_0000_0000_1111) as u8,
     ]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bitset_iterates_in_order() {
+        let mut set = BitSet::new();
+        for i in [0xffff, 3, 64, 63, 3] {
+            set.insert(i);
+        }
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![3, 63, 64, 0xffff]);
+        set.clear();
+        assert_eq!(set.iter().count(), 0);
+    }
+
+    #[test]
+    fn writes_are_tracked() {
+        let mut mem = Memory::new(Some(&[1, 2]));
+        mem[0xffff] = 5;
+        assert_eq!(mem.used().collect::<Vec<_>>(), vec![0, 1, 0xffff]);
+        assert!(mem.is_written(0xffff));
+        assert!(!mem.is_written(0));
+        mem.reset_accessed();
+        assert!(!mem.is_written(0xffff));
+
+        assert_eq!(mem.read(1), 2);
+        assert!(mem.is_read(1));
+        assert!(!mem.is_written(1));
+        // Indexing does not count as a read
+        assert_eq!(mem[0], 1);
+        assert!(!mem.is_read(0));
+    }
+
+    #[test]
+    fn undoes_a_step() {
+        let mut mem = Memory::new(Some(&[1, 2]));
+        mem[0] = 5;
+        assert_eq!(mem.read(1), 2);
+
+        let step = mem.start_step();
+        mem[0] = 6;
+        mem[0] = 7;
+        mem[9] = 8;
+        assert_eq!(mem.read(0), 7);
+        let step = mem.end_step(step);
+
+        mem.undo(step);
+        assert_eq!((mem[0], mem[9]), (5, 0));
+        assert_eq!(mem.used().collect::<Vec<_>>(), vec![0, 1]);
+        // The accesses of the step before are shown again
+        assert!(mem.is_written(0) && mem.is_read(1));
+        assert!(!mem.is_written(9) && !mem.is_read(0));
+    }
+
+    #[test]
+    fn loads_the_old_format() {
+        let json = r#"{"contents":[4,0,6],"accessed_i":[2],"mem_used":[0,2],"monitored":[2]}"#;
+        let loaded: Memory = serde_json::from_str(json).unwrap();
+        assert_eq!((loaded[0], loaded[2]), (4, 6));
+        assert_eq!(loaded.used().collect::<Vec<_>>(), vec![0, 2]);
+        assert_eq!(loaded.monitored, vec![2]);
+
+        assert!(serde_json::from_str::<Memory>(r#"{"monitored":[]}"#).is_err());
+        let json = r#"{"words":[[1,2]],"used":{"bits":[1]}}"#;
+        assert!(serde_json::from_str::<Memory>(json).is_err());
+    }
+
+    #[test]
+    fn saves_only_nonzero_words() {
+        let mut mem = Memory::new(Some(&[1, 0, 3]));
+        mem[0xffff] = 9;
+        let json = serde_json::to_string(&mem).unwrap();
+        let loaded: Memory = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded[0], 1);
+        assert_eq!(loaded[2], 3);
+        assert_eq!(loaded[0xffff], 9);
+        assert_eq!(loaded.used().collect::<Vec<_>>(), vec![0, 1, 2, 0xffff]);
+    }
+}
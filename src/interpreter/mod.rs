@@ -1,3 +1,9 @@
+pub mod breakpoint;
+pub mod console;
+pub mod control;
+pub mod disassembler;
+pub mod fault;
+pub mod flags;
 pub mod interpreter;
 pub mod memory;
 mod opcodes;
@@ -1,7 +1,7 @@
-use crate::interpreter::interpreter::{R15_eq, R15_g, R15_L};
+use crate::interpreter::control::CONTROL_REGISTERS;
+use crate::interpreter::fault::Fault;
 use crate::interpreter::memory::{word_to_nibbles, Memory};
 use crate::interpreter::register::Register;
-use std::error::Error;
 
 #[derive(Debug, Clone)]
 pub enum OpCodes {
@@ -27,93 +27,157 @@ pub enum OpCodes {
     Load(u8, u8, u16),
     Store(u8, u8, u16),
     Jump(u8, u8, u16),
-    Jumpc(fn(u16) -> bool, u8, u16), // Different jump conditions
+    Jumpc0(u8, u8, u16), // Jump if a bit of R15 is 0
+    Jumpc1(u8, u8, u16), // Jump if a bit of R15 is 1
     Jal(u8, u8, u16),
     Jumpz(u8, u8, u16),
     Jumpnz(u8, u8, u16),
     Testset(u8, u8, u16),
+
+    // EXP instructions
+    Resume,
+    Save(u8, u8, u8, u8),
+    Restore(u8, u8, u8, u8),
+    Push(u8, u8, u8),
+    Pop(u8, u8, u8),
+    Top(u8, u8, u8),
+    Shiftl(u8, u8, u8),
+    Shiftr(u8, u8, u8),
+    Logicw(u8, u8, u8, u8),
+    Logicf(u8, u8, u8, u8, u8),
+    Extract(u8, u8, u8, u8),
+    Getctl(u8, u8),
+    Putctl(u8, u8),
+    Brc0(u8, u8, i16),
+    Brc1(u8, u8, i16),
+    Dsptch(u8, u16),
 }
 
-// Pass in slice of current + max possible following.
-pub fn next_op(
-    memory: &Memory,
-    pc: &mut Register,
-    verbose: bool,
-) -> Result<OpCodes, Box<dyn Error>> {
-    let word: u16 = memory[pc.poinc(1).into()];
+// Words that are not instructions, such as data the program jumped into, give a fault
+pub fn next_op(memory: &mut Memory, pc: &mut Register, verbose: bool) -> Result<OpCodes, Fault> {
+    let address = pc.get_ui();
+    let word: u16 = memory.read(pc.poinc(1).into());
 
     if verbose {
         print!("Instruction: {word:#06x}");
     }
 
+    let mut word2 = 0;
+    if length(word) == 2 {
+        word2 = memory.read(pc.poinc(1) as usize);
+        if verbose {
+            print!(" {word2:#06x}");
+        }
+    }
+    if verbose {
+        println!();
+    }
+    decode(word, word2).ok_or(Fault::InvalidInstruction { pc: address, word })
+}
+
+/// Number of words in the instruction starting with this word
+pub fn length(word: u16) -> usize {
+    match word >> 12 {
+        14 | 15 => 2,
+        _ => 1,
+    }
+}
+
+/// Decodes an instruction without reading memory, word2 is ignored by one word instructions
+pub fn decode(word: u16, word2: u16) -> Option<OpCodes> {
     // Extract individual nibbles from the word
     let nibbles = word_to_nibbles(word);
 
-    // While there are panics in here, they are unreachable
-    // The assembler ensures any instructions that are inserted, are done so correctly
-    // The only case a panic might occur is due to logic errors in the interpreter
-    let opcode = match nibbles[3] {
+    match nibbles[3] {
         // iRRR instructions
-        0 => Ok(OpCodes::Add(nibbles[2], nibbles[1], nibbles[0])),
-        1 => Ok(OpCodes::Sub(nibbles[2], nibbles[1], nibbles[0])),
-        2 => Ok(OpCodes::Mul(nibbles[2], nibbles[1], nibbles[0])),
-        3 => Ok(OpCodes::Div(nibbles[2], nibbles[1], nibbles[0])),
-        5 => Ok(OpCodes::Addc(nibbles[2], nibbles[1], nibbles[0])),
-        6 => Ok(OpCodes::Muln(nibbles[2], nibbles[1], nibbles[0])),
-        7 => Ok(OpCodes::Divn(nibbles[2], nibbles[1], nibbles[0])),
-        8 => Ok(OpCodes::Rrr1(nibbles[2], nibbles[1], nibbles[0])),
-        9 => Ok(OpCodes::Rrr2(nibbles[2], nibbles[1], nibbles[0])),
-        10 => Ok(OpCodes::Rrr3(nibbles[2], nibbles[1], nibbles[0])),
-        11 => Ok(OpCodes::Rrr4(nibbles[2], nibbles[1], nibbles[0])),
-        12 => Ok(OpCodes::Trap(nibbles[2], nibbles[1], nibbles[0])),
+        0 => Some(OpCodes::Add(nibbles[2], nibbles[1], nibbles[0])),
+        1 => Some(OpCodes::Sub(nibbles[2], nibbles[1], nibbles[0])),
+        2 => Some(OpCodes::Mul(nibbles[2], nibbles[1], nibbles[0])),
+        3 => Some(OpCodes::Div(nibbles[2], nibbles[1], nibbles[0])),
+        5 => Some(OpCodes::Addc(nibbles[2], nibbles[1], nibbles[0])),
+        6 => Some(OpCodes::Muln(nibbles[2], nibbles[1], nibbles[0])),
+        7 => Some(OpCodes::Divn(nibbles[2], nibbles[1], nibbles[0])),
+        8 => Some(OpCodes::Rrr1(nibbles[2], nibbles[1], nibbles[0])),
+        9 => Some(OpCodes::Rrr2(nibbles[2], nibbles[1], nibbles[0])),
+        10 => Some(OpCodes::Rrr3(nibbles[2], nibbles[1], nibbles[0])),
+        11 => Some(OpCodes::Rrr4(nibbles[2], nibbles[1], nibbles[0])),
+        12 => Some(OpCodes::Trap(nibbles[2], nibbles[1], nibbles[0])),
 
         // RR Instructions
-        4 => Ok(OpCodes::Cmp(nibbles[1], nibbles[0])),
+        4 => Some(OpCodes::Cmp(nibbles[1], nibbles[0])),
 
         // iRX instructions
         15 => {
-            let word2 = memory[pc.poinc(1) as usize];
-            if verbose {
-                print!(" {word2:#06x}");
-            }
             match nibbles[0] {
-                0 => Ok(OpCodes::Lea(nibbles[2], nibbles[1], word2)),
-                1 => Ok(OpCodes::Load(nibbles[2], nibbles[1], word2)),
-                2 => Ok(OpCodes::Store(nibbles[2], nibbles[1], word2)),
-                3 => Ok(OpCodes::Jump(nibbles[2], nibbles[1], word2)),
-                4 => match nibbles[2] {
-                    0 => Ok(OpCodes::Jumpc(
-                        |r15| r15 & (R15_eq | R15_L) > 0,
-                        nibbles[1],
-                        word2,
-                    )), // jumple
-                    2 => Ok(OpCodes::Jumpc(|r15| r15 & R15_eq == 0, nibbles[1], word2)), // jumpne
-                    4 => Ok(OpCodes::Jumpc(
-                        |r15| r15 & (R15_eq | R15_g) > 0,
-                        nibbles[1],
-                        word2,
-                    )), // jumpge
-                    _ => panic!("Invalid op code"),
-                },
-                5 => match nibbles[2] {
-                    0 => Ok(OpCodes::Jumpc(|r15| r15 & R15_g > 0, nibbles[1], word2)), // jumpgt
-                    2 => Ok(OpCodes::Jumpc(|r15| r15 & R15_eq > 0, nibbles[1], word2)), // jumpeq
-                    4 => Ok(OpCodes::Jumpc(|r15| r15 & R15_L > 0, nibbles[1], word2)), // jumplt
-                    _ => panic!("Invalid op code"),
-                },
-                6 => Ok(OpCodes::Jal(nibbles[2], nibbles[1], word2)),
-                7 => Ok(OpCodes::Jumpz(nibbles[2], nibbles[1], word2)),
-                8 => Ok(OpCodes::Jumpnz(nibbles[2], nibbles[1], word2)),
-                9 => Ok(OpCodes::Testset(nibbles[2], nibbles[1], word2)),
-                _ => panic!("Invalid op code"),
+                0 => Some(OpCodes::Lea(nibbles[2], nibbles[1], word2)),
+                1 => Some(OpCodes::Load(nibbles[2], nibbles[1], word2)),
+                2 => Some(OpCodes::Store(nibbles[2], nibbles[1], word2)),
+                3 => Some(OpCodes::Jump(nibbles[2], nibbles[1], word2)),
+                // The first nibble is the R15 bit tested, the condition code bits are
+                // g (0) for jumple and jumpgt, eq (2) for jumpne and jumpeq,
+                // lt (4) for jumpge and jumplt
+                4 => Some(OpCodes::Jumpc0(nibbles[2], nibbles[1], word2)),
+                5 => Some(OpCodes::Jumpc1(nibbles[2], nibbles[1], word2)),
+                6 => Some(OpCodes::Jal(nibbles[2], nibbles[1], word2)),
+                7 => Some(OpCodes::Jumpz(nibbles[2], nibbles[1], word2)),
+                8 => Some(OpCodes::Jumpnz(nibbles[2], nibbles[1], word2)),
+                9 => Some(OpCodes::Testset(nibbles[2], nibbles[1], word2)),
+                _ => None,
             }
         }
 
         // iEXP instructions
-        _ => panic!("Invalid op code"),
-    };
-    if verbose {
-        println!();
+        // The secondary opcode is held in the lower byte of the first word
+        // The operands are held in the nibbles of the second word
+        14 => {
+            let fields = word_to_nibbles(word2);
+            match word & 0x00ff {
+                0x00 => Some(OpCodes::Resume),
+                0x01 => Some(OpCodes::Save(nibbles[2], fields[3], fields[2], word2 as u8)),
+                0x02 => Some(OpCodes::Restore(
+                    nibbles[2],
+                    fields[3],
+                    fields[2],
+                    word2 as u8,
+                )),
+                0x03 => Some(OpCodes::Push(nibbles[2], fields[3], fields[2])),
+                0x04 => Some(OpCodes::Pop(nibbles[2], fields[3], fields[2])),
+                0x05 => Some(OpCodes::Top(nibbles[2], fields[3], fields[2])),
+                0x06 => Some(OpCodes::Shiftl(nibbles[2], fields[3], fields[2])),
+                0x07 => Some(OpCodes::Shiftr(nibbles[2], fields[3], fields[2])),
+                0x08 => Some(OpCodes::Logicw(nibbles[2], fields[3], fields[2], fields[1])),
+                0x09 => Some(OpCodes::Logicf(
+                    nibbles[2], fields[3], fields[2], fields[1], fields[0],
+                )),
+                0x0a => Some(OpCodes::Extract(
+                    nibbles[2],
+                    fields[3],
+                    fields[2],
+                    fields[1] + 1,
+                )),
+                0x0b if (fields[3] as usize) < CONTROL_REGISTERS.len() => {
+                    Some(OpCodes::Getctl(nibbles[2], fields[3]))
+                }
+                0x0c if (fields[3] as usize) < CONTROL_REGISTERS.len() => {
+                    Some(OpCodes::Putctl(nibbles[2], fields[3]))
+                }
+                // The offset is a 12 bit two's complement number, shift it to the top of
+                // the word and back to sign extend it
+                0x0d => Some(OpCodes::Brc0(
+                    nibbles[2],
+                    fields[3],
+                    ((word2 << 4) as i16) >> 4,
+                )),
+                0x0e => Some(OpCodes::Brc1(
+                    nibbles[2],
+                    fields[3],
+                    ((word2 << 4) as i16) >> 4,
+                )),
+                0x0f => Some(OpCodes::Dsptch(nibbles[2], word2)),
+                _ => None,
+            }
+        }
+
+        _ => None,
     }
-    opcode
 }
@@ -1,4 +1,3 @@
-use crate::interpreter::memory::U16_MAX;
 use std::ops::{Add, Div, Mul, Rem, Sub};
 
 #[derive(
@@ -92,11 +91,7 @@ impl Register {
         }
 
         self.altered = true;
-        if self.value < U16_MAX {
-            self.value += amount;
-        } else {
-            self.value = 0;
-        }
+        self.value = self.value.wrapping_add(amount);
         self.value
     }
 
@@ -107,11 +102,7 @@ impl Register {
         }
         self.altered = true;
         let temp: u16 = self.value;
-        if self.value < U16_MAX {
-            self.value += amount;
-        } else {
-            self.value = 0;
-        }
+        self.value = self.value.wrapping_add(amount);
         temp
     }
 
@@ -123,7 +114,8 @@ impl Register {
         self.value = value;
     }
 
-    pub fn get_ui(&mut self) -> u16 {
+    /// The value without marking the register as accessed
+    pub fn get_ui(&self) -> u16 {
         self.value
     }
 
@@ -149,3 +141,21 @@ impl Register {
         self.is_r0 = true;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn increments_wrap() {
+        let mut reg = Register::new();
+        reg.set(0xffff);
+        assert_eq!(reg.poinc(1), 0xffff);
+        assert_eq!(reg.get(), 0);
+
+        reg.set(0xfffe);
+        assert_eq!(reg.peinc(3), 1);
+        assert_eq!(reg.poinc(2), 1);
+        assert_eq!(reg.get(), 3);
+    }
+}
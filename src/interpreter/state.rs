@@ -1,4 +1,6 @@
 use crate::assembler::code::Code;
+use crate::interpreter::console::Console;
+use crate::interpreter::fault::Fault;
 use crate::interpreter::memory::Memory;
 use crate::interpreter::register::Register;
 use log::{log, Level};
@@ -6,6 +8,8 @@ use std::collections::HashMap;
 use std::fmt::Display;
 
 #[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug, Clone, serde_diff::SerdeDiff)]
+// Enums must be diffed as a whole, otherwise the fields after them are not stepped back
+#[serde_diff(opaque)]
 pub enum RunningState {
     Error,
     Running,
@@ -14,6 +18,7 @@ pub enum RunningState {
     Breakpoint,
     Haulted,
     Interrupted,
+    Waiting,
 }
 impl Display for RunningState {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -25,6 +30,7 @@ impl Display for RunningState {
             RunningState::Haulted => "HALTED".to_string(),
             RunningState::Interrupted => "INTERRUPTED".to_string(),
             RunningState::Step => "STEP".to_string(),
+            RunningState::Waiting => "WAITING".to_string(),
         };
         write!(f, "{str}")
     }
@@ -34,18 +40,29 @@ impl Display for RunningState {
 pub struct State {
     pub pc: Register,
     pub r: [Register; 16],
+    #[serde(default)]
+    pub ctl: [Register; 7],
+    /// Not diffed, steps record the words they change instead, see Memory::start_step
+    #[serde_diff(skip)]
     pub memory: Memory,
     pub state: RunningState,
+    #[serde(default)]
+    pub fault: Option<Fault>,
+    #[serde(default)]
+    pub console: Console,
     pub verbose: bool,
     pub symbol_table: HashMap<String, usize>,
     pub monitored_symbols: Vec<(String, bool)>,
     pub monitored_addresses: Vec<u16>,
     pub monitored_registers: [bool; 16],
+    /// Number of times each line with a breakpoint has been hit
+    #[serde(default)]
+    pub hits: HashMap<usize, usize>,
 }
 
 impl State {
     pub fn new(code: &Code) -> State {
-        let run_state = if code.errors.is_empty() {
+        let run_state = if !code.errors.iter().any(|error| error.is_error()) {
             log!(Level::Info, "Empty, {:?}", code.errors.len());
             RunningState::Step
         } else {
@@ -56,19 +73,23 @@ impl State {
         let mut state = State {
             pc: (Register::new()),
             r: [Register::new(); 16],
-            memory: (Memory::new(Option::from(code.memory.as_slice()))),
+            ctl: [Register::new(); 7],
+            memory: Memory::load(&code.memory, code.memory_to_code.keys().copied()),
             state: run_state,
+            fault: None,
+            console: Console::default(),
             verbose: false,
-            symbol_table: code.symbol_table.clone(),
+            symbol_table: code.addresses(),
             monitored_symbols: {
                 let mut symbols = Vec::new();
-                for key in code.symbol_table.keys() {
+                for key in code.addresses().keys() {
                     symbols.push((key.clone(), false));
                 }
                 symbols
             },
             monitored_addresses: Vec::new(),
             monitored_registers: [false; 16],
+            hits: HashMap::new(),
         };
         state.r[0].set_r0();
         state
@@ -86,7 +107,7 @@ impl State {
 
         for (addr, monitor) in self.monitored_registers.iter().enumerate() {
             if *monitor {
-                if self.memory.get_altered_i().contains(&addr) {
+                if self.memory.is_written(addr) {
                     monitored.push(MonitorType::Address(addr));
                 }
             }
@@ -96,8 +117,7 @@ impl State {
             if monitor {
                 if self
                     .memory
-                    .get_altered_i()
-                    .contains(self.symbol_table.get(&symbol).unwrap())
+                    .is_written(*self.symbol_table.get(&symbol).unwrap())
                 {
                     monitored.push(MonitorType::Symbol(symbol));
                 }
@@ -106,7 +126,19 @@ impl State {
         monitored
     }
 
+    /// A copy to diff against after a step, memory is left out as it is too big to copy each step
+    pub fn snapshot(&mut self) -> State {
+        let memory = std::mem::replace(&mut self.memory, Memory::detached());
+        let snapshot = self.clone();
+        self.memory = memory;
+        snapshot
+    }
+
     pub fn reset_altered(&mut self) {
+        self.pc.reset_altered();
+        for reg in &mut self.ctl {
+            reg.reset_altered();
+        }
         for reg in &mut self.r {
             reg.reset_altered();
         }
@@ -142,8 +174,8 @@ impl State {
 
         // Print altered memory
         log.push_str("\nMemory\n");
-        for i in self.memory.get_used() {
-            log.push_str(&format!("  {:#06x} => {:#06x}\n", i, self.memory[*i]));
+        for i in self.memory.used() {
+            log.push_str(&format!("  {:#06x} => {:#06x}\n", i, self.memory[i]));
         }
 
         log.push_str(&format!("Running state: {}\n", self.state));